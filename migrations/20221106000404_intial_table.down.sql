@@ -0,0 +1,4 @@
+-- Add down migration script here
+DROP TABLE IF EXISTS lightningchess_transaction;
+DROP TABLE IF EXISTS lightningchess_balance;
+DROP TABLE IF EXISTS challenge;
@@ -0,0 +1,3 @@
+-- Add down migration script here
+-- fails if a challenge already has more than one transaction, e.g. two sided escrow
+ALTER TABLE lightningchess_transaction ADD CONSTRAINT lightningchess_transaction_lichess_challenge_id_key UNIQUE (lichess_challenge_id);
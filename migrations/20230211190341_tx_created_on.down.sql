@@ -0,0 +1,2 @@
+-- Add down migration script here
+ALTER TABLE lightningchess_transaction DROP COLUMN IF EXISTS created_on;
@@ -0,0 +1,2 @@
+-- Add down migration script here
+ALTER TABLE challenge DROP COLUMN IF EXISTS opponent_sats;
@@ -0,0 +1,3 @@
+-- Add migration script here
+ALTER TABLE challenge ADD COLUMN opponent_sats BIGINT;
+UPDATE challenge SET opponent_sats = sats WHERE opponent_sats IS NULL;
@@ -0,0 +1,3 @@
+-- Add down migration script here
+DROP INDEX IF EXISTS lightningchess_transaction_state_idx;
+ALTER TABLE lightningchess_transaction DROP COLUMN IF EXISTS release_after;
@@ -0,0 +1,3 @@
+-- Add migration script here
+ALTER TABLE lightningchess_transaction ADD COLUMN release_after TIMESTAMP without time zone;
+CREATE INDEX IF NOT EXISTS lightningchess_transaction_state_idx ON lightningchess_transaction(state);
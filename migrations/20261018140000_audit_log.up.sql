@@ -0,0 +1,32 @@
+-- Add up migration script here
+CREATE TABLE IF NOT EXISTS audit_log (
+  audit_id BIGSERIAL PRIMARY KEY,
+  actor_type VARCHAR (16) NOT NULL, -- user, admin or system
+  actor VARCHAR (255) NOT NULL,
+  action VARCHAR (255) NOT NULL,
+  username VARCHAR (255) NOT NULL,
+  amount BIGINT NOT NULL,
+  balance_before BIGINT NOT NULL,
+  balance_after BIGINT NOT NULL,
+  challenge_id INT,
+  transaction_id INT,
+  request_id VARCHAR (64),
+  detail VARCHAR (255),
+  created_on TIMESTAMP without time zone NOT NULL default (now() at time zone 'utc')
+);
+
+CREATE INDEX IF NOT EXISTS audit_log_username_idx ON audit_log(username);
+CREATE INDEX IF NOT EXISTS audit_log_challenge_id_idx ON audit_log(challenge_id);
+CREATE INDEX IF NOT EXISTS audit_log_transaction_id_idx ON audit_log(transaction_id);
+
+-- entries can only be appended
+CREATE OR REPLACE FUNCTION audit_log_append_only() RETURNS trigger AS $$
+BEGIN
+    RAISE EXCEPTION 'audit_log is append only';
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER audit_log_no_update BEFORE UPDATE OR DELETE ON audit_log
+    FOR EACH ROW EXECUTE PROCEDURE audit_log_append_only();
+CREATE TRIGGER audit_log_no_truncate BEFORE TRUNCATE ON audit_log
+    FOR EACH STATEMENT EXECUTE PROCEDURE audit_log_append_only();
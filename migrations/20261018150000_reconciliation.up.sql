@@ -0,0 +1,15 @@
+-- Add up migration script here
+CREATE TABLE IF NOT EXISTS reconciliation_discrepancy (
+  discrepancy_id serial PRIMARY KEY,
+  kind VARCHAR (64) NOT NULL,
+  transaction_id INT,
+  expected BIGINT,
+  actual BIGINT,
+  detail VARCHAR (1024) NOT NULL,
+  first_seen TIMESTAMP without time zone NOT NULL default (now() at time zone 'utc'),
+  last_seen TIMESTAMP without time zone NOT NULL default (now() at time zone 'utc'),
+  resolved_on TIMESTAMP without time zone
+);
+
+-- one open discrepancy per kind and transaction, reconciliation runs refresh it
+CREATE UNIQUE INDEX IF NOT EXISTS reconciliation_discrepancy_open_idx ON reconciliation_discrepancy(kind, COALESCE(transaction_id, 0)) WHERE resolved_on IS NULL;
@@ -0,0 +1,4 @@
+-- Add down migration script here
+DROP INDEX IF EXISTS lightningchess_transaction_username_id_idx;
+DROP INDEX IF EXISTS challenge_username_id_idx;
+DROP INDEX IF EXISTS challenge_opp_username_id_idx;
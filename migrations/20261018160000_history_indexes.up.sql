@@ -0,0 +1,5 @@
+-- Add up migration script here
+-- keyset pagination walks these newest first
+CREATE INDEX IF NOT EXISTS lightningchess_transaction_username_id_idx ON lightningchess_transaction(username, transaction_id);
+CREATE INDEX IF NOT EXISTS challenge_username_id_idx ON challenge(username, id);
+CREATE INDEX IF NOT EXISTS challenge_opp_username_id_idx ON challenge(opp_username, id);
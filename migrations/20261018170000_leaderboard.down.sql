@@ -0,0 +1,3 @@
+-- Add down migration script here
+DROP TABLE IF EXISTS leaderboard;
+ALTER TABLE lightningchess_balance DROP COLUMN IF EXISTS leaderboard_opt_out;
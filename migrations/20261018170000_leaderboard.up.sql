@@ -0,0 +1,16 @@
+-- Add up migration script here
+ALTER TABLE lightningchess_balance ADD COLUMN leaderboard_opt_out BOOLEAN NOT NULL DEFAULT false;
+
+-- rebuilt by the leaderboard job, one row per player per time window
+CREATE TABLE IF NOT EXISTS leaderboard (
+  time_window VARCHAR (16) NOT NULL,
+  username VARCHAR (255) NOT NULL,
+  net_sats BIGINT NOT NULL,
+  games BIGINT NOT NULL,
+  wins BIGINT NOT NULL,
+  losses BIGINT NOT NULL,
+  draws BIGINT NOT NULL,
+  win_rate DOUBLE PRECISION NOT NULL,
+  refreshed_on TIMESTAMP without time zone NOT NULL,
+  PRIMARY KEY (time_window, username)
+);
@@ -0,0 +1,22 @@
+-- Add up migration script here
+CREATE TABLE IF NOT EXISTS users (
+  user_id serial PRIMARY KEY,
+  lichess_id VARCHAR (255) NOT NULL UNIQUE,
+  username VARCHAR (255) NOT NULL,
+  preferences JSONB NOT NULL DEFAULT '{}',
+  created_on TIMESTAMP without time zone NOT NULL default (now() at time zone 'utc'),
+  last_seen TIMESTAMP without time zone NOT NULL default (now() at time zone 'utc')
+);
+
+-- only a hash of the session id is stored, the lichess token is encrypted with session.token_key
+CREATE TABLE IF NOT EXISTS user_session (
+  session_hash VARCHAR (64) PRIMARY KEY,
+  user_id INT NOT NULL REFERENCES users(user_id) ON DELETE CASCADE,
+  lichess_token VARCHAR (1024) NOT NULL,
+  created_on TIMESTAMP without time zone NOT NULL default (now() at time zone 'utc'),
+  last_seen TIMESTAMP without time zone NOT NULL default (now() at time zone 'utc'),
+  expires_on TIMESTAMP without time zone NOT NULL,
+  revoked_on TIMESTAMP without time zone
+);
+
+CREATE INDEX IF NOT EXISTS user_session_user_id_idx ON user_session(user_id);
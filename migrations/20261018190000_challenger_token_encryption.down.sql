@@ -0,0 +1,6 @@
+-- Add down migration script here
+DROP TRIGGER IF EXISTS challenge_purge_token ON challenge;
+DROP FUNCTION IF EXISTS challenge_purge_token();
+-- older versions expect raw tokens
+UPDATE challenge SET challenger_token = NULL WHERE challenger_token LIKE 'v1:%';
+ALTER TABLE challenge ALTER COLUMN challenger_token TYPE VARCHAR (255);
@@ -0,0 +1,18 @@
+-- Add up migration script here
+-- encrypted tokens are longer than raw ones
+ALTER TABLE challenge ALTER COLUMN challenger_token TYPE VARCHAR (1024);
+
+-- the challenger's token is only needed to accept the lichess challenge, drop it once the challenge is over
+UPDATE challenge SET challenger_token = NULL WHERE status NOT IN ('WAITING FOR ACCEPTANCE', 'ACCEPTED');
+
+CREATE OR REPLACE FUNCTION challenge_purge_token() RETURNS trigger AS $$
+BEGIN
+  IF NEW.status NOT IN ('WAITING FOR ACCEPTANCE', 'ACCEPTED') THEN
+    NEW.challenger_token := NULL;
+  END IF;
+  RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER challenge_purge_token BEFORE INSERT OR UPDATE ON challenge
+    FOR EACH ROW EXECUTE PROCEDURE challenge_purge_token();
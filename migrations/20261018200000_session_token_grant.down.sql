@@ -0,0 +1,3 @@
+-- Add down migration script here
+ALTER TABLE user_session DROP COLUMN IF EXISTS token_expires_on;
+ALTER TABLE user_session DROP COLUMN IF EXISTS token_scopes;
@@ -0,0 +1,4 @@
+-- Add up migration script here
+-- what lichess granted, unknown for sessions migrated from the old token cookie
+ALTER TABLE user_session ADD COLUMN IF NOT EXISTS token_scopes VARCHAR (255);
+ALTER TABLE user_session ADD COLUMN IF NOT EXISTS token_expires_on TIMESTAMP without time zone;
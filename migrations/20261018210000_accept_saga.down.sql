@@ -0,0 +1,14 @@
+-- Add down migration script here
+-- run once no accept is in flight, escrowed stakes of an ACCEPTING challenge aren't refunded here
+UPDATE challenge SET status = 'WAITING FOR ACCEPTANCE' WHERE status = 'ACCEPTING';
+
+CREATE OR REPLACE FUNCTION challenge_purge_token() RETURNS trigger AS $$
+BEGIN
+  IF NEW.status NOT IN ('WAITING FOR ACCEPTANCE', 'ACCEPTED') THEN
+    NEW.challenger_token := NULL;
+  END IF;
+  RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TABLE IF EXISTS accept_saga;
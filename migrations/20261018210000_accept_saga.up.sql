@@ -0,0 +1,27 @@
+-- Add up migration script here
+-- stakes are escrowed first, then the lichess game is set up one step at a time.
+-- a failed accept, or one left behind by a crash, is compensated: the lichess game is cancelled and the stakes refunded
+CREATE TABLE IF NOT EXISTS accept_saga (
+  saga_id serial PRIMARY KEY,
+  challenge_id INT NOT NULL REFERENCES challenge(id),
+  username VARCHAR (255) NOT NULL,
+  acceptor_token VARCHAR (1024), -- encrypted, needed to cancel the lichess challenge, dropped once the saga is over
+  step VARCHAR (32) NOT NULL,
+  lichess_challenge_id VARCHAR (255),
+  error VARCHAR (1024),
+  created_on TIMESTAMP without time zone NOT NULL default (now() at time zone 'utc'),
+  updated_on TIMESTAMP without time zone NOT NULL default (now() at time zone 'utc')
+);
+
+-- one accept in flight per challenge
+CREATE UNIQUE INDEX IF NOT EXISTS accept_saga_open_idx ON accept_saga(challenge_id) WHERE step NOT IN ('COMPLETED', 'COMPENSATED');
+
+-- the challenger's token is still needed while the challenge is being accepted
+CREATE OR REPLACE FUNCTION challenge_purge_token() RETURNS trigger AS $$
+BEGIN
+  IF NEW.status NOT IN ('WAITING FOR ACCEPTANCE', 'ACCEPTING', 'ACCEPTED') THEN
+    NEW.challenger_token := NULL;
+  END IF;
+  RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
@@ -0,0 +1,24 @@
+-- Add up migration script here
+-- the lichess export of a settled challenge's game, kept so players have a record of their wagered games
+CREATE TABLE IF NOT EXISTS game (
+  game_id serial PRIMARY KEY,
+  challenge_id INT NOT NULL UNIQUE REFERENCES challenge(id),
+  lichess_game_id VARCHAR (255) NOT NULL,
+  status VARCHAR (32) NOT NULL, -- lichess game status, like mate, resign or aborted
+  winner VARCHAR (16), -- white or black, NULL for draws and aborted games
+  white VARCHAR (255),
+  black VARCHAR (255),
+  white_rating INT,
+  black_rating INT,
+  speed VARCHAR (32),
+  clock_initial INT, -- seconds
+  clock_increment INT, -- seconds
+  opening_eco VARCHAR (16),
+  opening_name VARCHAR (255),
+  moves TEXT NOT NULL DEFAULT '', -- SAN, space separated
+  clocks INT[] NOT NULL DEFAULT '{}', -- centiseconds left after each move
+  pgn TEXT,
+  started_on TIMESTAMP without time zone,
+  finished_on TIMESTAMP without time zone,
+  created_on TIMESTAMP without time zone NOT NULL default (now() at time zone 'utc')
+);
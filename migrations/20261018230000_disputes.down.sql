@@ -0,0 +1,3 @@
+-- Add down migration script here
+DROP TABLE IF EXISTS notification;
+DROP TABLE IF EXISTS dispute;
@@ -0,0 +1,31 @@
+-- Add up migration script here
+-- a contested result, the challenge isn't settled and held winnings aren't released while it's open
+CREATE TABLE IF NOT EXISTS dispute (
+  dispute_id serial PRIMARY KEY,
+  challenge_id INT NOT NULL REFERENCES challenge(id),
+  username VARCHAR (255) NOT NULL, -- the player who filed it
+  reason VARCHAR (1024) NOT NULL,
+  status VARCHAR (16) NOT NULL DEFAULT 'OPEN', -- OPEN or RESOLVED
+  outcome VARCHAR (16), -- uphold, refund or award
+  awarded_to VARCHAR (255),
+  resolved_by VARCHAR (255),
+  note VARCHAR (1024),
+  created_on TIMESTAMP without time zone NOT NULL default (now() at time zone 'utc'),
+  resolved_on TIMESTAMP without time zone
+);
+
+-- one open dispute per challenge
+CREATE UNIQUE INDEX IF NOT EXISTS dispute_open_idx ON dispute(challenge_id) WHERE status = 'OPEN';
+
+-- messages for players, read through /api/notifications
+CREATE TABLE IF NOT EXISTS notification (
+  notification_id BIGSERIAL PRIMARY KEY,
+  username VARCHAR (255) NOT NULL,
+  kind VARCHAR (32) NOT NULL,
+  message VARCHAR (1024) NOT NULL,
+  challenge_id INT,
+  created_on TIMESTAMP without time zone NOT NULL default (now() at time zone 'utc'),
+  read_on TIMESTAMP without time zone
+);
+
+CREATE INDEX IF NOT EXISTS notification_username_idx ON notification(username);
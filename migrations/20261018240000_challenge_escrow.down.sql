@@ -0,0 +1,9 @@
+-- Add down migration script here
+-- older versions don't refund the stake of a voided challenge, void the waiting ones first
+DO $$
+BEGIN
+  IF EXISTS (SELECT 1 FROM challenge WHERE status IN ('WAITING FOR ACCEPTANCE', 'ACCEPTING')) THEN
+    RAISE EXCEPTION 'waiting challenges hold escrowed stakes, void them before reverting';
+  END IF;
+END
+$$;
@@ -0,0 +1,4 @@
+-- Add up migration script here
+-- the challenger's stake is escrowed when the challenge is created from now on,
+-- challenges created before that hold nothing and are voided
+UPDATE challenge SET status = 'VOIDED' WHERE status = 'WAITING FOR ACCEPTANCE';
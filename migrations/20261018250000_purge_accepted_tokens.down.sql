@@ -0,0 +1,10 @@
+-- Add down migration script here
+-- purged tokens aren't restored
+CREATE OR REPLACE FUNCTION challenge_purge_token() RETURNS trigger AS $$
+BEGIN
+  IF NEW.status NOT IN ('WAITING FOR ACCEPTANCE', 'ACCEPTING', 'ACCEPTED') THEN
+    NEW.challenger_token := NULL;
+  END IF;
+  RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
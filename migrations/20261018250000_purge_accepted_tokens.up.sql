@@ -0,0 +1,12 @@
+-- Add up migration script here
+-- nothing needs the challenger's token once the accept saga has set up the lichess game
+CREATE OR REPLACE FUNCTION challenge_purge_token() RETURNS trigger AS $$
+BEGIN
+  IF NEW.status NOT IN ('WAITING FOR ACCEPTANCE', 'ACCEPTING') THEN
+    NEW.challenger_token := NULL;
+  END IF;
+  RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+UPDATE challenge SET challenger_token = NULL WHERE status NOT IN ('WAITING FOR ACCEPTANCE', 'ACCEPTING') AND challenger_token IS NOT NULL;
@@ -0,0 +1,369 @@
+use std::time::Duration;
+use chrono::Utc;
+use sqlx::{Pool, Postgres};
+use tracing::{debug, error, info, info_span, warn, Instrument};
+use crate::config::AppConfig;
+use crate::crypto::{decrypt, encrypt, is_encrypted};
+use crate::errors::{AppError, AppResult};
+use crate::ledger::{change_balance, Actor, BalanceChange};
+use crate::lichess::client::{accept_lichess_challenge, add_time, cancel_lichess_challenge, create_lichess_challenge};
+use crate::metrics::ACCEPT_SAGAS;
+use crate::models::{AcceptSaga, Challenge, Transaction, User};
+use crate::settlement::credit;
+
+// accepting a challenge escrows the acceptor's stake next to the challenger's, escrowed when the
+// challenge was created, and then sets the game up on lichess one call at a time.
+// every step is recorded so a failed accept, or one a crash left behind, can be undone:
+// the lichess challenge is cancelled (aborting the game if it was accepted) and the acceptor's stake goes back
+
+pub const ESCROWED: &str = "ESCROWED";
+pub const CHALLENGE_CREATED: &str = "CHALLENGE CREATED";
+pub const CHALLENGE_ACCEPTED: &str = "CHALLENGE ACCEPTED";
+pub const TIME_ADDED: &str = "TIME ADDED";
+pub const COMPLETED: &str = "COMPLETED";
+pub const COMPENSATING: &str = "COMPENSATING";
+pub const COMPENSATED: &str = "COMPENSATED";
+
+const RECOVERY_INTERVAL_SECS: u64 = 60;
+// well past the lichess timeouts of an accept still in flight
+const STALE_AFTER_SECS: i64 = 300;
+
+// whose lichess token a step uses
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Player {
+    Acceptor,
+    Challenger
+}
+
+pub struct Failure {
+    pub error: AppError,
+    // set when lichess rejected the player's token
+    pub rejected: Option<Player>
+}
+
+impl From<AppError> for Failure {
+    fn from(error: AppError) -> Failure {
+        Failure { error, rejected: None }
+    }
+}
+
+fn failed(player: Player, error: AppError) -> Failure {
+    let rejected = matches!(error, AppError::TokenRejected(_)).then_some(player);
+    Failure { error, rejected }
+}
+
+// the lichess game starts on the shorter clock, the player with the longer one gets the difference
+// added by their opponent, lichess only lets players add time to the other side
+pub fn time_odds(challenge: &Challenge) -> Option<(Player, i32)> {
+    let time_limit = challenge.time_limit.unwrap_or_default();
+    let opponent_time_limit = challenge.opponent_time_limit.unwrap_or_default();
+    match time_limit.cmp(&opponent_time_limit) {
+        std::cmp::Ordering::Equal => None,
+        std::cmp::Ordering::Less => Some((Player::Challenger, opponent_time_limit - time_limit)),
+        std::cmp::Ordering::Greater => Some((Player::Acceptor, time_limit - opponent_time_limit))
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Recovery {
+    // every lichess call went through, only the challenge update is missing
+    Complete,
+    Compensate
+}
+
+fn recovery(step: &str) -> Recovery {
+    if step == TIME_ADDED { Recovery::Complete } else { Recovery::Compensate }
+}
+
+// escrows the acceptor's stake and records the saga, the challenge is ACCEPTING until the saga is over
+pub async fn begin(pool: &Pool<Postgres>, app_config: &AppConfig, actor: &Actor, challenge: &Challenge, acceptor: &User) -> AppResult<AcceptSaga> {
+    let acceptor_token = encrypt(&app_config.encryption, &acceptor.access_token)
+        .map_err(|e| AppError::Internal(format!("token encryption: {e}")))?;
+    let sats = challenge.sats.unwrap_or_default();
+    let opponent_sats = challenge.opponent_sats.unwrap_or(sats);
+
+    let mut tx = pool.begin().await?;
+
+    // only one accept gets past this
+    let updated = sqlx::query("UPDATE challenge SET status='ACCEPTING' WHERE id=$1 AND status='WAITING FOR ACCEPTANCE'")
+        .bind(challenge.id)
+        .execute(&mut tx).await?;
+    if updated.rows_affected() == 0 {
+        return Err(AppError::Validation("challenge is not waiting for acceptance".to_string()))
+    }
+
+    // the opponent puts up opponent_sats against the challenger's sats
+    let transaction = sqlx::query_as::<_,Transaction>("INSERT INTO lightningchess_transaction (username, ttype, detail, amount, state) VALUES ($1, $2, $3, $4, $5) RETURNING *")
+        .bind(&acceptor.username)
+        .bind("accept challenge")
+        .bind(format!("challenge vs {}", challenge.username))
+        .bind(-opponent_sats)
+        .bind("SETTLED")
+        .fetch_one(&mut tx).await?;
+    let change = BalanceChange::new(&acceptor.username, -opponent_sats, "escrow stake")
+        .challenge(challenge.id)
+        .transaction(transaction.transaction_id);
+    let balance = change_balance(&mut tx, actor, change).await?;
+    if balance.balance < 0 {
+        warn!(username = %acceptor.username, "balance is less than 0");
+        return Err(AppError::InsufficientFunds)
+    }
+    debug!(username = %acceptor.username, "escrowed stake");
+
+    let saga = sqlx::query_as::<_,AcceptSaga>("INSERT INTO accept_saga (challenge_id, username, acceptor_token, step) VALUES ($1, $2, $3, $4) RETURNING *")
+        .bind(challenge.id)
+        .bind(&acceptor.username)
+        .bind(&acceptor_token)
+        .bind(ESCROWED)
+        .fetch_one(&mut tx).await?;
+
+    tx.commit().await?;
+    info!(challenge_id = challenge.id, saga_id = saga.saga_id, "stake escrowed");
+    Ok(saga)
+}
+
+// moves the saga on from the step it's expected to be at, fails when recovery got to it first
+async fn advance(pool: &Pool<Postgres>, saga: &mut AcceptSaga, step: &str) -> AppResult<()> {
+    let updated = sqlx::query("UPDATE accept_saga SET step=$1, lichess_challenge_id=$2, updated_on=(now() at time zone 'utc') WHERE saga_id=$3 AND step=$4")
+        .bind(step)
+        .bind(&saga.lichess_challenge_id)
+        .bind(saga.saga_id)
+        .bind(&saga.step)
+        .execute(pool).await?;
+    if updated.rows_affected() == 0 {
+        return Err(AppError::Internal(format!("accept saga {} moved on from {}", saga.saga_id, saga.step)))
+    }
+    debug!(saga_id = saga.saga_id, step, "accept saga step");
+    saga.step = step.to_string();
+    Ok(())
+}
+
+async fn steps(pool: &Pool<Postgres>, app_config: &AppConfig, saga: &mut AcceptSaga, challenge: &Challenge, acceptor: &User, challenger_token: &str) -> Result<Challenge, Failure> {
+    let lichess = &app_config.lichess;
+
+    info!(challenge_id = challenge.id, "creating lichess challenge");
+    let lichess_challenge_response = create_lichess_challenge(lichess, acceptor, challenge).await
+        .map_err(|e| failed(Player::Acceptor, e))?;
+    let game_id = lichess_challenge_response.challenge.id.clone();
+    saga.lichess_challenge_id = Some(game_id.clone());
+    advance(pool, saga, CHALLENGE_CREATED).await?;
+
+    info!(lichess_challenge_id = %game_id, "accepting lichess challenge");
+    accept_lichess_challenge(lichess, challenger_token, &lichess_challenge_response).await
+        .map_err(|e| failed(Player::Challenger, e))?;
+    advance(pool, saga, CHALLENGE_ACCEPTED).await?;
+
+    if let Some((player, seconds)) = time_odds(challenge) {
+        let token = match player {
+            Player::Acceptor => &acceptor.access_token,
+            Player::Challenger => challenger_token
+        };
+        add_time(lichess, token, &game_id, seconds).await
+            .map_err(|e| failed(player, e))?;
+    }
+    advance(pool, saga, TIME_ADDED).await?;
+
+    Ok(complete(pool, saga).await?)
+}
+
+// the accepted challenge, once the game is set up on lichess
+async fn complete(pool: &Pool<Postgres>, saga: &AcceptSaga) -> AppResult<Challenge> {
+    let mut tx = pool.begin().await?;
+    let challenge = sqlx::query_as::<_,Challenge>("UPDATE challenge SET status='ACCEPTED', lichess_challenge_id=$1 WHERE id=$2 AND status='ACCEPTING' RETURNING *")
+        .bind(&saga.lichess_challenge_id)
+        .bind(saga.challenge_id)
+        .fetch_optional(&mut tx).await?
+        .ok_or_else(|| AppError::Internal(format!("challenge {} is not being accepted", saga.challenge_id)))?;
+    let updated = sqlx::query("UPDATE accept_saga SET step=$1, acceptor_token=NULL, updated_on=(now() at time zone 'utc') WHERE saga_id=$2 AND step=$3")
+        .bind(COMPLETED)
+        .bind(saga.saga_id)
+        .bind(TIME_ADDED)
+        .execute(&mut tx).await?;
+    if updated.rows_affected() == 0 {
+        return Err(AppError::Internal(format!("accept saga {} moved on from {}", saga.saga_id, TIME_ADDED)))
+    }
+    tx.commit().await?;
+    ACCEPT_SAGAS.with_label_values(&["completed"]).inc();
+    Ok(challenge)
+}
+
+// runs the lichess steps of an escrowed accept, undoing everything when one fails.
+// a compensation that fails too is retried by the recovery job
+pub async fn run(pool: &Pool<Postgres>, app_config: &AppConfig, actor: &Actor, mut saga: AcceptSaga, challenge: &Challenge, acceptor: &User, challenger_token: &str) -> Result<Challenge, Failure> {
+    let failure = match steps(pool, app_config, &mut saga, challenge, acceptor, challenger_token).await {
+        Ok(challenge) => return Ok(challenge),
+        Err(f) => f
+    };
+    warn!(saga_id = saga.saga_id, step = %saga.step, error = %failure.error, "accept failed, compensating");
+    // a token lichess rejected can't cancel anything
+    let acceptor_token = (failure.rejected != Some(Player::Acceptor)).then_some(acceptor.access_token.as_str());
+    let challenger_token = (failure.rejected != Some(Player::Challenger)).then_some(challenger_token);
+    if let Err(e) = compensate(pool, app_config, actor, &saga, challenge, acceptor_token, challenger_token, &failure.error.to_string()).await {
+        error!(saga_id = saga.saga_id, error = %e, "error compensating accept");
+    }
+    Err(failure)
+}
+
+// cancels the lichess challenge and gives the acceptor's stake back, the challenge waits for acceptance again
+#[allow(clippy::too_many_arguments)]
+async fn compensate(pool: &Pool<Postgres>, app_config: &AppConfig, actor: &Actor, saga: &AcceptSaga, challenge: &Challenge, acceptor_token: Option<&str>, challenger_token: Option<&str>, reason: &str) -> AppResult<()> {
+    // the lichess challenge id is kept even if recording its step failed
+    let claimed = sqlx::query("UPDATE accept_saga SET step=$1, error=LEFT($2, 1024), lichess_challenge_id=$3, updated_on=(now() at time zone 'utc') WHERE saga_id=$4 AND step=$5")
+        .bind(COMPENSATING)
+        .bind(reason)
+        .bind(&saga.lichess_challenge_id)
+        .bind(saga.saga_id)
+        .bind(&saga.step)
+        .execute(pool).await?;
+    if claimed.rows_affected() == 0 {
+        info!(saga_id = saga.saga_id, "accept saga already handled");
+        return Ok(())
+    }
+
+    if let Some(game_id) = saga.lichess_challenge_id.as_deref() {
+        match acceptor_token {
+            // with the challenger's token the game is aborted if the challenge was already accepted
+            Some(token) => match cancel_lichess_challenge(&app_config.lichess, token, game_id, challenger_token).await {
+                Ok(()) | Err(AppError::NotFound(_)) => info!(game_id, "lichess challenge cancelled"),
+                // lichess aborts games nobody moves in and expires challenges nobody accepts
+                Err(AppError::TokenRejected(_)) => warn!(game_id, "can't cancel the lichess challenge, leaving it to expire"),
+                Err(e) => return Err(e)
+            },
+            None => warn!(game_id, "no token to cancel the lichess challenge, leaving it to expire")
+        }
+    }
+
+    let sats = challenge.sats.unwrap_or_default();
+    let opponent_sats = challenge.opponent_sats.unwrap_or(sats);
+    let mut tx = pool.begin().await?;
+    let updated = sqlx::query("UPDATE challenge SET status='WAITING FOR ACCEPTANCE' WHERE id=$1 AND status='ACCEPTING'")
+        .bind(saga.challenge_id)
+        .execute(&mut tx).await?;
+    // the acceptor's stake was escrowed with the status change and goes back with it,
+    // the challenger's stays escrowed while the challenge waits
+    if updated.rows_affected() > 0 {
+        credit(&mut tx, actor, challenge, &saga.username, "challenge refund", &challenge.username, opponent_sats).await?;
+    }
+    sqlx::query("UPDATE accept_saga SET step=$1, acceptor_token=NULL, updated_on=(now() at time zone 'utc') WHERE saga_id=$2")
+        .bind(COMPENSATED)
+        .bind(saga.saga_id)
+        .execute(&mut tx).await?;
+    tx.commit().await?;
+    ACCEPT_SAGAS.with_label_values(&["compensated"]).inc();
+    info!(saga_id = saga.saga_id, challenge_id = saga.challenge_id, "accept compensated");
+    Ok(())
+}
+
+// the challenger's lichess token, plaintext ones stored before encryption get encrypted on the way
+pub async fn challenger_token(pool: &Pool<Postgres>, app_config: &AppConfig, challenge: &Challenge) -> AppResult<String> {
+    let token = challenge.challenger_token.as_deref()
+        .ok_or_else(|| AppError::Validation("challenge can no longer be accepted".to_string()))?;
+    if is_encrypted(token) {
+        return decrypt(&app_config.encryption, token).map_err(|e| AppError::Internal(format!("challenger token: {e}")))
+    }
+    let encrypted = encrypt(&app_config.encryption, token).map_err(|e| AppError::Internal(format!("challenger token: {e}")))?;
+    sqlx::query("UPDATE challenge SET challenger_token=$1 WHERE id=$2 AND challenger_token=$3")
+        .bind(encrypted)
+        .bind(challenge.id)
+        .bind(token)
+        .execute(pool).await?;
+    Ok(token.to_string())
+}
+
+fn decrypted(app_config: &AppConfig, token: Option<&str>) -> Option<String> {
+    let token = token?;
+    if !is_encrypted(token) {
+        return Some(token.to_string())
+    }
+    decrypt(&app_config.encryption, token)
+        .map_err(|e| warn!(error = %e, "error decrypting token"))
+        .ok()
+}
+
+async fn recover(pool: &Pool<Postgres>, app_config: &AppConfig, saga: &AcceptSaga) -> AppResult<()> {
+    if recovery(&saga.step) == Recovery::Complete {
+        complete(pool, saga).await?;
+        info!(saga_id = saga.saga_id, "accept completed");
+        return Ok(())
+    }
+    let challenge = sqlx::query_as::<_,Challenge>("SELECT * FROM challenge WHERE id=$1")
+        .bind(saga.challenge_id)
+        .fetch_optional(pool).await?
+        .ok_or_else(|| AppError::NotFound("challenge".to_string()))?;
+    let acceptor_token = decrypted(app_config, saga.acceptor_token.as_deref());
+    let challenger_token = decrypted(app_config, challenge.challenger_token.as_deref());
+    let reason = saga.error.as_deref().unwrap_or("left behind");
+    compensate(pool, app_config, &Actor::system("accept_recovery_job"), saga, &challenge, acceptor_token.as_deref(), challenger_token.as_deref(), reason).await
+}
+
+// finishes or undoes accepts that stopped midway, runs once on startup and then every minute
+pub async fn accept_recovery_job(pool: Pool<Postgres>, config: AppConfig) {
+    let mut interval = tokio::time::interval(Duration::from_secs(RECOVERY_INTERVAL_SECS));
+    loop {
+        interval.tick().await;
+        recover_stale_sagas(&pool, &config).instrument(info_span!("accept_recovery_job")).await;
+    }
+}
+
+async fn recover_stale_sagas(pool: &Pool<Postgres>, config: &AppConfig) {
+    let sagas_result = sqlx::query_as::<_,AcceptSaga>("SELECT * FROM accept_saga WHERE step NOT IN ($1, $2) \
+        AND updated_on < $3 ORDER BY saga_id")
+        .bind(COMPLETED)
+        .bind(COMPENSATED)
+        .bind(Utc::now().naive_utc() - chrono::Duration::seconds(STALE_AFTER_SECS))
+        .fetch_all(pool).await;
+    let sagas = match sagas_result {
+        Ok(s) => s,
+        Err(e) => {
+            error!(error = %e, "error loading stale accept sagas");
+            return
+        }
+    };
+
+    for saga in sagas {
+        info!(saga_id = saga.saga_id, step = %saga.step, "recovering accept saga");
+        if let Err(e) = recover(pool, config, &saga).await {
+            ACCEPT_SAGAS.with_label_values(&["recovery_failed"]).inc();
+            error!(saga_id = saga.saga_id, error = %e, "error recovering accept saga");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::test_challenge;
+
+    fn clocks(time_limit: i32, opponent_time_limit: i32) -> Challenge {
+        Challenge {
+            time_limit: Some(time_limit),
+            opponent_time_limit: Some(opponent_time_limit),
+            status: Some("ACCEPTING".to_string()),
+            ..test_challenge()
+        }
+    }
+
+    #[test]
+    fn longer_clock_is_topped_up_by_the_other_player() {
+        assert_eq!(time_odds(&clocks(300, 300)), None);
+        // the acceptor plays on the longer clock, the challenger gives them the difference
+        assert_eq!(time_odds(&clocks(180, 300)), Some((Player::Challenger, 120)));
+        assert_eq!(time_odds(&clocks(300, 180)), Some((Player::Acceptor, 120)));
+    }
+
+    #[test]
+    fn only_fully_set_up_games_are_completed() {
+        assert_eq!(recovery(TIME_ADDED), Recovery::Complete);
+        for step in [ESCROWED, CHALLENGE_CREATED, CHALLENGE_ACCEPTED, COMPENSATING] {
+            assert_eq!(recovery(step), Recovery::Compensate);
+        }
+    }
+
+    #[test]
+    fn rejected_tokens_are_attributed() {
+        let failure = failed(Player::Challenger, AppError::TokenRejected("accept challenge".to_string()));
+        assert_eq!(failure.rejected, Some(Player::Challenger));
+        let failure = failed(Player::Acceptor, AppError::Lichess("create challenge: 500".to_string()));
+        assert_eq!(failure.rejected, None);
+    }
+}
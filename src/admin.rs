@@ -0,0 +1,418 @@
+use serde::Serialize;
+use sqlx::{Pool, Postgres};
+use tracing::{error, info, warn};
+use crate::config::{AppConfig, EncryptionConfig, LndConfig};
+use crate::crypto;
+use crate::errors::{AppError, AppResult};
+use crate::lightning::hodl_invoices::lookup_hodl_invoice;
+use crate::lightning::node::{channel_balance, wallet_balance};
+use crate::lightning::payment::{make_payment, track_payment, PaymentOutcome};
+use crate::models::{Balance, Challenge, Dispute, Transaction};
+use crate::ledger::{change_balance, Actor, BalanceChange};
+use crate::disputes::{announce_resolution, close_dispute, color_player, held_payouts, lock_challenge, DisputeOutcome};
+use crate::settlement::{move_held_payout, settle_stakes, void_waiting, HoldOutcome};
+
+// operator actions on the money system, shared by the cli and the admin api
+
+// reasons are stored as the transaction and audit log detail
+const REASON_MAX_LEN: usize = 255;
+
+fn check_reason(reason: &str) -> AppResult<&str> {
+    let reason = reason.trim();
+    if reason.is_empty() {
+        return Err(AppError::Validation("reason required".to_string()))
+    }
+    if reason.chars().count() > REASON_MAX_LEN {
+        return Err(AppError::Validation(format!("reason must be at most {REASON_MAX_LEN} characters")))
+    }
+    Ok(reason)
+}
+
+// credits (positive amount) or debits (negative amount) a user, the reason is kept as the transaction detail
+pub async fn adjust_balance(pool: &Pool<Postgres>, actor: &Actor, username: &str, amount: i64, reason: &str) -> AppResult<Balance> {
+    if amount == 0 {
+        return Err(AppError::Validation("amount can't be zero".to_string()))
+    }
+    let reason = check_reason(reason)?;
+
+    let mut tx = pool.begin().await?;
+    let ttype = if amount > 0 { "admin credit" } else { "admin debit" };
+    let transaction = sqlx::query_as::<_,Transaction>("INSERT INTO lightningchess_transaction (username, ttype, detail, amount, state) VALUES ($1, $2, $3, $4, $5) RETURNING *")
+        .bind(username)
+        .bind(ttype)
+        .bind(reason)
+        .bind(amount)
+        .bind("SETTLED")
+        .fetch_one(&mut tx).await?;
+
+    let change = BalanceChange::new(username, amount, ttype)
+        .transaction(transaction.transaction_id)
+        .detail(reason);
+    let balance = change_balance(&mut tx, actor, change).await?;
+    if balance.balance < 0 {
+        return Err(AppError::InsufficientFunds)
+    }
+
+    tx.commit().await?;
+    info!(username, amount, "balance adjusted");
+    Ok(balance)
+}
+
+async fn load_challenge(pool: &Pool<Postgres>, challenge_id: i32) -> AppResult<Challenge> {
+    sqlx::query_as::<_,Challenge>("SELECT * FROM challenge WHERE id=$1")
+        .bind(challenge_id)
+        .fetch_optional(pool).await?
+        .ok_or_else(|| AppError::NotFound("challenge".to_string()))
+}
+
+// settles an accepted challenge without waiting for lichess, winner is a color or None for a draw.
+// an open dispute on the challenge is resolved along with it, a draw counts as a refund
+pub async fn force_settle(pool: &Pool<Postgres>, actor: &Actor, challenge_id: i32, winner: Option<&str>) -> AppResult<Challenge> {
+    if !matches!(winner, None | Some("white") | Some("black")) {
+        return Err(AppError::Validation("winner must be white, black or draw".to_string()))
+    }
+    let mut tx = pool.begin().await?;
+    let (challenge, dispute) = lock_challenge(&mut tx, challenge_id).await?
+        .ok_or_else(|| AppError::NotFound("challenge".to_string()))?;
+    // winnings still go through the payout hold job so the fair play checks apply
+    if !settle_stakes(&mut tx, actor, &challenge, "FINISHED", winner, 0).await? {
+        return Err(AppError::Validation("challenge is not accepted".to_string()))
+    }
+    let outcome = match winner.and_then(|color| color_player(&challenge, color)) {
+        Some(player) => DisputeOutcome::Award(player.to_string()),
+        None => DisputeOutcome::Refund
+    };
+    resolve_with(pool, tx, actor, &challenge, dispute, outcome).await?;
+    load_challenge(pool, challenge_id).await
+}
+
+// gives both players their stake back, either from escrow or by reversing held winnings.
+// an open dispute on the challenge is resolved along with it
+pub async fn refund_challenge(pool: &Pool<Postgres>, actor: &Actor, challenge_id: i32) -> AppResult<Challenge> {
+    let mut tx = pool.begin().await?;
+    let (challenge, dispute) = lock_challenge(&mut tx, challenge_id).await?
+        .ok_or_else(|| AppError::NotFound("challenge".to_string()))?;
+    refund_stakes(&mut tx, actor, &challenge).await?;
+    resolve_with(pool, tx, actor, &challenge, dispute, DisputeOutcome::Refund).await?;
+    load_challenge(pool, challenge_id).await
+}
+
+// the stake movements of refund_challenge, in a transaction holding the challenge lock
+pub async fn refund_stakes(tx: &mut sqlx::Transaction<'_, Postgres>, actor: &Actor, challenge: &Challenge) -> AppResult<()> {
+    match challenge.status.as_deref() {
+        Some("ACCEPTED") | Some("STARTED") => {
+            settle_stakes(tx, actor, challenge, "REFUNDED", None, 0).await?;
+        },
+        Some("FINISHED") => {
+            let payouts = held_payouts(tx, challenge).await?;
+            if payouts.is_empty() {
+                return Err(AppError::Validation("winnings were already released, adjust balances instead".to_string()))
+            }
+            for payout in &payouts {
+                move_held_payout(tx, actor, payout, challenge, HoldOutcome::Refund).await?;
+            }
+        },
+        _ => return Err(AppError::Validation("only accepted or finished challenges can be refunded".to_string()))
+    }
+    Ok(())
+}
+
+// commits the admin's decision, closing the challenge's open dispute in the same transaction
+async fn resolve_with(pool: &Pool<Postgres>, mut tx: sqlx::Transaction<'_, Postgres>, actor: &Actor, challenge: &Challenge, dispute: Option<Dispute>, outcome: DisputeOutcome) -> AppResult<()> {
+    let dispute = match dispute {
+        Some(d) => d,
+        None => {
+            tx.commit().await?;
+            return Ok(())
+        }
+    };
+    let dispute = close_dispute(&mut tx, actor, &dispute, &outcome, None).await?;
+    tx.commit().await?;
+    announce_resolution(pool, challenge, &dispute, &outcome, None).await;
+    Ok(())
+}
+
+// cancels a challenge nobody accepted yet, the challenger gets their stake back
+pub async fn void_challenge(pool: &Pool<Postgres>, actor: &Actor, challenge_id: i32) -> AppResult<Challenge> {
+    void_waiting(pool, actor, challenge_id).await?
+        .ok_or_else(|| AppError::Validation("only challenges waiting for acceptance can be voided".to_string()))
+}
+
+// looks an open invoice or withdrawal up on lnd and applies its final state, key is a transaction id or payment hash
+pub async fn recheck_transaction(pool: &Pool<Postgres>, actor: &Actor, lnd: &LndConfig, key: &str) -> AppResult<Transaction> {
+    let query = match key.parse::<i32>() {
+        Ok(id) => sqlx::query_as::<_,Transaction>("SELECT * FROM lightningchess_transaction WHERE transaction_id=$1").bind(id),
+        Err(_) => sqlx::query_as::<_,Transaction>("SELECT * FROM lightningchess_transaction WHERE payment_hash=$1").bind(key)
+    };
+    let transaction = query.fetch_optional(pool).await?
+        .ok_or_else(|| AppError::NotFound("transaction".to_string()))?;
+
+    // (new state, amount, balance change), for withdrawals what the balance should have moved in total
+    let resolution = match transaction.ttype.as_str() {
+        "invoice" => {
+            let payment_addr = transaction.payment_addr.as_deref().ok_or_else(|| AppError::Validation("invoice has no payment_addr".to_string()))?;
+            let invoice = lookup_hodl_invoice(lnd, payment_addr).await?;
+            let paid = invoice.amt_paid_sat.parse::<i64>().unwrap_or(0);
+            match invoice.state.as_str() {
+                "SETTLED" => Some(("SETTLED", paid, paid)),
+                "CANCELED" => Some(("CANCELED", 0, 0)),
+                _ => None
+            }
+        },
+        "withdrawal" => {
+            // the balance change is worked out against the audit log below, open withdrawals
+            // from before they were debited up front were never debited
+            let payment_hash = transaction.payment_hash.as_deref().ok_or_else(|| AppError::Validation("withdrawal has no payment_hash".to_string()))?;
+            let payment = track_payment(lnd, payment_hash).await?;
+            match payment.status.as_str() {
+                "SUCCEEDED" => Some(("SETTLED", transaction.amount, transaction.amount)),
+                "FAILED" => Some(("FAILED", transaction.amount, 0)),
+                _ => None
+            }
+        },
+        _ => return Err(AppError::Validation("only invoices and withdrawals can be rechecked".to_string()))
+    };
+
+    let (state, amount, balance_change) = match resolution {
+        Some(r) if transaction.state == "OPEN" => r,
+        _ => {
+            info!(transaction_id = transaction.transaction_id, state = %transaction.state, "nothing to apply");
+            return Ok(transaction)
+        }
+    };
+
+    let mut tx = pool.begin().await?;
+    let updated = sqlx::query_as::<_,Transaction>("UPDATE lightningchess_transaction SET state=$1, amount=$2 WHERE transaction_id=$3 AND state='OPEN' RETURNING *")
+        .bind(state)
+        .bind(amount)
+        .bind(transaction.transaction_id)
+        .fetch_optional(&mut tx).await?;
+    let updated = match updated {
+        Some(t) => t,
+        None => return Ok(transaction)
+    };
+    let balance_change = match updated.ttype.as_str() {
+        "withdrawal" => balance_change - recorded_change(&mut tx, updated.transaction_id).await?,
+        _ => balance_change
+    };
+    if balance_change != 0 {
+        let action = match (updated.ttype.as_str(), balance_change > 0) {
+            ("invoice", _) => "deposit",
+            (_, true) => "failed withdrawal refund",
+            _ => "withdrawal"
+        };
+        let change = BalanceChange::new(&updated.username, balance_change, action).transaction(updated.transaction_id);
+        change_balance(&mut tx, actor, change).await?;
+    }
+    tx.commit().await?;
+    info!(transaction_id = updated.transaction_id, state, "transaction rechecked");
+    Ok(updated)
+}
+
+// what the ledger already moved for a transaction
+async fn recorded_change(tx: &mut sqlx::Transaction<'_, Postgres>, transaction_id: i32) -> Result<i64, sqlx::Error> {
+    sqlx::query_scalar("SELECT COALESCE(SUM(amount), 0)::BIGINT FROM audit_log WHERE transaction_id=$1")
+        .bind(transaction_id)
+        .fetch_one(&mut *tx).await
+}
+
+async fn claim_withdrawal(tx: &mut sqlx::Transaction<'_, Postgres>, transaction_id: i32, state: &str) -> AppResult<Transaction> {
+    sqlx::query_as::<_,Transaction>("UPDATE lightningchess_transaction SET state=$1 WHERE transaction_id=$2 AND ttype='withdrawal' AND state='AWAITING APPROVAL' RETURNING *")
+        .bind(state)
+        .bind(transaction_id)
+        .fetch_optional(&mut *tx).await?
+        .ok_or_else(|| AppError::Validation("withdrawal is not awaiting approval".to_string()))
+}
+
+// pays a withdrawal that was held for approval, the balance was already debited when it was requested
+pub async fn approve_withdrawal(pool: &Pool<Postgres>, actor: &Actor, app_config: &AppConfig, transaction_id: i32) -> AppResult<Transaction> {
+    // claimed as OPEN first so it can't be paid twice. the balance doesn't move,
+    // the zero change puts who approved it in the audit log
+    let mut tx = pool.begin().await?;
+    let withdrawal = claim_withdrawal(&mut tx, transaction_id, "OPEN").await?;
+    let change = BalanceChange::new(&withdrawal.username, 0, "withdrawal approved").transaction(transaction_id);
+    change_balance(&mut tx, actor, change).await?;
+    tx.commit().await?;
+    info!(transaction_id, "withdrawal approved");
+
+    let payment_request = withdrawal.payment_request.as_deref().unwrap_or_default();
+    let outcome = match make_payment(&app_config.lnd, payment_request, app_config.stakes.withdrawal_fee_limit_sats).await {
+        Ok(o) => o,
+        Err(e) => {
+            // lnd may still pay it, it stays OPEN and debited until rechecked
+            error!(transaction_id, error = %e, "approved withdrawal outcome unknown, left open for a recheck");
+            return Err(e)
+        }
+    };
+
+    if let PaymentOutcome::Failed(reason) = outcome {
+        let mut tx = pool.begin().await?;
+        let failed = sqlx::query("UPDATE lightningchess_transaction SET state='FAILED' WHERE transaction_id=$1 AND state='OPEN'")
+            .bind(transaction_id)
+            .execute(&mut tx).await?;
+        if failed.rows_affected() > 0 {
+            let change = BalanceChange::new(&withdrawal.username, -withdrawal.amount, "failed withdrawal refund").transaction(transaction_id);
+            change_balance(&mut tx, actor, change).await?;
+        }
+        tx.commit().await?;
+        warn!(transaction_id, reason = %reason, "approved withdrawal failed, refunded");
+        return Err(AppError::Lnd(format!("payment failed {reason}")))
+    }
+
+    let withdrawal = sqlx::query_as::<_,Transaction>("UPDATE lightningchess_transaction SET state='SETTLED' WHERE transaction_id=$1 AND state='OPEN' RETURNING *")
+        .bind(transaction_id)
+        .fetch_optional(pool).await?
+        .ok_or_else(|| AppError::Validation("withdrawal is no longer open".to_string()))?;
+    info!(transaction_id, "approved withdrawal settled");
+    Ok(withdrawal)
+}
+
+// refuses a withdrawal that was held for approval and credits the amount back
+pub async fn reject_withdrawal(pool: &Pool<Postgres>, actor: &Actor, transaction_id: i32, reason: &str) -> AppResult<Transaction> {
+    let reason = check_reason(reason)?;
+    let mut tx = pool.begin().await?;
+    let withdrawal = sqlx::query_as::<_,Transaction>("UPDATE lightningchess_transaction SET state='REJECTED', detail=$1 WHERE transaction_id=$2 AND ttype='withdrawal' AND state='AWAITING APPROVAL' RETURNING *")
+        .bind(reason)
+        .bind(transaction_id)
+        .fetch_optional(&mut tx).await?
+        .ok_or_else(|| AppError::Validation("withdrawal is not awaiting approval".to_string()))?;
+    let change = BalanceChange::new(&withdrawal.username, -withdrawal.amount, "withdrawal rejected")
+        .transaction(transaction_id)
+        .detail(reason);
+    change_balance(&mut tx, actor, change).await?;
+    tx.commit().await?;
+    info!(transaction_id, "withdrawal rejected");
+    Ok(withdrawal)
+}
+
+#[derive(Serialize)]
+pub struct SolvencyReport {
+    // what we owe users
+    pub user_balances: i64,
+    pub held_payouts: i64,
+    pub escrowed: i64,
+    // debited from balances but not paid out yet
+    pub pending_withdrawals: i64,
+    pub liabilities: i64,
+    // what the node holds
+    pub channel_local: i64,
+    pub onchain_confirmed: i64,
+    pub assets: i64,
+    pub surplus: i64
+}
+
+pub fn solvency_report(user_balances: i64, held_payouts: i64, escrowed: i64, pending_withdrawals: i64, channel_local: i64, onchain_confirmed: i64) -> SolvencyReport {
+    let liabilities = user_balances + held_payouts + escrowed + pending_withdrawals;
+    let assets = channel_local + onchain_confirmed;
+    SolvencyReport { user_balances, held_payouts, escrowed, pending_withdrawals, liabilities, channel_local, onchain_confirmed, assets, surplus: assets - liabilities }
+}
+
+pub async fn solvency(pool: &Pool<Postgres>, app_config: &AppConfig) -> AppResult<SolvencyReport> {
+    let user_balances: i64 = sqlx::query_scalar("SELECT COALESCE(SUM(balance), 0)::BIGINT FROM lightningchess_balance")
+        .fetch_one(pool).await?;
+    let held_payouts: i64 = sqlx::query_scalar("SELECT COALESCE(SUM(amount), 0)::BIGINT FROM lightningchess_transaction WHERE state='HELD'")
+        .fetch_one(pool).await?;
+    // only the challenger's stake is escrowed until the challenge is accepted
+    let escrowed: i64 = sqlx::query_scalar("SELECT COALESCE(SUM(COALESCE(sats, 0) + CASE WHEN status='WAITING FOR ACCEPTANCE' THEN 0 ELSE COALESCE(opponent_sats, sats, 0) END), 0)::BIGINT \
+        FROM challenge WHERE status IN ('WAITING FOR ACCEPTANCE', 'ACCEPTING', 'ACCEPTED', 'STARTED')")
+        .fetch_one(pool).await?;
+    let pending_withdrawals: i64 = sqlx::query_scalar("SELECT COALESCE(-SUM(amount), 0)::BIGINT FROM lightningchess_transaction WHERE ttype='withdrawal' AND state IN ('AWAITING APPROVAL', 'OPEN')")
+        .fetch_one(pool).await?;
+
+    let (channels, wallet) = tokio::try_join!(channel_balance(&app_config.lnd), wallet_balance(&app_config.lnd))?;
+    let channel_local = channels.local_balance.sat.parse::<i64>().unwrap_or(0);
+    let onchain_confirmed = wallet.confirmed_balance.parse::<i64>().unwrap_or(0);
+    Ok(solvency_report(user_balances, held_payouts, escrowed, pending_withdrawals, channel_local, onchain_confirmed))
+}
+
+#[derive(Serialize, Default, Debug)]
+pub struct RotationReport {
+    pub challenges: u64,
+    pub sessions: u64,
+    // couldn't be decrypted with any configured key
+    pub failed: u64
+}
+
+// re-encrypts the value with the active key, None when there's nothing to do.
+// plaintext challenger tokens from before encryption get encrypted
+fn rotated_token(keys: &EncryptionConfig, value: &str) -> Result<Option<String>, String> {
+    if crypto::is_encrypted(value) {
+        crypto::rotate(keys, value)
+    } else {
+        crypto::encrypt(keys, value).map(Some)
+    }
+}
+
+// moves every stored lichess token onto the active key so retired keys can be dropped from the config
+pub async fn rotate_token_keys(pool: &Pool<Postgres>, keys: &EncryptionConfig) -> AppResult<RotationReport> {
+    let mut report = RotationReport::default();
+
+    let challenges: Vec<(i32, String)> = sqlx::query_as("SELECT id, challenger_token FROM challenge WHERE challenger_token IS NOT NULL")
+        .fetch_all(pool).await?;
+    for (id, token) in challenges {
+        match rotated_token(keys, &token) {
+            Ok(Some(rotated)) => {
+                sqlx::query("UPDATE challenge SET challenger_token=$1 WHERE id=$2 AND challenger_token=$3")
+                    .bind(rotated)
+                    .bind(id)
+                    .bind(token)
+                    .execute(pool).await?;
+                report.challenges += 1;
+            },
+            Ok(None) => (),
+            Err(e) => {
+                warn!(id, error = %e, "can't rotate challenger token");
+                report.failed += 1;
+            }
+        }
+    }
+
+    let sessions: Vec<(String, String)> = sqlx::query_as("SELECT session_hash, lichess_token FROM user_session WHERE revoked_on IS NULL")
+        .fetch_all(pool).await?;
+    for (hash, token) in sessions {
+        match crypto::rotate(keys, &token) {
+            Ok(Some(rotated)) => {
+                sqlx::query("UPDATE user_session SET lichess_token=$1 WHERE session_hash=$2")
+                    .bind(rotated)
+                    .bind(hash)
+                    .execute(pool).await?;
+                report.sessions += 1;
+            },
+            Ok(None) => (),
+            Err(e) => {
+                warn!(error = %e, "can't rotate session token");
+                report.failed += 1;
+            }
+        }
+    }
+
+    info!(challenges = report.challenges, sessions = report.sessions, failed = report.failed, key = %keys.active_key, "token keys rotated");
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn solvency_surplus() {
+        let report = solvency_report(1_000, 200, 300, 100, 1_200, 500);
+        assert_eq!(report.liabilities, 1_600);
+        assert_eq!(report.assets, 1_700);
+        assert_eq!(report.surplus, 100);
+    }
+
+    #[test]
+    fn solvency_shortfall() {
+        let report = solvency_report(1_000, 0, 0, 0, 400, 0);
+        assert_eq!(report.surplus, -600);
+    }
+
+    #[test]
+    fn reasons_fit_the_detail_column() {
+        assert_eq!(check_reason("  goodwill  ").unwrap(), "goodwill");
+        assert!(check_reason("   ").is_err());
+        assert!(check_reason(&"é".repeat(REASON_MAX_LEN)).is_ok());
+        assert!(check_reason(&"x".repeat(REASON_MAX_LEN + 1)).is_err());
+    }
+}
@@ -0,0 +1,116 @@
+use rocket::figment::Figment;
+use sqlx::postgres::PgPoolOptions;
+use sqlx::{Pool, Postgres};
+use serde::Serialize;
+use crate::{admin, config, migrate, reconcile};
+use crate::errors::AppError;
+use crate::ledger::Actor;
+
+const USAGE: &str = "usage: lightningchess [command]
+  migrate status|run|revert
+  credit <username> <sats> <reason>
+  debit <username> <sats> <reason>
+  challenge settle <id> white|black|draw
+  challenge refund <id>
+  challenge void <id>
+  recheck <transaction id|payment hash>
+  solvency
+  reconcile
+  rotate-keys";
+
+// subcommands run against the configured database instead of starting the server
+pub async fn run(args: &[String], figment: &Figment) -> Result<(), String> {
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    match args.as_slice() {
+        ["migrate", "status"] => migrate_status(&connect(figment).await?).await,
+        ["migrate", "run"] => migrate::run(&connect(figment).await?).await.map_err(|e| e.to_string()),
+        ["migrate", "revert"] => migrate_revert(&connect(figment).await?).await,
+        ["credit", username, sats, reason @ ..] if !reason.is_empty() => {
+            let balance = admin::adjust_balance(&connect(figment).await?, &Actor::cli(), username, parse_sats(sats)?, &reason.join(" ")).await;
+            print_json(balance)
+        },
+        ["debit", username, sats, reason @ ..] if !reason.is_empty() => {
+            let balance = admin::adjust_balance(&connect(figment).await?, &Actor::cli(), username, -parse_sats(sats)?, &reason.join(" ")).await;
+            print_json(balance)
+        },
+        ["challenge", "settle", id, winner] => {
+            let winner = if *winner == "draw" { None } else { Some(*winner) };
+            print_json(admin::force_settle(&connect(figment).await?, &Actor::cli(), parse_id(id)?, winner).await)
+        },
+        ["challenge", "refund", id] => print_json(admin::refund_challenge(&connect(figment).await?, &Actor::cli(), parse_id(id)?).await),
+        ["challenge", "void", id] => print_json(admin::void_challenge(&connect(figment).await?, &Actor::cli(), parse_id(id)?).await),
+        ["recheck", key] => {
+            let app_config = load_config(figment)?;
+            print_json(admin::recheck_transaction(&connect(figment).await?, &Actor::cli(), &app_config.lnd, key).await)
+        },
+        ["solvency"] => {
+            let app_config = load_config(figment)?;
+            print_json(admin::solvency(&connect(figment).await?, &app_config).await)
+        },
+        ["reconcile"] => {
+            let app_config = load_config(figment)?;
+            let findings = reconcile::reconcile(&connect(figment).await?, &app_config).await.map_err(|e| e.to_string())?;
+            for f in &findings {
+                println!("{:<30}{:<12}{}", f.kind, f.transaction_id.map(|id| id.to_string()).unwrap_or_default(), f.detail);
+            }
+            println!("{} discrepancies", findings.len());
+            Ok(())
+        },
+        ["rotate-keys"] => {
+            let app_config = load_config(figment)?;
+            print_json(admin::rotate_token_keys(&connect(figment).await?, &app_config.encryption).await)
+        },
+        _ => Err(USAGE.to_string())
+    }
+}
+
+fn parse_sats(sats: &str) -> Result<i64, String> {
+    match sats.parse::<i64>() {
+        Ok(s) if s > 0 => Ok(s),
+        _ => Err(format!("sats must be a positive number, got {sats}"))
+    }
+}
+
+fn parse_id(id: &str) -> Result<i32, String> {
+    id.parse::<i32>().map_err(|_| format!("invalid challenge id {id}"))
+}
+
+fn load_config(figment: &Figment) -> Result<config::AppConfig, String> {
+    config::load(figment).map_err(|errors| errors.join("\n"))
+}
+
+// results are printed as the same json the api returns
+fn print_json<T: Serialize>(result: Result<T, AppError>) -> Result<(), String> {
+    let value = result.map_err(|e| e.to_string())?;
+    println!("{}", serde_json::to_string_pretty(&value).map_err(|e| e.to_string())?);
+    Ok(())
+}
+
+async fn connect(figment: &Figment) -> Result<Pool<Postgres>, String> {
+    let db_url: String = figment.extract_inner("db_url").map_err(|e| e.to_string())?;
+    PgPoolOptions::new()
+        .max_connections(1)
+        .connect(&db_url).await
+        .map_err(|e| format!("error connecting to database: {e}"))
+}
+
+async fn migrate_status(pool: &Pool<Postgres>) -> Result<(), String> {
+    let statuses = migrate::status(pool).await.map_err(|e| e.to_string())?;
+    for s in statuses {
+        let state = match (s.applied, s.checksum_mismatch) {
+            (true, true) => "applied (checksum mismatch)",
+            (true, false) => "applied",
+            (false, _) => "pending"
+        };
+        println!("{:<16}{:<28}{}", s.version, state, s.description);
+    }
+    Ok(())
+}
+
+async fn migrate_revert(pool: &Pool<Postgres>) -> Result<(), String> {
+    match migrate::revert(pool).await.map_err(|e| e.to_string())? {
+        Some(version) => println!("reverted {version}"),
+        None => println!("no migrations to revert")
+    }
+    Ok(())
+}
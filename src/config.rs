@@ -1,26 +1,365 @@
+use std::collections::HashMap;
+use std::fs;
 use rocket::{Build, Rocket};
-use crate::AppConfig;
+use rocket::figment::Figment;
+use rocket::figment::providers::Env;
+use serde::Deserialize;
+use sqlx::postgres::PgPoolOptions;
+use tracing::{error, info};
+use crate::migrate;
+use crate::sessions::session_cache;
+
+#[derive(Deserialize, Clone)]
+pub struct AppConfig {
+    pub url: String,
+    pub fe_url: String,
+    pub db_url: String,
+    #[serde(default = "default_db_pool_size")]
+    pub db_pool_size: u32,
+    // apply pending migrations at ignition, otherwise run `lightningchess migrate run`
+    #[serde(default)]
+    pub run_migrations: bool,
+    pub lnd: LndConfig,
+    #[serde(default)]
+    pub lichess: LichessConfig,
+    #[serde(default)]
+    pub stakes: StakeConfig,
+    #[serde(default)]
+    pub admin: AdminConfig,
+    #[serde(default)]
+    pub session: SessionConfig,
+    #[serde(default)]
+    pub encryption: EncryptionConfig
+}
+
+#[derive(Deserialize, Clone)]
+pub struct LndConfig {
+    pub url: String,
+    // pem file, only needed when the node doesn't have a publicly trusted certificate
+    pub tls_cert_path: Option<String>,
+    // hex encoded, or read from macaroon_path
+    pub macaroon: Option<String>,
+    pub macaroon_path: Option<String>,
+    // long enough for payments, which lnd gives up on after 10 seconds
+    #[serde(default = "default_lnd_timeout_secs")]
+    pub timeout_secs: u64
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct LichessConfig {
+    pub url: String,
+    pub client_id: String,
+    pub timeout_secs: u64
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct StakeConfig {
+    pub min_sats: i64,
+    pub max_sats: i64,
+    // max routing fee we pay on withdrawals
+    pub withdrawal_fee_limit_sats: i64,
+    // hours game payouts are held before they become withdrawable
+    pub payout_hold_hours: i64
+}
+
+#[derive(Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct AdminConfig {
+    // lichess usernames allowed to use the admin api
+    pub usernames: Vec<String>,
+    // sent as X-Admin-Key by scripts that don't log in through lichess
+    pub api_key: Option<String>,
+    // withdrawals above this wait for an admin to approve them
+    pub withdrawal_approval_sats: Option<i64>
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct SessionConfig {
+    pub ttl_days: i64,
+    // how long a loaded session is trusted before it's read from the database again
+    pub cache_ttl_secs: u64,
+    pub cache_idle_secs: u64
+}
+
+// keys stored lichess tokens are encrypted with, by key id. new tokens use active_key,
+// older keys are kept until `lightningchess rotate-keys` has moved everything to the active one
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct EncryptionConfig {
+    pub active_key: String,
+    // hex encoded 32 byte keys
+    pub keys: HashMap<String, String>
+}
+
+impl AdminConfig {
+    pub fn is_admin(&self, username: &str) -> bool {
+        self.usernames.iter().any(|u| u.eq_ignore_ascii_case(username))
+    }
+
+    pub fn needs_approval(&self, withdrawal_sats: i64) -> bool {
+        matches!(self.withdrawal_approval_sats, Some(limit) if withdrawal_sats > limit)
+    }
+}
+
+fn default_db_pool_size() -> u32 {
+    5
+}
+
+fn default_lnd_timeout_secs() -> u64 {
+    30
+}
+
+impl Default for LichessConfig {
+    fn default() -> Self {
+        LichessConfig {
+            url: "https://lichess.org".to_string(),
+            client_id: "lightningchess".to_string(),
+            timeout_secs: 10
+        }
+    }
+}
+
+impl Default for StakeConfig {
+    fn default() -> Self {
+        StakeConfig {
+            min_sats: 100,
+            max_sats: 3_000_000,
+            withdrawal_fee_limit_sats: 10,
+            payout_hold_hours: 72
+        }
+    }
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        SessionConfig { ttl_days: 30, cache_ttl_secs: 300, cache_idle_secs: 60 }
+    }
+}
+
+impl Default for EncryptionConfig {
+    fn default() -> Self {
+        EncryptionConfig {
+            active_key: "k1".to_string(),
+            keys: HashMap::new()
+        }
+    }
+}
+
+impl EncryptionConfig {
+    pub fn key_bytes(&self, key_id: &str) -> Option<Vec<u8>> {
+        self.keys.get(key_id).and_then(|k| hex::decode(k).ok()).filter(|k| k.len() == 32)
+    }
+}
+
+impl StakeConfig {
+    pub fn allowed(&self, sats: i64) -> bool {
+        (self.min_sats..=self.max_sats).contains(&sats)
+    }
+}
+
+impl LndConfig {
+    // hex encoded macaroon sent with every lnd call
+    pub fn macaroon_hex(&self) -> Result<String, String> {
+        match (&self.macaroon, &self.macaroon_path) {
+            (Some(m), _) => Ok(m.trim().to_string()),
+            (None, Some(path)) => fs::read(path)
+                .map(hex::encode)
+                .map_err(|e| format!("lnd.macaroon_path {path}: {e}")),
+            (None, None) => Err("lnd.macaroon or lnd.macaroon_path is required".to_string())
+        }
+    }
+}
+
+impl AppConfig {
+    // every problem is reported at once so a bad deploy can be fixed in one go
+    pub fn validate(&self) -> Vec<String> {
+        let mut errors = vec![];
+        for (key, value) in [("url", &self.url), ("fe_url", &self.fe_url), ("lnd.url", &self.lnd.url), ("lichess.url", &self.lichess.url)] {
+            if !value.starts_with("http://") && !value.starts_with("https://") {
+                errors.push(format!("{key} must be an http(s) url, got {value:?}"));
+            }
+        }
+        if !self.db_url.starts_with("postgres://") && !self.db_url.starts_with("postgresql://") {
+            errors.push("db_url must be a postgres url".to_string());
+        }
+        if self.db_pool_size == 0 {
+            errors.push("db_pool_size must be at least 1".to_string());
+        }
+        if self.lichess.client_id.is_empty() {
+            errors.push("lichess.client_id is required".to_string());
+        }
+        match self.lnd.macaroon_hex() {
+            Ok(m) if hex::decode(&m).is_err() || m.is_empty() => errors.push("lnd macaroon must be hex encoded".to_string()),
+            Ok(_) => (),
+            Err(e) => errors.push(e)
+        }
+        if self.lichess.timeout_secs == 0 || self.lnd.timeout_secs == 0 {
+            errors.push("lichess.timeout_secs and lnd.timeout_secs must be positive".to_string());
+        }
+        if let Some(path) = &self.lnd.tls_cert_path {
+            if let Err(e) = fs::read(path) {
+                errors.push(format!("lnd.tls_cert_path {path}: {e}"));
+            }
+        }
+        let stakes = &self.stakes;
+        if stakes.min_sats <= 0 || stakes.min_sats > stakes.max_sats {
+            errors.push("stakes.min_sats must be positive and at most stakes.max_sats".to_string());
+        }
+        if stakes.withdrawal_fee_limit_sats < 0 || stakes.payout_hold_hours < 0 {
+            errors.push("stakes.withdrawal_fee_limit_sats and stakes.payout_hold_hours can't be negative".to_string());
+        }
+        if matches!(&self.admin.api_key, Some(key) if key.len() < 32) {
+            errors.push("admin.api_key must be at least 32 characters".to_string());
+        }
+        if !self.encryption.keys.contains_key(&self.encryption.active_key) {
+            errors.push(format!("encryption.keys has no key for encryption.active_key {:?}", self.encryption.active_key));
+        }
+        for key_id in self.encryption.keys.keys() {
+            if key_id.is_empty() || key_id.contains(':') {
+                errors.push(format!("encryption key id {key_id:?} can't be empty or contain ':'"));
+            } else if self.encryption.key_bytes(key_id).is_none() {
+                errors.push(format!("encryption key {key_id} must be 32 hex encoded bytes"));
+            }
+        }
+        if self.session.ttl_days <= 0 {
+            errors.push("session.ttl_days must be positive".to_string());
+        }
+        if self.session.cache_ttl_secs == 0 || self.session.cache_idle_secs == 0 {
+            errors.push("session.cache_ttl_secs and session.cache_idle_secs must be positive".to_string());
+        }
+        errors
+    }
+}
+
+// Rocket.toml and ROCKET_* variables, plus the DB_URL, LND_*, ADMIN_API_KEY and ENCRYPTION_* variables deployments set
+pub fn figment() -> Figment {
+    rocket::Config::figment()
+        .merge(Env::raw().only(&["DB_URL", "LND_MACAROON", "LND_MACAROON_PATH", "LND_URL", "LND_TLS_CERT_PATH", "ADMIN_API_KEY", "ENCRYPTION_KEYS", "ENCRYPTION_ACTIVE_KEY"]).map(|key| {
+            key.as_str().to_ascii_lowercase().replacen("lnd_", "lnd.", 1).replacen("admin_", "admin.", 1).replacen("encryption_", "encryption.", 1).into()
+        }))
+}
+
+// extracts and validates the config for the server and the cli
+pub fn load(figment: &Figment) -> Result<AppConfig, Vec<String>> {
+    let mut app_config: AppConfig = figment.extract()
+        .map_err(|e| e.into_iter().map(|e| e.to_string()).collect::<Vec<_>>())?;
+    let errors = app_config.validate();
+    if !errors.is_empty() {
+        return Err(errors)
+    }
+    // read the macaroon file once instead of on every lnd call
+    app_config.lnd.macaroon = app_config.lnd.macaroon_hex().ok();
+    Ok(app_config)
+}
 
 pub async fn parse_config(rocket: Rocket<Build>) -> Result<Rocket<Build>, Rocket<Build>> {
-    let fe_url: String = match rocket.figment().extract_inner::<String>("fe_url") {
-        Ok(value) => {
-            info!("fe url: {value}");
-            value
-        },
-        Err(e) => {
-            info!("error: {e}");
-            "".to_string()
+    let app_config = match load(rocket.figment()) {
+        Ok(c) => c,
+        Err(errors) => {
+            for e in errors {
+                error!(error = %e, "invalid configuration");
+            }
+            return Err(rocket)
         }
     };
+    info!(url = %app_config.url, fe_url = %app_config.fe_url, lnd = %app_config.lnd.url, lichess = %app_config.lichess.url, "config");
 
-    match rocket.figment().extract_inner("url") {
-        Ok(value) => {
-            info!("api host: {value}");
-            Ok(rocket.manage(AppConfig { url: value, fe_url } ))
-        },
+    // connect lazily so the service comes up and /readyz can report a database outage
+    let pool = match PgPoolOptions::new()
+        .max_connections(app_config.db_pool_size)
+        .connect_lazy(&app_config.db_url) {
+        Ok(p) => p,
         Err(e) => {
-            info!("error: {e}");
-            Err(rocket)
+            error!(error = %e, "invalid db_url");
+            return Err(rocket)
+        }
+    };
+
+    if app_config.run_migrations {
+        if let Err(e) = migrate::run(&pool).await {
+            error!(error = %e, "error running migrations");
+            return Err(rocket)
         }
     }
-}
\ No newline at end of file
+
+    let cache = session_cache(&app_config.session);
+    Ok(rocket.manage(pool).manage(cache).manage(app_config))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_config() -> AppConfig {
+        AppConfig {
+            url: "http://localhost:8000".to_string(),
+            fe_url: "http://localhost:8080".to_string(),
+            db_url: "postgres://localhost/lightningchess".to_string(),
+            db_pool_size: 5,
+            run_migrations: false,
+            lnd: LndConfig {
+                url: "https://localhost:8080".to_string(),
+                tls_cert_path: None,
+                macaroon: Some("0201036c6e64".to_string()),
+                macaroon_path: None,
+                timeout_secs: 30
+            },
+            lichess: LichessConfig::default(),
+            stakes: StakeConfig::default(),
+            admin: AdminConfig::default(),
+            session: SessionConfig::default(),
+            encryption: EncryptionConfig {
+                active_key: "k1".to_string(),
+                keys: HashMap::from([("k1".to_string(), "11".repeat(32))])
+            }
+        }
+    }
+
+    #[test]
+    fn valid_config() {
+        assert!(get_config().validate().is_empty());
+    }
+
+    #[test]
+    fn missing_macaroon() {
+        let mut config = get_config();
+        config.lnd.macaroon = None;
+        assert_eq!(config.validate().len(), 1);
+    }
+
+    #[test]
+    fn reports_every_error() {
+        let mut config = get_config();
+        config.db_url = "mysql://localhost".to_string();
+        config.lnd.macaroon = Some("not hex".to_string());
+        config.stakes.min_sats = 0;
+        assert_eq!(config.validate().len(), 3);
+    }
+
+    #[test]
+    fn encryption_keys() {
+        let mut config = get_config();
+        config.encryption.keys.insert("k0".to_string(), "11".repeat(16));
+        assert_eq!(config.validate(), vec!["encryption key k0 must be 32 hex encoded bytes".to_string()]);
+        config.encryption.keys.clear();
+        assert_eq!(config.validate().len(), 1);
+    }
+
+    #[test]
+    fn admin_usernames_ignore_case() {
+        let admin = AdminConfig { usernames: vec!["DrNykterstein".to_string()], ..AdminConfig::default() };
+        assert!(admin.is_admin("drnykterstein"));
+        assert!(!admin.is_admin("someone"));
+    }
+
+    #[test]
+    fn withdrawal_approval_threshold() {
+        let admin = AdminConfig { withdrawal_approval_sats: Some(100_000), ..AdminConfig::default() };
+        assert!(!admin.needs_approval(100_000));
+        assert!(admin.needs_approval(100_001));
+        assert!(!AdminConfig::default().needs_approval(i64::MAX));
+    }
+}
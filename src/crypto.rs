@@ -0,0 +1,138 @@
+use openssl::rand::rand_bytes;
+use openssl::symm::{decrypt_aead, encrypt_aead, Cipher};
+use crate::config::EncryptionConfig;
+
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+const ENVELOPE_VERSION: &str = "v1";
+
+// aes-256-gcm, returns nonce | tag | ciphertext
+fn seal(key: &[u8], aad: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, String> {
+    let mut nonce = [0u8; NONCE_LEN];
+    rand_bytes(&mut nonce).map_err(|e| e.to_string())?;
+    let mut tag = [0u8; TAG_LEN];
+    let ciphertext = encrypt_aead(Cipher::aes_256_gcm(), key, Some(&nonce), aad, plaintext, &mut tag)
+        .map_err(|e| e.to_string())?;
+    Ok([&nonce[..], &tag[..], &ciphertext[..]].concat())
+}
+
+fn open(key: &[u8], aad: &[u8], sealed: &[u8]) -> Result<Vec<u8>, String> {
+    if sealed.len() < NONCE_LEN + TAG_LEN {
+        return Err("ciphertext too short".to_string())
+    }
+    let (nonce, rest) = sealed.split_at(NONCE_LEN);
+    let (tag, ciphertext) = rest.split_at(TAG_LEN);
+    decrypt_aead(Cipher::aes_256_gcm(), key, Some(nonce), aad, ciphertext, tag)
+        .map_err(|_| "decryption failed".to_string())
+}
+
+struct Envelope<'a> {
+    key_id: &'a str,
+    wrapped_key: Vec<u8>,
+    ciphertext: &'a str
+}
+
+// v1:<key id>:<data key wrapped with the key>:<token encrypted with the data key>
+fn parse(envelope: &str) -> Result<Envelope<'_>, String> {
+    match envelope.splitn(4, ':').collect::<Vec<_>>().as_slice() {
+        [ENVELOPE_VERSION, key_id, wrapped_key, ciphertext] => Ok(Envelope {
+            key_id,
+            wrapped_key: base64::decode(wrapped_key).map_err(|e| e.to_string())?,
+            ciphertext
+        }),
+        _ => Err("not an encrypted token".to_string())
+    }
+}
+
+fn key(keys: &EncryptionConfig, key_id: &str) -> Result<Vec<u8>, String> {
+    keys.key_bytes(key_id).ok_or_else(|| format!("unknown encryption key {key_id}"))
+}
+
+fn wrap(keys: &EncryptionConfig, data_key: &[u8], ciphertext: &str) -> Result<String, String> {
+    let key_id = &keys.active_key;
+    let wrapped_key = seal(&key(keys, key_id)?, key_id.as_bytes(), data_key)?;
+    Ok(format!("{ENVELOPE_VERSION}:{key_id}:{}:{ciphertext}", base64::encode(wrapped_key)))
+}
+
+// every token gets its own data key, only the data key is encrypted with the configured key
+pub fn encrypt(keys: &EncryptionConfig, plaintext: &str) -> Result<String, String> {
+    let mut data_key = [0u8; 32];
+    rand_bytes(&mut data_key).map_err(|e| e.to_string())?;
+    let ciphertext = base64::encode(seal(&data_key, &[], plaintext.as_bytes())?);
+    wrap(keys, &data_key, &ciphertext)
+}
+
+pub fn decrypt(keys: &EncryptionConfig, envelope: &str) -> Result<String, String> {
+    let envelope = parse(envelope)?;
+    let data_key = open(&key(keys, envelope.key_id)?, envelope.key_id.as_bytes(), &envelope.wrapped_key)?;
+    let ciphertext = base64::decode(envelope.ciphertext).map_err(|e| e.to_string())?;
+    String::from_utf8(open(&data_key, &[], &ciphertext)?).map_err(|e| e.to_string())
+}
+
+pub fn is_encrypted(value: &str) -> bool {
+    parse(value).is_ok()
+}
+
+// re-wraps the data key with the active key, None when it already is.
+// the token itself isn't re-encrypted
+pub fn rotate(keys: &EncryptionConfig, envelope: &str) -> Result<Option<String>, String> {
+    let parsed = parse(envelope)?;
+    if parsed.key_id == keys.active_key {
+        return Ok(None)
+    }
+    let data_key = open(&key(keys, parsed.key_id)?, parsed.key_id.as_bytes(), &parsed.wrapped_key)?;
+    wrap(keys, &data_key, parsed.ciphertext).map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use super::*;
+
+    fn keys(active_key: &str) -> EncryptionConfig {
+        EncryptionConfig {
+            active_key: active_key.to_string(),
+            keys: HashMap::from([("k1".to_string(), "11".repeat(32)), ("k2".to_string(), "22".repeat(32))])
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let encrypted = encrypt(&keys("k1"), "lio_token").unwrap();
+        assert!(encrypted.starts_with("v1:k1:"));
+        assert!(is_encrypted(&encrypted));
+        assert_ne!(encrypted, encrypt(&keys("k1"), "lio_token").unwrap());
+        assert_eq!(decrypt(&keys("k1"), &encrypted).unwrap(), "lio_token");
+    }
+
+    #[test]
+    fn rotation_keeps_the_token() {
+        let encrypted = encrypt(&keys("k1"), "lio_token").unwrap();
+        assert_eq!(rotate(&keys("k1"), &encrypted).unwrap(), None);
+
+        let rotated = rotate(&keys("k2"), &encrypted).unwrap().unwrap();
+        assert!(rotated.starts_with("v1:k2:"));
+        assert_eq!(rotated.rsplit(':').next(), encrypted.rsplit(':').next());
+        assert_eq!(decrypt(&keys("k2"), &rotated).unwrap(), "lio_token");
+
+        let mut retired = keys("k2");
+        retired.keys.remove("k1");
+        assert!(decrypt(&retired, &encrypted).is_err());
+        assert_eq!(decrypt(&retired, &rotated).unwrap(), "lio_token");
+    }
+
+    #[test]
+    fn tampering_fails() {
+        let encrypted = encrypt(&keys("k1"), "lio_token").unwrap();
+        // the key id is bound to the wrapped key
+        let relabeled = encrypted.replacen("v1:k1:", "v1:k2:", 1);
+        assert!(decrypt(&keys("k1"), &relabeled).is_err());
+
+        let mut ciphertext = base64::decode(encrypted.rsplit(':').next().unwrap()).unwrap();
+        let last = ciphertext.len() - 1;
+        ciphertext[last] ^= 1;
+        let (prefix, _) = encrypted.rsplit_once(':').unwrap();
+        assert!(decrypt(&keys("k1"), &format!("{prefix}:{}", base64::encode(ciphertext))).is_err());
+        assert!(decrypt(&keys("k1"), "lio_plaintext").is_err());
+    }
+}
@@ -0,0 +1,282 @@
+use sqlx::{Pool, Postgres};
+use tracing::info;
+use crate::admin::refund_stakes;
+use crate::errors::{AppError, AppResult};
+use crate::ledger::Actor;
+use crate::metrics::DISPUTES;
+use crate::models::{Challenge, Dispute, Transaction};
+use crate::notifications::notify;
+use crate::settlement::{move_held_payout, settle_stakes, HoldOutcome};
+
+// players contest results (disconnects, aborts, engine use) while we still hold the stakes.
+// an open dispute freezes the settlement and the payout hold until an admin resolves it
+
+const REASON_MAX_LEN: usize = 1024;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum DisputeOutcome {
+    // the result stands, settlement carries on
+    Uphold,
+    // both players get their stake back
+    Refund,
+    // the player takes both stakes
+    Award(String)
+}
+
+impl DisputeOutcome {
+    fn label(&self) -> &'static str {
+        match self {
+            DisputeOutcome::Uphold => "uphold",
+            DisputeOutcome::Refund => "refund",
+            DisputeOutcome::Award(_) => "award"
+        }
+    }
+}
+
+pub fn parse_outcome(outcome: &str, winner: Option<&str>, challenge: &Challenge) -> AppResult<DisputeOutcome> {
+    match (outcome, winner) {
+        ("uphold", None) => Ok(DisputeOutcome::Uphold),
+        ("refund", None) => Ok(DisputeOutcome::Refund),
+        ("uphold", Some(_)) | ("refund", Some(_)) => Err(AppError::Validation("only an award has a winner".to_string())),
+        ("award", Some(w)) if w == challenge.username || w == challenge.opp_username => Ok(DisputeOutcome::Award(w.to_string())),
+        ("award", _) => Err(AppError::Validation("winner must be one of the players".to_string())),
+        _ => Err(AppError::Validation("outcome must be uphold, refund or award".to_string()))
+    }
+}
+
+// the stakes are in escrow while the game is on, or held as payouts after it
+fn disputable(status: Option<&str>, winnings_held: bool) -> bool {
+    match status {
+        Some("ACCEPTED") | Some("STARTED") => true,
+        Some("FINISHED") => winnings_held,
+        _ => false
+    }
+}
+
+// the lichess color the player had, the challenger picked theirs
+fn player_color<'a>(challenge: &'a Challenge, username: &str) -> Option<&'a str> {
+    let color = challenge.color.as_deref()?;
+    if username == challenge.username {
+        Some(color)
+    } else {
+        match color {
+            "white" => Some("black"),
+            "black" => Some("white"),
+            _ => None
+        }
+    }
+}
+
+// the player who had the lichess color
+pub fn color_player<'a>(challenge: &'a Challenge, color: &str) -> Option<&'a str> {
+    [&challenge.username, &challenge.opp_username].into_iter()
+        .find(|username| player_color(challenge, username) == Some(color))
+        .map(String::as_str)
+}
+
+// the winner's pot, or each player's stake after a draw
+pub async fn held_payouts(tx: &mut sqlx::Transaction<'_, Postgres>, challenge: &Challenge) -> Result<Vec<Transaction>, sqlx::Error> {
+    sqlx::query_as::<_,Transaction>("SELECT * FROM lightningchess_transaction WHERE lichess_challenge_id=$1 AND state='HELD' ORDER BY transaction_id")
+        .bind(&challenge.lichess_challenge_id)
+        .fetch_all(&mut *tx).await
+}
+
+async fn notify_players(pool: &Pool<Postgres>, challenge: &Challenge, kind: &str, message: &str) {
+    for username in [&challenge.username, &challenge.opp_username] {
+        notify(pool, username, kind, Some(challenge.id), message).await;
+    }
+}
+
+pub async fn under_dispute(pool: &Pool<Postgres>, challenge_id: i32) -> Result<bool, sqlx::Error> {
+    sqlx::query_scalar("SELECT EXISTS (SELECT 1 FROM dispute WHERE challenge_id=$1 AND status='OPEN')")
+        .bind(challenge_id)
+        .fetch_one(pool).await
+}
+
+// locks the challenge and its open dispute, None when there's no such challenge.
+// moving stakes and filing a dispute both take the challenge lock first, so a dispute can't be filed
+// while the stakes are being paid out and stakes aren't paid out while a dispute is open
+pub async fn lock_challenge(tx: &mut sqlx::Transaction<'_, Postgres>, challenge_id: i32) -> Result<Option<(Challenge, Option<Dispute>)>, sqlx::Error> {
+    let challenge = sqlx::query_as::<_,Challenge>("SELECT * FROM challenge WHERE id=$1 FOR UPDATE")
+        .bind(challenge_id)
+        .fetch_optional(&mut *tx).await?;
+    let challenge = match challenge {
+        Some(c) => c,
+        None => return Ok(None)
+    };
+    let dispute = sqlx::query_as::<_,Dispute>("SELECT * FROM dispute WHERE challenge_id=$1 AND status='OPEN' FOR UPDATE")
+        .bind(challenge_id)
+        .fetch_optional(&mut *tx).await?;
+    Ok(Some((challenge, dispute)))
+}
+
+pub async fn file_dispute(pool: &Pool<Postgres>, username: &str, challenge_id: i32, reason: &str) -> AppResult<Dispute> {
+    let reason = reason.trim();
+    if reason.is_empty() {
+        return Err(AppError::Validation("reason required".to_string()))
+    }
+    if reason.chars().count() > REASON_MAX_LEN {
+        return Err(AppError::Validation(format!("reason must be at most {REASON_MAX_LEN} characters")))
+    }
+
+    let mut tx = pool.begin().await?;
+    let (challenge, open) = lock_challenge(&mut tx, challenge_id).await?
+        .ok_or_else(|| AppError::NotFound("challenge".to_string()))?;
+    if open.is_some() {
+        return Err(AppError::Validation("challenge is already disputed".to_string()))
+    }
+    let winnings_held = !held_payouts(&mut tx, &challenge).await?.is_empty();
+    if !disputable(challenge.status.as_deref(), winnings_held) {
+        return Err(AppError::Validation("only games being played or with winnings on hold can be disputed".to_string()))
+    }
+
+    let dispute = sqlx::query_as::<_,Dispute>("INSERT INTO dispute (challenge_id, username, reason) VALUES ($1, $2, $3) RETURNING *")
+        .bind(challenge.id)
+        .bind(username)
+        .bind(reason)
+        .fetch_one(&mut tx).await?;
+    tx.commit().await?;
+
+    DISPUTES.with_label_values(&["filed"]).inc();
+    info!(dispute_id = dispute.dispute_id, challenge_id = challenge.id, "dispute filed");
+    let message = format!("{username} disputed challenge {}, it won't be settled until an admin reviews it", challenge.id);
+    notify_players(pool, &challenge, "dispute filed", &message).await;
+    Ok(dispute)
+}
+
+// disputes waiting for an admin, oldest first
+pub async fn dispute_queue(pool: &Pool<Postgres>, status: &str) -> Result<Vec<Dispute>, sqlx::Error> {
+    sqlx::query_as::<_,Dispute>("SELECT * FROM dispute WHERE status=$1 ORDER BY dispute_id LIMIT 100")
+        .bind(status)
+        .fetch_all(pool).await
+}
+
+// moves the stakes as the admin decided, in the transaction that resolves the dispute
+async fn apply_outcome(tx: &mut sqlx::Transaction<'_, Postgres>, actor: &Actor, challenge: &Challenge, outcome: &DisputeOutcome) -> AppResult<()> {
+    match outcome {
+        DisputeOutcome::Uphold => (),
+        DisputeOutcome::Refund => refund_stakes(tx, actor, challenge).await?,
+        DisputeOutcome::Award(winner) => match challenge.status.as_deref() {
+            Some("ACCEPTED") | Some("STARTED") => {
+                let color = player_color(challenge, winner)
+                    .ok_or_else(|| AppError::Internal(format!("challenge {} has no color", challenge.id)))?;
+                // winnings still go through the payout hold job so the fair play checks apply
+                settle_stakes(tx, actor, challenge, "FINISHED", Some(color), 0).await?;
+            },
+            _ => {
+                let payouts = held_payouts(tx, challenge).await?;
+                if payouts.is_empty() {
+                    return Err(AppError::Validation("winnings were already released, adjust balances instead".to_string()))
+                }
+                // winnings that already go to the awarded player stay on hold for the fair play checks
+                for payout in payouts.iter().filter(|p| &p.username != winner) {
+                    move_held_payout(tx, actor, payout, challenge, HoldOutcome::Reverse).await?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+// marks the locked open dispute resolved, in the transaction that moved the stakes
+pub async fn close_dispute(tx: &mut sqlx::Transaction<'_, Postgres>, actor: &Actor, dispute: &Dispute, outcome: &DisputeOutcome, note: Option<&str>) -> Result<Dispute, sqlx::Error> {
+    let awarded_to = match outcome {
+        DisputeOutcome::Award(winner) => Some(winner.as_str()),
+        _ => None
+    };
+    sqlx::query_as::<_,Dispute>("UPDATE dispute SET status='RESOLVED', outcome=$1, awarded_to=$2, resolved_by=$3, note=LEFT($4, 1024), \
+        resolved_on=(now() at time zone 'utc') WHERE dispute_id=$5 RETURNING *")
+        .bind(outcome.label())
+        .bind(awarded_to)
+        .bind(&actor.name)
+        .bind(note)
+        .bind(dispute.dispute_id)
+        .fetch_one(&mut *tx).await
+}
+
+// after the resolution is committed
+pub async fn announce_resolution(pool: &Pool<Postgres>, challenge: &Challenge, dispute: &Dispute, outcome: &DisputeOutcome, note: Option<&str>) {
+    DISPUTES.with_label_values(&[outcome.label()]).inc();
+    info!(dispute_id = dispute.dispute_id, challenge_id = challenge.id, outcome = outcome.label(), "dispute resolved");
+    notify_players(pool, challenge, "dispute resolved", &outcome_message(challenge.id, outcome, note)).await;
+}
+
+fn outcome_message(challenge_id: i32, outcome: &DisputeOutcome, note: Option<&str>) -> String {
+    let message = match outcome {
+        DisputeOutcome::Uphold => format!("the dispute on challenge {challenge_id} was reviewed, the result stands"),
+        DisputeOutcome::Refund => format!("the dispute on challenge {challenge_id} was reviewed, both stakes were refunded"),
+        DisputeOutcome::Award(winner) => format!("the dispute on challenge {challenge_id} was reviewed, both stakes go to {winner}")
+    };
+    match note {
+        Some(note) => format!("{message}: {note}"),
+        None => message
+    }
+}
+
+pub async fn resolve_dispute(pool: &Pool<Postgres>, actor: &Actor, dispute_id: i32, outcome: &str, winner: Option<&str>, note: Option<&str>) -> AppResult<Dispute> {
+    let challenge_id: i32 = sqlx::query_scalar("SELECT challenge_id FROM dispute WHERE dispute_id=$1")
+        .bind(dispute_id)
+        .fetch_optional(pool).await?
+        .ok_or_else(|| AppError::NotFound("dispute".to_string()))?;
+    let note = note.map(str::trim).filter(|n| !n.is_empty());
+
+    let mut tx = pool.begin().await?;
+    let (challenge, open) = lock_challenge(&mut tx, challenge_id).await?
+        .ok_or_else(|| AppError::NotFound("challenge".to_string()))?;
+    let open = open.filter(|d| d.dispute_id == dispute_id)
+        .ok_or_else(|| AppError::Validation("dispute is already resolved".to_string()))?;
+    let outcome = parse_outcome(outcome, winner, &challenge)?;
+
+    apply_outcome(&mut tx, actor, &challenge, &outcome).await?;
+    let dispute = close_dispute(&mut tx, actor, &open, &outcome, note).await?;
+    tx.commit().await?;
+
+    announce_resolution(pool, &challenge, &dispute, &outcome, note).await;
+    Ok(dispute)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::test_challenge;
+
+    fn get_challenge() -> Challenge {
+        Challenge {
+            status: Some("FINISHED".to_string()),
+            lichess_challenge_id: Some("abcd1234".to_string()),
+            ..test_challenge()
+        }
+    }
+
+    #[test]
+    fn outcomes() {
+        let challenge = get_challenge();
+        assert_eq!(parse_outcome("uphold", None, &challenge).unwrap(), DisputeOutcome::Uphold);
+        assert_eq!(parse_outcome("refund", None, &challenge).unwrap(), DisputeOutcome::Refund);
+        assert_eq!(parse_outcome("award", Some("user2"), &challenge).unwrap(), DisputeOutcome::Award("user2".to_string()));
+        assert!(parse_outcome("award", None, &challenge).is_err());
+        assert!(parse_outcome("award", Some("user3"), &challenge).is_err());
+        assert!(parse_outcome("refund", Some("user1"), &challenge).is_err());
+        assert!(parse_outcome("void", None, &challenge).is_err());
+    }
+
+    #[test]
+    fn only_stakes_we_hold_can_be_disputed() {
+        assert!(disputable(Some("ACCEPTED"), false));
+        assert!(disputable(Some("STARTED"), false));
+        assert!(disputable(Some("FINISHED"), true));
+        assert!(!disputable(Some("FINISHED"), false));
+        assert!(!disputable(Some("ABORTED"), false));
+        assert!(!disputable(Some("WAITING FOR ACCEPTANCE"), false));
+    }
+
+    #[test]
+    fn awarded_player_colors() {
+        let challenge = get_challenge();
+        assert_eq!(player_color(&challenge, "user1"), Some("white"));
+        assert_eq!(player_color(&challenge, "user2"), Some("black"));
+        assert_eq!(color_player(&challenge, "white"), Some("user1"));
+        assert_eq!(color_player(&challenge, "black"), Some("user2"));
+        assert_eq!(color_player(&challenge, "green"), None);
+    }
+}
@@ -0,0 +1,125 @@
+use rocket::State;
+use sqlx::{Pool, Postgres};
+use tracing::{info, instrument};
+use crate::admin::{adjust_balance, approve_withdrawal, force_settle, refund_challenge, reject_withdrawal, void_challenge};
+use crate::config::AppConfig;
+use crate::disputes::{dispute_queue, resolve_dispute};
+use crate::errors::{AppError, AppResult};
+use crate::ledger::Actor;
+use crate::models::{Admin, AuditEntry, Balance, Discrepancy, BalanceAdjustmentRequest, Challenge, ChallengeResolveRequest, DisputeResolveRequest, RequestId, Transaction, WithdrawalRejectRequest};
+
+fn parse_id(id: &str, name: &str) -> AppResult<i32> {
+    id.parse::<i32>().map_err(|_| AppError::Validation(format!("invalid {name} id")))
+}
+
+#[get("/api/admin/users?<q>")]
+#[instrument(skip_all, fields(request_id = %request_id, admin = %admin.username))]
+pub async fn admin_users(request_id: RequestId, admin: Admin, pool: &State<Pool<Postgres>>, q: Option<String>) -> AppResult<String> {
+    let users = sqlx::query_as::<_,Balance>("SELECT * FROM lightningchess_balance WHERE username ILIKE '%' || $1 || '%' ORDER BY username LIMIT 100")
+        .bind(q.unwrap_or_default())
+        .fetch_all(&**pool).await?;
+    Ok(serde_json::to_string(&users)?)
+}
+
+#[get("/api/admin/transactions?<username>&<state>&<ttype>")]
+#[instrument(skip_all, fields(request_id = %request_id, admin = %admin.username))]
+pub async fn admin_transactions(request_id: RequestId, admin: Admin, pool: &State<Pool<Postgres>>, username: Option<String>, state: Option<String>, ttype: Option<String>) -> AppResult<String> {
+    let transactions = sqlx::query_as::<_,Transaction>("SELECT * FROM lightningchess_transaction WHERE ($1::VARCHAR IS NULL OR username=$1) AND ($2::VARCHAR IS NULL OR state=$2) AND ($3::VARCHAR IS NULL OR ttype=$3) ORDER BY transaction_id DESC LIMIT 100")
+        .bind(username)
+        .bind(state)
+        .bind(ttype)
+        .fetch_all(&**pool).await?;
+    Ok(serde_json::to_string(&transactions)?)
+}
+
+#[get("/api/admin/challenges?<username>&<status>")]
+#[instrument(skip_all, fields(request_id = %request_id, admin = %admin.username))]
+pub async fn admin_challenges(request_id: RequestId, admin: Admin, pool: &State<Pool<Postgres>>, username: Option<String>, status: Option<String>) -> AppResult<String> {
+    let challenges = sqlx::query_as::<_,Challenge>("SELECT * FROM challenge WHERE ($1::VARCHAR IS NULL OR username=$1 OR opp_username=$1) AND ($2::VARCHAR IS NULL OR status=$2) ORDER BY created_on DESC LIMIT 100")
+        .bind(username)
+        .bind(status)
+        .fetch_all(&**pool).await?;
+    Ok(serde_json::to_string(&challenges)?)
+}
+
+#[get("/api/admin/audit?<username>&<challenge_id>&<transaction_id>")]
+#[instrument(skip_all, fields(request_id = %request_id, admin = %admin.username))]
+pub async fn admin_audit_log(request_id: RequestId, admin: Admin, pool: &State<Pool<Postgres>>, username: Option<String>, challenge_id: Option<i32>, transaction_id: Option<i32>) -> AppResult<String> {
+    let entries = sqlx::query_as::<_,AuditEntry>("SELECT * FROM audit_log WHERE ($1::VARCHAR IS NULL OR username=$1) AND ($2::INT IS NULL OR challenge_id=$2) AND ($3::INT IS NULL OR transaction_id=$3) ORDER BY audit_id DESC LIMIT 100")
+        .bind(username)
+        .bind(challenge_id)
+        .bind(transaction_id)
+        .fetch_all(&**pool).await?;
+    Ok(serde_json::to_string(&entries)?)
+}
+
+// open discrepancies found by the reconciliation job, resolved ones too when asked
+#[get("/api/admin/discrepancies?<resolved>")]
+#[instrument(skip_all, fields(request_id = %request_id, admin = %admin.username))]
+pub async fn admin_discrepancies(request_id: RequestId, admin: Admin, pool: &State<Pool<Postgres>>, resolved: Option<bool>) -> AppResult<String> {
+    let discrepancies = sqlx::query_as::<_,Discrepancy>("SELECT * FROM reconciliation_discrepancy WHERE $1 OR resolved_on IS NULL ORDER BY discrepancy_id DESC LIMIT 100")
+        .bind(resolved.unwrap_or(false))
+        .fetch_all(&**pool).await?;
+    Ok(serde_json::to_string(&discrepancies)?)
+}
+
+#[post("/api/admin/balance", data = "<adjustment_request>")]
+#[instrument(skip_all, fields(request_id = %request_id, admin = %admin.username))]
+pub async fn admin_adjust_balance(request_id: RequestId, admin: Admin, pool: &State<Pool<Postgres>>, adjustment_request: String) -> AppResult<String> {
+    let adjustment: BalanceAdjustmentRequest = serde_json::from_str(&adjustment_request)?;
+    info!(username = %adjustment.username, sats = adjustment.sats, note = %adjustment.note, "balance adjustment");
+    let balance = adjust_balance(pool, &Actor::admin(&admin, &request_id), &adjustment.username, adjustment.sats, &adjustment.note).await?;
+    Ok(serde_json::to_string(&balance)?)
+}
+
+#[post("/api/admin/withdrawal/<transaction_id>/approve")]
+#[instrument(skip_all, fields(request_id = %request_id, admin = %admin.username))]
+pub async fn admin_approve_withdrawal(request_id: RequestId, admin: Admin, pool: &State<Pool<Postgres>>, app_config: &State<AppConfig>, transaction_id: String) -> AppResult<String> {
+    let withdrawal = approve_withdrawal(pool, &Actor::admin(&admin, &request_id), app_config, parse_id(&transaction_id, "transaction")?).await?;
+    Ok(serde_json::to_string(&withdrawal)?)
+}
+
+#[post("/api/admin/withdrawal/<transaction_id>/reject", data = "<reject_request>")]
+#[instrument(skip_all, fields(request_id = %request_id, admin = %admin.username))]
+pub async fn admin_reject_withdrawal(request_id: RequestId, admin: Admin, pool: &State<Pool<Postgres>>, transaction_id: String, reject_request: String) -> AppResult<String> {
+    let reject: WithdrawalRejectRequest = serde_json::from_str(&reject_request)?;
+    let withdrawal = reject_withdrawal(pool, &Actor::admin(&admin, &request_id), parse_id(&transaction_id, "transaction")?, &reject.reason).await?;
+    Ok(serde_json::to_string(&withdrawal)?)
+}
+
+// overrides the result of a game, e.g. when lichess never reported it or the players dispute it
+#[post("/api/admin/challenge/<challenge_id>/resolve", data = "<resolve_request>")]
+#[instrument(skip_all, fields(request_id = %request_id, admin = %admin.username))]
+pub async fn admin_resolve_challenge(request_id: RequestId, admin: Admin, pool: &State<Pool<Postgres>>, challenge_id: String, resolve_request: String) -> AppResult<String> {
+    let resolve: ChallengeResolveRequest = serde_json::from_str(&resolve_request)?;
+    let challenge_id = parse_id(&challenge_id, "challenge")?;
+    info!(challenge_id, outcome = %resolve.outcome, "resolving challenge");
+    let actor = Actor::admin(&admin, &request_id);
+    let challenge = match resolve.outcome.as_str() {
+        "white" | "black" => force_settle(pool, &actor, challenge_id, Some(&resolve.outcome)).await?,
+        "draw" => force_settle(pool, &actor, challenge_id, None).await?,
+        "refund" => refund_challenge(pool, &actor, challenge_id).await?,
+        "void" => void_challenge(pool, &actor, challenge_id).await?,
+        _ => return Err(AppError::Validation("outcome must be white, black, draw, refund or void".to_string()))
+    };
+    Ok(serde_json::to_string(&challenge)?)
+}
+
+// disputes waiting for a decision, oldest first, resolved ones with status=RESOLVED
+#[get("/api/admin/disputes?<status>")]
+#[instrument(skip_all, fields(request_id = %request_id, admin = %admin.username))]
+pub async fn admin_disputes(request_id: RequestId, admin: Admin, pool: &State<Pool<Postgres>>, status: Option<String>) -> AppResult<String> {
+    let disputes = dispute_queue(pool, status.as_deref().unwrap_or("OPEN")).await?;
+    Ok(serde_json::to_string(&disputes)?)
+}
+
+#[post("/api/admin/dispute/<dispute_id>/resolve", data = "<resolve_request>")]
+#[instrument(skip_all, fields(request_id = %request_id, admin = %admin.username))]
+pub async fn admin_resolve_dispute(request_id: RequestId, admin: Admin, pool: &State<Pool<Postgres>>, dispute_id: String, resolve_request: String) -> AppResult<String> {
+    let resolve: DisputeResolveRequest = serde_json::from_str(&resolve_request)?;
+    let dispute_id = parse_id(&dispute_id, "dispute")?;
+    info!(dispute_id, outcome = %resolve.outcome, winner = ?resolve.winner, "resolving dispute");
+    let actor = Actor::admin(&admin, &request_id);
+    let dispute = resolve_dispute(pool, &actor, dispute_id, &resolve.outcome, resolve.winner.as_deref(), resolve.note.as_deref()).await?;
+    Ok(serde_json::to_string(&dispute)?)
+}
@@ -1,65 +1,133 @@
-use cookie::SameSite;
-use cookie::time::Duration;
-use reqwest::Client;
+use openssl::memcmp;
 use rocket::http::{Cookie, CookieJar};
 use rocket::response::Redirect;
 use rocket::State;
-use serde_json::json;
-use crate::models::{AppConfig, TokenResponse};
+use sqlx::{Pool, Postgres};
+use crate::config::AppConfig;
+use crate::endpoints::login::{CODE_VERIFIER_COOKIE, SCOPES, STATE_COOKIE};
+use crate::lichess::client::{exchange_code, fetch_account, revoke_token};
+use crate::models::RequestId;
+use crate::sessions::{create_session, session_cookie};
+use tracing::{info, instrument, warn};
 
-#[get("/callback?<code>")]
-pub async fn callback(code: String, app_config: &State<AppConfig>, cookies: &CookieJar<'_>) -> Option<Redirect> {
-    println!("in callback");
+// one time values set by /login, removed whatever the outcome
+fn take_private(cookies: &CookieJar<'_>, name: &'static str) -> Option<String> {
+    let value = cookies.get_private(name).map(|c| c.value().to_string());
+    cookies.remove_private(Cookie::named(name));
+    value
+}
+
+// the code and verifier to exchange, or why the callback can't be trusted.
+// the reasons end up in the redirect for the frontend
+fn verify_callback(code: Option<String>, state: Option<&str>, error: Option<&str>, expected_state: Option<&str>, code_verifier: Option<String>) -> Result<(String, String), &'static str> {
+    match error {
+        Some("access_denied") => return Err("access_denied"),
+        Some(_) => return Err("authorization_failed"),
+        None => ()
+    }
+    // the login cookies only last 10 minutes
+    let (expected_state, code_verifier) = match (expected_state, code_verifier) {
+        (Some(s), Some(v)) => (s, v),
+        _ => return Err("expired")
+    };
+    match state {
+        Some(s) if s.len() == expected_state.len() && memcmp::eq(s.as_bytes(), expected_state.as_bytes()) => (),
+        _ => return Err("invalid_state")
+    }
+    let code = code.filter(|c| !c.is_empty()).ok_or("missing_code")?;
+    Ok((code, code_verifier))
+}
+
+// lichess lets users untick scopes on the consent screen
+fn has_scopes(granted: &str) -> bool {
+    let granted: Vec<&str> = granted.split(|c: char| c == ',' || c.is_whitespace()).collect();
+    SCOPES.split(' ').all(|s| granted.contains(&s))
+}
+
+async fn complete_login(pool: &Pool<Postgres>, app_config: &AppConfig, cookies: &CookieJar<'_>, code: &str, code_verifier: &str) -> Result<(), &'static str> {
     let redirect_uri = format!("{}/callback", &app_config.url);
-    let code_verifier: String = match cookies.get_private("codeVerifier") {
-        Some(cookie) => {
-            let cv = cookie.value().to_string();
-            cookies.remove_private(cookie);
-            cv
-        }
-        None => {
-            println!("No code verifier found!");
-            "".to_string()
+    let mut token = exchange_code(&app_config.lichess, &redirect_uri, code, code_verifier).await
+        .map_err(|e| {
+            warn!(error = %e, "error exchanging code");
+            "token_exchange"
+        })?;
+    info!(scope = ?token.scope, expires_in = ?token.expires_in, "lichess token exchange");
+
+    // no scope in the response means everything asked for was granted
+    let scope = token.scope.get_or_insert_with(|| SCOPES.to_string());
+    if !has_scopes(scope) {
+        warn!(scope = %scope, "missing scopes");
+        // we won't use it
+        if let Err(e) = revoke_token(&app_config.lichess, &token.access_token).await {
+            warn!(error = %e, "error revoking lichess token");
         }
+        return Err("missing_scope")
+    }
+
+    let account = fetch_account(&app_config.lichess, &token.access_token).await
+        .map_err(|e| {
+            warn!(error = %e, "error fetching account");
+            "account"
+        })?;
+    let session_id = create_session(pool, app_config, &account, &token).await
+        .map_err(|e| {
+            warn!(error = %e, "error creating session");
+            "session"
+        })?;
+    cookies.add(session_cookie(session_id, &app_config.session));
+    Ok(())
+}
+
+#[get("/callback?<code>&<state>&<error>")]
+#[instrument(skip_all, fields(request_id = %request_id))]
+pub async fn callback(request_id: RequestId, code: Option<String>, state: Option<String>, error: Option<String>, pool: &State<Pool<Postgres>>, app_config: &State<AppConfig>, cookies: &CookieJar<'_>) -> Redirect {
+    let expected_state = take_private(cookies, STATE_COOKIE);
+    let code_verifier = take_private(cookies, CODE_VERIFIER_COOKIE);
+
+    let result = match verify_callback(code, state.as_deref(), error.as_deref(), expected_state.as_deref(), code_verifier) {
+        Ok((code, code_verifier)) => complete_login(pool, app_config, cookies, &code, &code_verifier).await,
+        Err(reason) => Err(reason)
     };
+    match result {
+        Ok(()) => Redirect::to(format!("{}/dashboard", &app_config.url)),
+        Err(reason) => {
+            warn!(reason, "login failed");
+            Redirect::to(format!("{}/?login_error={reason}", &app_config.url))
+        }
+    }
+}
 
-    let body = json!({
-        "grant_type": "authorization_code",
-        "redirect_uri": redirect_uri,
-        "client_id": "lightningchess",
-        "code": code,
-        "code_verifier": code_verifier
-    });
-    println!("body: {}", body.to_string());
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    return match Client::new()
-        .post("https://lichess.org/api/token")
-        .json(&body)
-        .send().await {
-        Ok(res) => {
-            println!("Status: {}", res.status());
-            println!("Headers:\n{:#?}", res.headers());
+    fn verify(code: Option<&str>, state: Option<&str>, error: Option<&str>) -> Result<(String, String), &'static str> {
+        verify_callback(code.map(str::to_string), state, error, Some("expected"), Some("verifier".to_string()))
+    }
+
+    #[test]
+    fn valid_callback() {
+        assert_eq!(verify(Some("code"), Some("expected"), None), Ok(("code".to_string(), "verifier".to_string())));
+    }
+
+    #[test]
+    fn rejected_callbacks() {
+        assert_eq!(verify(None, Some("expected"), Some("access_denied")), Err("access_denied"));
+        assert_eq!(verify(None, Some("expected"), Some("server_error")), Err("authorization_failed"));
+        assert_eq!(verify(Some("code"), None, None), Err("invalid_state"));
+        assert_eq!(verify(Some("code"), Some("forged"), None), Err("invalid_state"));
+        assert_eq!(verify(Some("code"), Some("expectedx"), None), Err("invalid_state"));
+        assert_eq!(verify(None, Some("expected"), None), Err("missing_code"));
+        assert_eq!(verify_callback(Some("code".to_string()), Some("expected"), None, None, Some("verifier".to_string())), Err("expired"));
+        assert_eq!(verify_callback(Some("code".to_string()), Some("expected"), None, Some("expected"), None), Err("expired"));
+    }
 
-            match res.text().await {
-                Ok(text) => {
-                    println!("text!: {}", text);
-                    let token_response: TokenResponse = serde_json::from_str(&text).unwrap();
-                    let cookie = Cookie::build("llchess_access_token", token_response.access_token)
-                        .same_site(SameSite::None)
-                        .secure(true)
-                        .max_age(Duration::days(365))
-                        .finish();
-                    cookies.add(cookie);
-                }
-                Err(e) => {
-                    println!("error:\n{}", e);
-                }
-            };
-            Some(Redirect::to(format!("{}/dashboard", &app_config.url)))
-        },
-        Err(e) => {
-            println!("error:\n{}", e);
-            None
-        },
+    #[test]
+    fn granted_scopes() {
+        assert!(has_scopes("challenge:write"));
+        assert!(has_scopes("preference:read challenge:write"));
+        assert!(has_scopes("challenge:read,challenge:write"));
+        assert!(!has_scopes("challenge:read"));
+        assert!(!has_scopes(""));
     }
-}
\ No newline at end of file
+}
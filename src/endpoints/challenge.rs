@@ -1,497 +1,543 @@
-use rocket::http::{Status};
+use moka::future::Cache;
 use rocket::State;
-use crate::models::{Balance, Challenge, ChallengeAcceptRequest, Transaction, User};
+use crate::models::{Balance, Challenge, Transaction, ChallengeAcceptRequest, ChallengeTotals, DisputeRequest, OddsSuggestion, RequestId, User};
 use sqlx::Postgres;
 use sqlx::Pool;
-use crate::errors::{LightningChessResult, ParseRequestError};
-use crate::lichess::client::{accept_lichess_challenge, add_time, create_lichess_challenge};
+use tracing::{info, instrument};
+use crate::accept_saga::{self, Failure, Player};
+use crate::config::{AppConfig, StakeConfig};
+use crate::disputes::file_dispute;
+use crate::crypto::encrypt;
+use crate::errors::{AppError, AppResult};
+use crate::game_events::GameWatcher;
+use crate::history::{date_range, page_size, paginate, parse_cursor};
+use crate::ledger::{change_balance, Actor, BalanceChange};
+use crate::games::{load_game, record_game};
+use crate::lichess::client::{export_game, fetch_lichess_user};
+use crate::metrics::{CHALLENGES, SATS_ESCROWED};
+use crate::odds::{fair_opponent_sats, perf_for_speed, speed_for_clock, win_probability};
+use crate::sessions::revoke_rejected_token;
+use crate::settlement::{void_waiting, UNFINISHED_STATUSES};
+
+fn required<T>(value: Option<T>, name: &str) -> AppResult<T> {
+    value.ok_or_else(|| AppError::Validation(format!("{name} required")))
+}
+
+fn parse_request_to_challenge(challenge_request: &str, stakes: &StakeConfig) -> AppResult<Challenge> {
+    let challenge: Challenge = serde_json::from_str(challenge_request)?;
 
-fn parse_request_to_challenge(challenge_request: &str) -> LightningChessResult<Challenge> {
-    let challenge: Challenge = serde_json::from_str(&challenge_request)?;
+    let time_limit = required(challenge.time_limit, "time_limit")?;
+    let opp_time_limit = required(challenge.opponent_time_limit, "opp_time_limit")?;
+    if !(60..=600).contains(&time_limit) || !(60..=600).contains(&opp_time_limit) || time_limit % 15 != 0 || opp_time_limit % 15 != 0 {
+        return Err(AppError::Validation("time limit constraint".to_string()))
+    }
 
-    let time_limit = challenge.time_limit.ok_or::<ParseRequestError>(ParseRequestError { m: "time_limit required".to_string()})?;
-    let opp_time_limit = challenge.opponent_time_limit.ok_or::<ParseRequestError>(ParseRequestError {m: "opp_time_limit required".to_string()})?;
-    if time_limit < 60 || time_limit > 600 || opp_time_limit < 60 || opp_time_limit > 600 || time_limit % 15 != 0 || opp_time_limit % 15 != 0 {
-        return Err(ParseRequestError { m: "time limit constraint".to_string()}.into())
+    let increment = required(challenge.increment, "increment")?;
+    if !(0..=5).contains(&increment) {
+        return Err(AppError::Validation("increment constraint".to_string()))
     }
 
-    let increment = challenge.increment.ok_or::<ParseRequestError>(ParseRequestError {m: "increment required".to_string()})?;
-    if increment < 0 || increment > 5 {
-        return Err(ParseRequestError { m: "increment constraint".to_string()}.into())
+    let sats = required(challenge.sats, "sats")?;
+    if !stakes.allowed(sats) {
+        return Err(AppError::Validation("sats constraint".to_string()))
     }
 
-    let sats = challenge.sats.ok_or::<ParseRequestError>(ParseRequestError {m: "sats required".to_string()})?;
-    if sats < 100 || sats > 3_000_000 {
-        return Err(ParseRequestError {m: "sats constraint".to_string()}.into())
+    // opponent stake defaults to an even wager
+    let opponent_sats = challenge.opponent_sats.unwrap_or(sats);
+    if !stakes.allowed(opponent_sats) {
+        return Err(AppError::Validation("opponent_sats constraint".to_string()))
     }
 
-    let color = challenge.color.as_ref().ok_or::<ParseRequestError>(ParseRequestError {m: "color required".to_string()})?;
+    let color = required(challenge.color.as_ref(), "color")?;
     if color != "white" && color != "black" {
-        return Err(ParseRequestError {m: "color constraint".to_string()}.into())
+        return Err(AppError::Validation("color constraint".to_string()))
     }
 
-    Ok(challenge)
+    Ok(Challenge { opponent_sats: Some(opponent_sats), ..challenge })
+}
+
+async fn require_balance(pool: &Pool<Postgres>, username: &str, amount: i64) -> AppResult<()> {
+    let balance = sqlx::query_as::<_,Balance>( "SELECT * FROM lightningchess_balance WHERE username=$1")
+        .bind(username)
+        .fetch_optional(pool).await?;
+    match balance {
+        Some(balance) if balance.balance >= 0 && balance.balance >= amount => Ok(()),
+        _ => Err(AppError::InsufficientFunds)
+    }
 }
 
 #[post("/api/challenge", data = "<challenge_request>")]
-pub async fn create_challenge(user: User, pool: &State<Pool<Postgres>>, challenge_request: String) -> Result<String, Status> {
-    println!("challenge request!: {}", challenge_request);
-    let challenge_result = parse_request_to_challenge(&challenge_request);
-    let challenge = match challenge_result {
-        Ok(c) => c,
-        Err(e) => {
-            println!("error: {}", e);
-            return Err(Status::BadRequest)
-        }
-    };
+#[instrument(skip_all, fields(request_id = %request_id, username = %user.username))]
+pub async fn create_challenge(request_id: RequestId, user: User, pool: &State<Pool<Postgres>>, app_config: &State<AppConfig>, challenge_request: String) -> AppResult<String> {
+    info!(request = %challenge_request, "challenge request");
+    let challenge = parse_request_to_challenge(&challenge_request, &app_config.stakes)?;
 
     // only allow creation of challenge if user has enough funds
-    let balance_result = sqlx::query_as::<_,Balance>( "SELECT * FROM lightningchess_balance WHERE username=$1")
-        .bind(&user.username)
-        .fetch_one(&**pool).await;
-    match balance_result {
-        Ok(balance) => {
-            if balance.balance < 0 || balance.balance < challenge.sats.unwrap() {
-                return Err(Status::InternalServerError)
-            }
-        },
-        Err(e) => {
-            println!("error: {}", e);
-            return Err(Status::InternalServerError)
-        }
-    }
+    require_balance(pool, &user.username, challenge.sats.unwrap_or_default()).await?;
+
+    // the challenger's token is needed to accept the lichess challenge on their behalf later
+    let challenger_token = encrypt(&app_config.encryption, &user.access_token)
+        .map_err(|e| AppError::Internal(format!("token encryption: {e}")))?;
 
     //create transaction
-    let tx_result = pool.begin().await;
-    let mut tx = match tx_result {
-        Ok(t) => t,
-        Err(e) => {
-            println!("error: {}", e);
-            return Err(Status::InternalServerError)
-        }
-    };
+    let mut tx = pool.begin().await?;
 
     // save challenge to db
     let status = "WAITING FOR ACCEPTANCE";
-    let challenge_result = sqlx::query_as::<_,Challenge>("INSERT INTO challenge (username, time_limit, opponent_time_limit, increment, color, sats, opp_username, status, expire_after, challenger_token) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10) RETURNING *")
+    let challenge = sqlx::query_as::<_,Challenge>("INSERT INTO challenge (username, time_limit, opponent_time_limit, increment, color, sats, opponent_sats, opp_username, status, expire_after, challenger_token) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11) RETURNING *")
         .bind(&user.username)
-        .bind(&challenge.time_limit)
-        .bind(&challenge.opponent_time_limit)
-        .bind(&challenge.increment)
+        .bind(challenge.time_limit)
+        .bind(challenge.opponent_time_limit)
+        .bind(challenge.increment)
         .bind(&challenge.color)
-        .bind(&challenge.sats)
+        .bind(challenge.sats)
+        .bind(challenge.opponent_sats)
         .bind(&challenge.opp_username)
-        .bind(&status)
+        .bind(status)
         .bind(1800) // default to 30min expiry
-        .bind(&user.access_token)
-        .fetch_one(&mut tx).await;
+        .bind(&challenger_token)
+        .fetch_one(&mut tx).await?;
 
-    let challenge_json_result = match challenge_result {
-        Ok(r) => {
-            Ok(serde_json::to_string(&r).unwrap())
-        },
-        Err(e) => {
-            println!("insert challenge error: {}", e);
-            return Err(Status::InternalServerError)
-        }
-    };
-    challenge_json_result
+    // the challenger's stake is escrowed until the challenge is accepted, voided or expires
+    let sats = challenge.sats.unwrap_or_default();
+    let transaction = sqlx::query_as::<_,Transaction>("INSERT INTO lightningchess_transaction (username, ttype, detail, amount, state) VALUES ($1, $2, $3, $4, $5) RETURNING *")
+        .bind(&user.username)
+        .bind("create challenge")
+        .bind(format!("challenge vs {}", challenge.opp_username))
+        .bind(-sats)
+        .bind("SETTLED")
+        .fetch_one(&mut tx).await?;
+    let change = BalanceChange::new(&user.username, -sats, "escrow stake")
+        .challenge(challenge.id)
+        .transaction(transaction.transaction_id);
+    let balance = change_balance(&mut tx, &Actor::user(&user.username, &request_id), change).await?;
+    if balance.balance < 0 {
+        return Err(AppError::InsufficientFunds)
+    }
+
+    // commit transaction, return challenge
+    tx.commit().await?;
+    CHALLENGES.with_label_values(&["created"]).inc();
+    SATS_ESCROWED.inc_by(sats as u64);
+    Ok(serde_json::to_string(&challenge)?)
 }
 
 #[post("/api/accept-challenge", data = "<challenge_accept_request>")]
-pub async fn accept_challenge(user: User, pool: &State<Pool<Postgres>>, challenge_accept_request: String) -> Result<String, Status> {
-    println!("challenge_accept_request!: {}", challenge_accept_request);
-    let challenge_accept_request_result: Result<ChallengeAcceptRequest, serde_json::Error> = serde_json::from_str(&challenge_accept_request);
-    let challenge_accept_request = match challenge_accept_request_result {
-        Ok(c) => c,
-        Err(e) => {
-            println!("error: {}", e);
-            return Err(Status::BadRequest)
-        }
-    };
+#[instrument(skip_all, fields(request_id = %request_id, username = %user.username))]
+pub async fn accept_challenge(request_id: RequestId, user: User, pool: &State<Pool<Postgres>>, app_config: &State<AppConfig>, cache: &State<Cache<String, User>>, game_watcher: &State<GameWatcher>, challenge_accept_request: String) -> AppResult<String> {
+    info!(request = %challenge_accept_request, "challenge accept request");
+    let challenge_accept_request: ChallengeAcceptRequest = serde_json::from_str(&challenge_accept_request)?;
 
-    let challenge_result = sqlx::query_as::<_,Challenge>( "SELECT * FROM challenge WHERE id=$1")
+    let challenge = sqlx::query_as::<_,Challenge>( "SELECT * FROM challenge WHERE id=$1")
         .bind(challenge_accept_request.id)
-        .fetch_one(&**pool).await;
-
-    let challenge = match challenge_result {
-        Ok(c) => c,
-        Err(e) => {
-            println!("error getting challenge in challenge accept: {}", e.as_database_error().unwrap().message());
-            return Err(Status::InternalServerError)
-        }
-    };
+        .fetch_optional(&**pool).await?
+        .ok_or_else(|| AppError::NotFound("challenge".to_string()))?;
 
     // only opponent can accept the challenge and challenge must be in correct status
-    if challenge.opp_username != user.username || challenge.status.as_ref().unwrap() != "WAITING FOR ACCEPTANCE" {
-        return Err(Status::BadRequest)
+    if challenge.opp_username != user.username {
+        return Err(AppError::Forbidden)
     }
-
-    // only allow accept of challenge if user has enough funds
-    let balance_result = sqlx::query_as::<_,Balance>( "SELECT * FROM lightningchess_balance WHERE username=$1")
-        .bind(&user.username)
-        .fetch_one(&**pool).await;
-    match balance_result {
-        Ok(balance) => {
-            if balance.balance < 0 || balance.balance < challenge.sats.unwrap() {
-                return Err(Status::InternalServerError)
-            }
-        },
-        Err(e) => {
-            println!("error: {}", e);
-            return Err(Status::InternalServerError)
-        }
+    if challenge.status.as_deref() != Some("WAITING FOR ACCEPTANCE") {
+        return Err(AppError::Validation("challenge is not waiting for acceptance".to_string()))
     }
 
-    //create transaction
-    let tx_result = pool.begin().await;
-    let mut tx = match tx_result {
-        Ok(t) => t,
-        Err(e) => {
-            println!("error creating tx: {}", e);
-            return Err(Status::InternalServerError)
-        }
-    };
+    let challenger_token = accept_saga::challenger_token(pool, app_config, &challenge).await?;
 
-    // deduct balance
-    let balance_result = sqlx::query_as::<_,Balance>( "UPDATE lightningchess_balance SET balance=balance - $1 WHERE username=$2 RETURNING *")
-        .bind(challenge.sats.unwrap())
-        .bind(&user.username)
-        .fetch_one(&mut tx).await;
-
-    match balance_result {
-        Ok(balance) => {
-            if balance.balance < 0 {
-                println!("balance is less than 0");
-                return Err(Status::InternalServerError)
-            }
-            println!("updated balance")
+    // only allow accept of challenge if user has enough funds
+    let sats = required(challenge.sats, "sats")?;
+    let opponent_sats = challenge.opponent_sats.unwrap_or(sats);
+    require_balance(pool, &user.username, opponent_sats).await?;
+
+    // the acceptor's stake is escrowed first, a failed lichess step undoes everything before the error is returned
+    let actor = Actor::user(&user.username, &request_id);
+    let saga = accept_saga::begin(pool, app_config, &actor, &challenge, &user).await?;
+    let challenge = match accept_saga::run(pool, app_config, &actor, saga, &challenge, &user, &challenger_token).await {
+        Ok(challenge) => challenge,
+        Err(Failure { error, rejected: Some(Player::Acceptor) }) => {
+            revoke_rejected_token(pool, app_config, cache, &user.username, &user.access_token).await?;
+            return Err(error)
         },
-        Err(e) => {
-            println!("error updating balance: {}", e);
-            return Err(Status::InternalServerError)
-        }
-    };
-
-    // insert transaction into transaction db
-    let ttype = "accept challenge";
-    let detail = format!("challenge vs {}", challenge.username);
-    let state = "SETTLED";
-    let transaction_result = sqlx::query_as::<_,Transaction>("INSERT INTO lightningchess_transaction (username, ttype, detail, amount, state) VALUES ($1, $2, $3, $4, $5) RETURNING *")
-        .bind(&user.username)
-        .bind(ttype)
-        .bind(&detail)
-        .bind(-challenge.sats.unwrap())
-        .bind(state)
-        .fetch_one(&mut tx).await;
-
-    match transaction_result {
-        Ok(_) => println!("successfully inserted transaction"),
-        Err(e) => {
-            println!("error inserting tx: {}", e);
-            return Err(Status::InternalServerError)
-        }
-    }
-
-    println!("create challenge lichess challenge");
-    let lichess_challenge_response = create_lichess_challenge(&user, &challenge).await?;
-    println!("accept challenge lichess");
-    accept_lichess_challenge(&challenge, &lichess_challenge_response).await?;
-    println!("add time lichess");
-    add_time(&user, &challenge, &lichess_challenge_response).await?;
-
-    // update challenge in db
-    let status = "ACCEPTED";
-    let pg_query_result = sqlx::query_as::<_,Challenge>("UPDATE challenge SET status=$1, lichess_challenge_id=$2 WHERE id=$3 RETURNING *")
-        .bind(status)
-        .bind(&lichess_challenge_response.challenge.id)
-        .bind(&challenge_accept_request.id)
-        .fetch_one(&mut tx).await;
-
-    let challenge_json_result = match pg_query_result {
-        Ok(r) => {
-            Ok(serde_json::to_string(&r).unwrap())
+        Err(Failure { rejected: Some(Player::Challenger), .. }) => {
+            // nobody can accept it without the challenger's token
+            revoke_rejected_token(pool, app_config, cache, &challenge.username, &challenger_token).await?;
+            void_waiting(pool, &actor, challenge.id).await?;
+            return Err(AppError::Validation("the challenger's lichess login expired, the challenge was voided".to_string()))
         },
-        Err(e) => {
-            println!("update challenge in challenge accept: {}", e);
-            return Err(Status::InternalServerError)
-        }
+        Err(Failure { error, .. }) => return Err(error)
     };
 
-    // commit transaction, return challenge
-    let commit_result = tx.commit().await;
-    return match commit_result {
-        Ok(_) => {
-            challenge_json_result
-        },
-        Err(e) => {
-            println!("error committing: {}", e);
-            Err(Status::InternalServerError)
-        }
+    CHALLENGES.with_label_values(&["accepted"]).inc();
+    SATS_ESCROWED.inc_by(opponent_sats as u64);
+    if let Some(game_id) = &challenge.lichess_challenge_id {
+        game_watcher.watch(game_id);
     }
-
+    Ok(serde_json::to_string(&challenge)?)
 }
-#[get("/api/challenges")]
-pub async fn challenges(user: User, pool: &State<Pool<Postgres>>) -> Result<String, Status> {
-    let challenges = sqlx::query_as::<_,Challenge>( "SELECT * FROM challenge WHERE username=$1 OR opp_username=$1 ORDER BY created_on DESC LIMIT 100")
+
+#[allow(clippy::too_many_arguments)]
+#[get("/api/challenges?<cursor>&<limit>&<status>&<opponent>&<from>&<to>")]
+#[instrument(skip_all, fields(request_id = %request_id, username = %user.username))]
+pub async fn challenges(request_id: RequestId, user: User, pool: &State<Pool<Postgres>>, cursor: Option<String>, limit: Option<i64>, status: Option<String>, opponent: Option<String>, from: Option<String>, to: Option<String>) -> AppResult<String> {
+    let (from, to) = date_range(from.as_deref(), to.as_deref())?;
+    let cursor = parse_cursor(cursor.as_deref())?;
+    let page_size = page_size(limit);
+    let filter = "(username=$1 OR opp_username=$1) AND ($2::VARCHAR IS NULL OR status=$2) \
+        AND ($3::VARCHAR IS NULL OR (username=$1 AND opp_username=$3) OR (opp_username=$1 AND username=$3)) \
+        AND ($4::TIMESTAMP IS NULL OR created_on >= $4) AND ($5::TIMESTAMP IS NULL OR created_on < $5)";
+
+    let challenges = sqlx::query_as::<_,Challenge>(&format!("SELECT * FROM challenge WHERE {filter} AND ($6::INT IS NULL OR id < $6) ORDER BY id DESC LIMIT $7"))
+        .bind(&user.username)
+        .bind(&status)
+        .bind(&opponent)
+        .bind(from)
+        .bind(to)
+        .bind(cursor)
+        .bind(page_size + 1)
+        .fetch_all(&**pool).await?;
+    let totals = sqlx::query_as::<_,ChallengeTotals>(&format!("SELECT COUNT(*) AS count, \
+        COALESCE(SUM(CASE WHEN username=$1 THEN COALESCE(sats, 0) ELSE COALESCE(opponent_sats, sats, 0) END), 0)::BIGINT AS sats_staked \
+        FROM challenge WHERE {filter}"))
         .bind(&user.username)
-        .fetch_all(&**pool).await;
-    match challenges {
-        Ok(challenges) => Ok(serde_json::to_string(&challenges).unwrap()),
-        Err(e) => {
-            println!("error: {}", e);
-            Err(Status::InternalServerError)
-        }
+        .bind(&status)
+        .bind(&opponent)
+        .bind(from)
+        .bind(to)
+        .fetch_one(&**pool).await?;
+
+    Ok(serde_json::to_string(&paginate(challenges, page_size, |c| c.id, totals))?)
+}
+
+// a challenge the user plays in
+async fn player_challenge(pool: &Pool<Postgres>, user: &User, challenge_id: &str) -> AppResult<Challenge> {
+    let challenge_id_int = challenge_id.parse::<i32>()
+        .map_err(|_| AppError::Validation("invalid challenge id".to_string()))?;
+    let challenge = sqlx::query_as::<_,Challenge>( "SELECT * FROM challenge WHERE id=$1")
+        .bind(challenge_id_int)
+        .fetch_optional(pool).await?
+        .ok_or_else(|| AppError::NotFound("challenge".to_string()))?;
+
+    // only be able to look up own games
+    if challenge.username != user.username && challenge.opp_username != user.username {
+        return Err(AppError::Forbidden)
     }
+    Ok(challenge)
 }
 
 #[get("/api/challenge/<challenge_id>")]
-pub async fn lookup_challenge(user: User, pool: &State<Pool<Postgres>>, challenge_id: String) -> Result<String, Status> {
-    let challenge_id_int = match challenge_id.parse::<i32>() {
-        Ok(i) => i,
-        Err(_) => return Err(Status::BadRequest)
+#[instrument(skip_all, fields(request_id = %request_id, username = %user.username))]
+pub async fn lookup_challenge(request_id: RequestId, user: User, pool: &State<Pool<Postgres>>, challenge_id: String) -> AppResult<String> {
+    let challenge = player_challenge(pool, &user, &challenge_id).await?;
+    Ok(serde_json::to_string(&challenge)?)
+}
+
+// pgn, moves, clocks and opening of the challenge's game once it's over
+#[get("/api/challenge/<challenge_id>/game")]
+#[instrument(skip_all, fields(request_id = %request_id, username = %user.username))]
+pub async fn challenge_game(request_id: RequestId, user: User, pool: &State<Pool<Postgres>>, app_config: &State<AppConfig>, challenge_id: String) -> AppResult<String> {
+    let challenge = player_challenge(pool, &user, &challenge_id).await?;
+    if let Some(game) = load_game(pool, challenge.id).await? {
+        return Ok(serde_json::to_string(&game)?)
+    }
+
+    // games settled before they were recorded, or resolved by an admin, are exported on first look
+    let game_id = match (challenge.status.as_deref(), challenge.lichess_challenge_id.as_deref()) {
+        (Some("ACCEPTED") | Some("STARTED"), _) => return Err(AppError::Validation("game is not over yet".to_string())),
+        (_, Some(game_id)) => game_id,
+        _ => return Err(AppError::NotFound("game".to_string()))
     };
-    let challenge = sqlx::query_as::<_,Challenge>( "SELECT * FROM challenge WHERE id=$1")
-        .bind(challenge_id_int)
-        .fetch_one(&**pool).await;
-
-    match challenge {
-        Ok(challenge) =>  {
-            // only be able to look up own games
-            if challenge.username != user.username && challenge.opp_username != user.username {
-                Err(Status::Unauthorized)
-            } else {
-                Ok(serde_json::to_string(&challenge).unwrap())
-            }
-        },
-        Err(e) => {
-            println!("error: {}", e);
-            Err(Status::InternalServerError)
-        }
+    let game = export_game(&app_config.lichess, game_id).await?;
+    if UNFINISHED_STATUSES.contains(&game.status.as_str()) {
+        return Err(AppError::Validation("game is not over yet".to_string()))
     }
+    record_game(pool, challenge.id, &game).await?;
+    let game = load_game(pool, challenge.id).await?
+        .ok_or_else(|| AppError::NotFound("game".to_string()))?;
+    Ok(serde_json::to_string(&game)?)
+}
+
+// contests the result, settlement waits until an admin resolves it
+#[post("/api/challenge/<challenge_id>/dispute", data = "<dispute_request>")]
+#[instrument(skip_all, fields(request_id = %request_id, username = %user.username))]
+pub async fn dispute_challenge(request_id: RequestId, user: User, pool: &State<Pool<Postgres>>, challenge_id: String, dispute_request: String) -> AppResult<String> {
+    let dispute_request: DisputeRequest = serde_json::from_str(&dispute_request)?;
+    let challenge = player_challenge(pool, &user, &challenge_id).await?;
+    let dispute = file_dispute(pool, &user.username, challenge.id, &dispute_request.reason).await?;
+    Ok(serde_json::to_string(&dispute)?)
+}
+
+// suggests the stake the opponent should put up so the wager is fair given both lichess ratings
+#[get("/api/odds/<opp_username>?<sats>&<time_limit>&<increment>")]
+#[instrument(skip_all, fields(request_id = %request_id, username = %user.username))]
+pub async fn suggest_odds(request_id: RequestId, user: User, app_config: &State<AppConfig>, opp_username: String, sats: i64, time_limit: i32, increment: i32) -> AppResult<String> {
+    if !app_config.stakes.allowed(sats) {
+        return Err(AppError::Validation("sats constraint".to_string()))
+    }
+    let speed = speed_for_clock(time_limit, increment);
+    let lichess_user = fetch_lichess_user(&app_config.lichess, &user.username).await?;
+    let opp_lichess_user = fetch_lichess_user(&app_config.lichess, &opp_username).await?;
+    // odds from a rating of 0 would ask for an extreme handicap
+    let rating = perf_for_speed(&lichess_user.perfs, speed)
+        .ok_or_else(|| AppError::Validation(format!("{} has no {speed} rating, no odds to suggest", user.username)))?
+        .rating;
+    let opponent_rating = perf_for_speed(&opp_lichess_user.perfs, speed)
+        .ok_or_else(|| AppError::Validation(format!("{opp_username} has no {speed} rating, no odds to suggest")))?
+        .rating;
+    let win_probability = win_probability(rating, opponent_rating);
+
+    let odds_suggestion = OddsSuggestion {
+        speed: speed.to_string(),
+        rating,
+        opponent_rating,
+        win_probability,
+        sats,
+        opponent_sats: fair_opponent_sats(sats, win_probability)
+    };
+    Ok(serde_json::to_string(&odds_suggestion)?)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-
-    fn get_challenge() -> Challenge {
-        Challenge {
-            id: 1,
-            username: "user1".to_string(),
-            time_limit: Some(300),
-            opponent_time_limit: Some(300),
-            increment: Some(0),
-            color: Some("white".to_string()),
-            sats: Some(100),
-            opp_username: "user2".to_string(),
-            status: None,
-            lichess_challenge_id: None,
-            created_on: None,
-            expire_after: None
-        }
-    }
+    use crate::models::test_challenge;
 
     #[test]
     fn valid_challenge() {
-        let challenge = get_challenge();
+        let challenge = test_challenge();
         let challenge_json = serde_json::to_string(&challenge).unwrap();
-        let res = parse_request_to_challenge(&challenge_json);
+        let res = parse_request_to_challenge(&challenge_json, &StakeConfig::default());
         assert!(res.is_ok());
     }
 
     #[test]
     fn invalid_sat_low() {
-        let base_challenge = get_challenge();
+        let base_challenge = test_challenge();
         let challenge = Challenge {
             sats: Some(99),
             ..base_challenge
         };
         let challenge_json = serde_json::to_string(&challenge).unwrap();
-        let res = parse_request_to_challenge(&challenge_json);
+        let res = parse_request_to_challenge(&challenge_json, &StakeConfig::default());
         assert!(res.is_err());
     }
 
     #[test]
     fn invalid_sat_high() {
-        let base_challenge = get_challenge();
+        let base_challenge = test_challenge();
         let challenge = Challenge {
             sats: Some(3_000_001),
             ..base_challenge
         };
         let challenge_json = serde_json::to_string(&challenge).unwrap();
-        let res = parse_request_to_challenge(&challenge_json);
+        let res = parse_request_to_challenge(&challenge_json, &StakeConfig::default());
         assert!(res.is_err());
     }
 
     #[test]
     fn invalid_sat_none() {
-        let base_challenge = get_challenge();
+        let base_challenge = test_challenge();
         let challenge = Challenge {
             sats: None,
             ..base_challenge
         };
         let challenge_json = serde_json::to_string(&challenge).unwrap();
-        let res = parse_request_to_challenge(&challenge_json);
+        let res = parse_request_to_challenge(&challenge_json, &StakeConfig::default());
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn opponent_sats_defaults_to_sats() {
+        let base_challenge = test_challenge();
+        let challenge = Challenge {
+            sats: Some(500),
+            opponent_sats: None,
+            ..base_challenge
+        };
+        let challenge_json = serde_json::to_string(&challenge).unwrap();
+        let res = parse_request_to_challenge(&challenge_json, &StakeConfig::default()).unwrap();
+        assert_eq!(res.opponent_sats, Some(500));
+    }
+
+    #[test]
+    fn valid_uneven_stakes() {
+        let base_challenge = test_challenge();
+        let challenge = Challenge {
+            sats: Some(3000),
+            opponent_sats: Some(1000),
+            ..base_challenge
+        };
+        let challenge_json = serde_json::to_string(&challenge).unwrap();
+        let res = parse_request_to_challenge(&challenge_json, &StakeConfig::default());
+        assert!(res.is_ok());
+    }
+
+    #[test]
+    fn invalid_opponent_sats_low() {
+        let base_challenge = test_challenge();
+        let challenge = Challenge {
+            opponent_sats: Some(99),
+            ..base_challenge
+        };
+        let challenge_json = serde_json::to_string(&challenge).unwrap();
+        let res = parse_request_to_challenge(&challenge_json, &StakeConfig::default());
         assert!(res.is_err());
     }
 
     #[test]
     fn invalid_color() {
-        let base_challenge = get_challenge();
+        let base_challenge = test_challenge();
         let challenge = Challenge {
             color: Some("test".to_string()),
             ..base_challenge
         };
         let challenge_json = serde_json::to_string(&challenge).unwrap();
-        let res = parse_request_to_challenge(&challenge_json);
+        let res = parse_request_to_challenge(&challenge_json, &StakeConfig::default());
         assert!(res.is_err());
     }
 
     #[test]
     fn invalid_color_none() {
-        let base_challenge = get_challenge();
+        let base_challenge = test_challenge();
         let challenge = Challenge {
             color: None,
             ..base_challenge
         };
         let challenge_json = serde_json::to_string(&challenge).unwrap();
-        let res = parse_request_to_challenge(&challenge_json);
+        let res = parse_request_to_challenge(&challenge_json, &StakeConfig::default());
         assert!(res.is_err());
     }
 
     #[test]
     fn invalid_time_limit_low() {
-        let base_challenge = get_challenge();
+        let base_challenge = test_challenge();
         let challenge = Challenge {
             time_limit: Some(45),
             ..base_challenge
         };
         let challenge_json = serde_json::to_string(&challenge).unwrap();
-        let res = parse_request_to_challenge(&challenge_json);
+        let res = parse_request_to_challenge(&challenge_json, &StakeConfig::default());
         assert!(res.is_err());
     }
 
     #[test]
     fn invalid_time_limit_high() {
-        let base_challenge = get_challenge();
+        let base_challenge = test_challenge();
         let challenge = Challenge {
             time_limit: Some(615),
             ..base_challenge
         };
         let challenge_json = serde_json::to_string(&challenge).unwrap();
-        let res = parse_request_to_challenge(&challenge_json);
+        let res = parse_request_to_challenge(&challenge_json, &StakeConfig::default());
         assert!(res.is_err());
     }
 
 
     #[test]
     fn invalid_time_limit_none() {
-        let base_challenge = get_challenge();
+        let base_challenge = test_challenge();
         let challenge = Challenge {
             time_limit: None,
             ..base_challenge
         };
         let challenge_json = serde_json::to_string(&challenge).unwrap();
-        let res = parse_request_to_challenge(&challenge_json);
+        let res = parse_request_to_challenge(&challenge_json, &StakeConfig::default());
         assert!(res.is_err());
     }
 
     #[test]
     fn invalid_time_limit_not_fifteen() {
-        let base_challenge = get_challenge();
+        let base_challenge = test_challenge();
         let challenge = Challenge {
             time_limit: Some(74),
             ..base_challenge
         };
         let challenge_json = serde_json::to_string(&challenge).unwrap();
-        let res = parse_request_to_challenge(&challenge_json);
+        let res = parse_request_to_challenge(&challenge_json, &StakeConfig::default());
         assert!(res.is_err());
     }
 
     #[test]
     fn invalid_opp_time_limit_low() {
-        let base_challenge = get_challenge();
+        let base_challenge = test_challenge();
         let challenge = Challenge {
             opponent_time_limit: Some(30),
             ..base_challenge
         };
         let challenge_json = serde_json::to_string(&challenge).unwrap();
-        let res = parse_request_to_challenge(&challenge_json);
+        let res = parse_request_to_challenge(&challenge_json, &StakeConfig::default());
         assert!(res.is_err());
     }
 
     #[test]
     fn invalid_opp_time_limit_high() {
-        let base_challenge = get_challenge();
+        let base_challenge = test_challenge();
         let challenge = Challenge {
             opponent_time_limit: Some(615),
             ..base_challenge
         };
         let challenge_json = serde_json::to_string(&challenge).unwrap();
-        let res = parse_request_to_challenge(&challenge_json);
+        let res = parse_request_to_challenge(&challenge_json, &StakeConfig::default());
         assert!(res.is_err());
     }
 
 
     #[test]
     fn invalid_opp_time_limit_none() {
-        let base_challenge = get_challenge();
+        let base_challenge = test_challenge();
         let challenge = Challenge {
             opponent_time_limit: None,
             ..base_challenge
         };
         let challenge_json = serde_json::to_string(&challenge).unwrap();
-        let res = parse_request_to_challenge(&challenge_json);
+        let res = parse_request_to_challenge(&challenge_json, &StakeConfig::default());
         assert!(res.is_err());
     }
 
     #[test]
     fn invalid_opponent_time_limit_not_fifteen() {
-        let base_challenge = get_challenge();
+        let base_challenge = test_challenge();
         let challenge = Challenge {
             opponent_time_limit: Some(74),
             ..base_challenge
         };
         let challenge_json = serde_json::to_string(&challenge).unwrap();
-        let res = parse_request_to_challenge(&challenge_json);
+        let res = parse_request_to_challenge(&challenge_json, &StakeConfig::default());
         assert!(res.is_err());
     }
 
     #[test]
     fn invalid_increment_low() {
-        let base_challenge = get_challenge();
+        let base_challenge = test_challenge();
         let challenge = Challenge {
             increment: Some(-1),
             ..base_challenge
         };
         let challenge_json = serde_json::to_string(&challenge).unwrap();
-        let res = parse_request_to_challenge(&challenge_json);
+        let res = parse_request_to_challenge(&challenge_json, &StakeConfig::default());
         assert!(res.is_err());
     }
 
     #[test]
     fn invalid_increment_high() {
-        let base_challenge = get_challenge();
+        let base_challenge = test_challenge();
         let challenge = Challenge {
             increment: Some(6),
             ..base_challenge
         };
         let challenge_json = serde_json::to_string(&challenge).unwrap();
-        let res = parse_request_to_challenge(&challenge_json);
+        let res = parse_request_to_challenge(&challenge_json, &StakeConfig::default());
         assert!(res.is_err());
     }
 
 
     #[test]
     fn invalid_increment_none() {
-        let base_challenge = get_challenge();
+        let base_challenge = test_challenge();
         let challenge = Challenge {
             increment: None,
             ..base_challenge
         };
         let challenge_json = serde_json::to_string(&challenge).unwrap();
-        let res = parse_request_to_challenge(&challenge_json);
+        let res = parse_request_to_challenge(&challenge_json, &StakeConfig::default());
         assert!(res.is_err());
     }
 
@@ -0,0 +1,129 @@
+use std::time::{Duration, Instant};
+use rocket::http::{ContentType, Status};
+use rocket::State;
+use serde::Serialize;
+use serde_json::{json, Value};
+use sqlx::{Pool, Postgres};
+use tokio::sync::Mutex;
+use crate::config::{AppConfig, LichessConfig, LndConfig};
+use crate::lichess::client::lichess_status;
+use crate::lightning::node::{channel_balance, get_info};
+
+// probes are answered from the last result for this long so they don't hammer the upstreams
+const READINESS_TTL: Duration = Duration::from_secs(10);
+
+#[derive(Default)]
+pub struct ReadinessCache {
+    last: Mutex<Option<(Instant, Readiness)>>
+}
+
+#[derive(Clone, Serialize)]
+pub struct DependencyCheck {
+    pub ok: bool,
+    pub latency_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(skip_serializing_if = "Value::is_null")]
+    pub detail: Value
+}
+
+#[derive(Clone, Serialize)]
+pub struct Readiness {
+    pub status: &'static str,
+    pub postgres: DependencyCheck,
+    pub lnd: DependencyCheck,
+    pub lichess: DependencyCheck
+}
+
+impl Readiness {
+    // without postgres nothing works, lnd and lichess outages only degrade parts of the app
+    pub fn http_status(&self) -> Status {
+        if self.postgres.ok { Status::Ok } else { Status::ServiceUnavailable }
+    }
+}
+
+fn check(start: Instant, result: Result<Value, String>) -> DependencyCheck {
+    let latency_ms = start.elapsed().as_millis() as u64;
+    match result {
+        Ok(detail) => DependencyCheck { ok: true, latency_ms, error: None, detail },
+        Err(e) => DependencyCheck { ok: false, latency_ms, error: Some(e), detail: Value::Null }
+    }
+}
+
+async fn check_postgres(pool: &Pool<Postgres>) -> DependencyCheck {
+    let start = Instant::now();
+    let result = sqlx::query("SELECT 1").execute(pool).await
+        .map(|_| json!({ "pool_size": pool.size(), "idle": pool.num_idle() }))
+        .map_err(|e| e.to_string());
+    check(start, result)
+}
+
+async fn check_lnd(lnd: &LndConfig) -> DependencyCheck {
+    let start = Instant::now();
+    let (info, balance) = match tokio::try_join!(get_info(lnd), channel_balance(lnd)) {
+        Ok(r) => r,
+        Err(e) => return check(start, Err(e.to_string()))
+    };
+    let outbound_sats = balance.local_balance.sat.parse::<i64>().unwrap_or(0);
+    let error = if !info.synced_to_chain {
+        Some("not synced to chain")
+    } else if info.num_active_channels == 0 {
+        Some("no active channels")
+    } else if outbound_sats <= 0 {
+        Some("no outbound liquidity")
+    } else {
+        None
+    };
+    // node details are reported either way so an operator can see why it's failing
+    DependencyCheck {
+        ok: error.is_none(),
+        latency_ms: start.elapsed().as_millis() as u64,
+        error: error.map(str::to_string),
+        detail: json!({
+            "synced_to_chain": info.synced_to_chain,
+            "active_channels": info.num_active_channels,
+            "outbound_sats": outbound_sats,
+            "block_height": info.block_height
+        })
+    }
+}
+
+async fn check_lichess(lichess: &LichessConfig) -> DependencyCheck {
+    let start = Instant::now();
+    let result = lichess_status(lichess).await
+        .map(|_| Value::Null)
+        .map_err(|e| e.to_string());
+    check(start, result)
+}
+
+async fn readiness(pool: &Pool<Postgres>, app_config: &AppConfig, cache: &ReadinessCache) -> Readiness {
+    // holding the lock while probing collapses concurrent probes into one
+    let mut last = cache.last.lock().await;
+    if let Some((checked_at, readiness)) = last.as_ref() {
+        if checked_at.elapsed() < READINESS_TTL {
+            return readiness.clone()
+        }
+    }
+
+    let (postgres, lnd, lichess) = tokio::join!(check_postgres(pool), check_lnd(&app_config.lnd), check_lichess(&app_config.lichess));
+    let status = match (postgres.ok, lnd.ok && lichess.ok) {
+        (false, _) => "unavailable",
+        (true, false) => "degraded",
+        (true, true) => "ok"
+    };
+    let readiness = Readiness { status, postgres, lnd, lichess };
+    *last = Some((Instant::now(), readiness.clone()));
+    readiness
+}
+
+#[get("/healthz")]
+pub fn healthz() -> (ContentType, String) {
+    (ContentType::JSON, json!({ "status": "ok" }).to_string())
+}
+
+#[get("/readyz")]
+pub async fn readyz(pool: &State<Pool<Postgres>>, app_config: &State<AppConfig>, cache: &State<ReadinessCache>) -> (Status, (ContentType, String)) {
+    let readiness = readiness(pool, app_config, cache).await;
+    let body = serde_json::to_string(&readiness).unwrap_or_default();
+    (readiness.http_status(), (ContentType::JSON, body))
+}
@@ -0,0 +1,25 @@
+use rocket::State;
+use sqlx::{Pool, Postgres};
+use tracing::{info, instrument};
+use crate::errors::AppResult;
+use crate::leaderboard::{set_opt_out, top, MAX_ENTRIES};
+use crate::models::{LeaderboardOptOutRequest, LeaderboardResponse, RequestId, User};
+
+// public, defaults to the all time top earners
+#[get("/api/leaderboard?<window>&<category>&<limit>")]
+#[instrument(skip_all, fields(request_id = %request_id))]
+pub async fn leaderboard_endpoint(request_id: RequestId, pool: &State<Pool<Postgres>>, window: Option<String>, category: Option<String>, limit: Option<i64>) -> AppResult<String> {
+    let window = window.unwrap_or_else(|| "all".to_string());
+    let category = category.unwrap_or_else(|| "net_sats".to_string());
+    let entries = top(pool, &window, &category, limit.unwrap_or(MAX_ENTRIES)).await?;
+    Ok(serde_json::to_string(&LeaderboardResponse { window, category, entries })?)
+}
+
+#[post("/api/leaderboard/opt-out", data = "<opt_out_request>")]
+#[instrument(skip_all, fields(request_id = %request_id, username = %user.username))]
+pub async fn leaderboard_opt_out(request_id: RequestId, user: User, pool: &State<Pool<Postgres>>, opt_out_request: String) -> AppResult<String> {
+    let request: LeaderboardOptOutRequest = serde_json::from_str(&opt_out_request)?;
+    set_opt_out(pool, &user.username, request.opt_out).await?;
+    info!(opt_out = request.opt_out, "leaderboard opt out changed");
+    Ok(serde_json::to_string(&request)?)
+}
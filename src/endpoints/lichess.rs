@@ -1,8 +1,12 @@
-use rocket::http::Status;
+use rocket::State;
+use crate::config::AppConfig;
+use crate::errors::AppResult;
 use crate::lichess::client::lichess_user;
-use crate::models::{User};
+use crate::models::{RequestId, User};
+use tracing::instrument;
 
 #[get("/api/lichess/user/<username>")]
-pub async fn lichess_user_endpoint(_user: User, username: String) -> Result<String, Status> {
-    lichess_user(&username).await
-}
\ No newline at end of file
+#[instrument(skip_all, fields(request_id = %request_id, username = %username))]
+pub async fn lichess_user_endpoint(request_id: RequestId, _user: User, app_config: &State<AppConfig>, username: String) -> AppResult<String> {
+    lichess_user(&app_config.lichess, &username).await
+}
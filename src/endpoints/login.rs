@@ -5,7 +5,29 @@ use rocket::{State};
 use rocket::http::{Cookie, CookieJar};
 use rocket::response::Redirect;
 use sha2::{Digest, Sha256};
-use crate::AppConfig;
+use crate::config::AppConfig;
+
+pub const CODE_VERIFIER_COOKIE: &str = "codeVerifier";
+pub const STATE_COOKIE: &str = "oauthState";
+// creating, accepting and adding time to challenges, nothing else is used
+pub const SCOPES: &str = "challenge:write";
+
+fn random_string(len: usize) -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(len)
+        .map(char::from)
+        .collect()
+}
+
+// only readable by us and gone once the login is over
+fn login_cookie(name: &'static str, value: String) -> Cookie<'static> {
+    Cookie::build(name, value)
+        .same_site(SameSite::None)
+        .secure(true)
+        .max_age(Duration::minutes(10))
+        .finish()
+}
 
 #[get("/login")]
 pub fn login(app_config: &State<AppConfig>, cookies: &CookieJar<'_>) -> Redirect {
@@ -18,22 +40,24 @@ pub fn login(app_config: &State<AppConfig>, cookies: &CookieJar<'_>) -> Redirect
         .collect();
     let verifier = base64::encode_config(&rand, base64::URL_SAFE_NO_PAD);
     let digest = Sha256::digest(verifier.as_bytes());
-    let challenge = base64::encode_config(&digest, base64::URL_SAFE_NO_PAD);
+    let challenge = base64::encode_config(digest, base64::URL_SAFE_NO_PAD);
 
-    // add verifier to private cookie
-    let cookie = Cookie::build("codeVerifier", verifier)
-        .same_site(SameSite::None)
-        .secure(true)
-        .max_age(Duration::minutes(10))
-        .finish();
-    cookies.add_private(cookie);
+    // the callback only continues a login this browser started
+    let state = random_string(32);
+
+    // add verifier and state to private cookies
+    cookies.add_private(login_cookie(CODE_VERIFIER_COOKIE, verifier));
+    cookies.add_private(login_cookie(STATE_COOKIE, state.clone()));
 
-    Redirect::to(format!("https://lichess.org/oauth?\
+    let lichess_url = &app_config.lichess.url;
+    let client_id = &app_config.lichess.client_id;
+    Redirect::to(format!("{lichess_url}/oauth?\
        response_type=code&\
-       client_id=lightningchess&\
+       client_id={client_id}&\
        redirect_uri={redirect_uri}&\
-       scope=preference:read%20challenge:write&\
+       scope={SCOPES}&\
+       state={state}&\
        code_challenge_method=S256&\
        code_challenge={challenge}")
     )
-}
\ No newline at end of file
+}
@@ -0,0 +1,22 @@
+use prometheus::TextEncoder;
+use rocket::http::ContentType;
+use rocket::State;
+use sqlx::{Pool, Postgres};
+use tracing::error;
+use crate::metrics::DB_POOL;
+
+#[get("/metrics")]
+pub async fn metrics_endpoint(pool: &State<Pool<Postgres>>) -> (ContentType, String) {
+    // pool usage is sampled at scrape time
+    let size = pool.size() as i64;
+    let idle = pool.num_idle() as i64;
+    DB_POOL.with_label_values(&["open"]).set(size);
+    DB_POOL.with_label_values(&["idle"]).set(idle);
+    DB_POOL.with_label_values(&["in_use"]).set(size - idle);
+
+    let body = TextEncoder::new().encode_to_string(&prometheus::gather()).unwrap_or_else(|e| {
+        error!(error = %e, "error encoding metrics");
+        String::new()
+    });
+    (ContentType::new("text", "plain").with_params(("version", "0.0.4")), body)
+}
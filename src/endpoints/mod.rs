@@ -1,6 +1,12 @@
+pub mod admin;
 pub mod callback;
 pub mod challenge;
+pub mod health;
+pub mod leaderboard;
 pub mod login;
+pub mod metrics;
+pub mod notifications;
 pub mod lichess;
 pub mod profile;
+pub mod session;
 pub mod money;
@@ -1,24 +1,33 @@
 use rand::distributions::Alphanumeric;
 use rand::Rng;
-use rocket::http::Status;
+use chrono::NaiveDateTime;
+use rocket::http::Header;
 use rocket::State;
 use sqlx::{Pool, Postgres};
-use crate::errors::{sqlx_err_to_status};
-use crate::models::{Transaction, AddInvoiceRequest, User, Balance, SendPaymentRequest, SendPaymentResponse};
+use crate::config::AppConfig;
+use crate::errors::{AppError, AppResult};
+use crate::history::{date_range, page_size, paginate, parse_cursor, to_csv};
+use crate::ledger::{change_balance, Actor, BalanceChange};
+use crate::models::{Transaction, AddInvoiceRequest, RequestId, User, Balance, SendPaymentRequest, SendPaymentResponse, TransactionTotals};
+use tracing::{info, instrument, warn};
 use crate::lightning::invoices::add_invoice;
-use crate::lightning::payment::{decode_payment, make_payment};
+use crate::metrics::{outcome_label, DEPOSITS, WITHDRAWALS};
+use crate::lightning::payment::{decode_payment, make_payment, PaymentOutcome};
 
 #[post("/api/invoice", data = "<invoice_request_str>")]
-pub async fn add_invoice_endpoint(user: User, pool: &State<Pool<Postgres>>, invoice_request_str: String) -> Result<String, Status> {
-    println!("invoice request: {}", invoice_request_str);
-    let invoice_request_result: Result<AddInvoiceRequest, serde_json::Error> = serde_json::from_str(&invoice_request_str);
-    let invoice_request = match invoice_request_result {
-        Ok(i) => i,
-        Err(e) => {
-            println!("error: {}", e);
-            return Err(Status::BadRequest)
-        }
-    };
+#[instrument(skip_all, fields(request_id = %request_id, username = %user.username))]
+pub async fn add_invoice_endpoint(request_id: RequestId, user: User, pool: &State<Pool<Postgres>>, app_config: &State<AppConfig>, invoice_request_str: String) -> AppResult<String> {
+    let result = create_deposit_invoice(user, pool, app_config, invoice_request_str).await;
+    DEPOSITS.with_label_values(&[outcome_label(&result, "invoice_created")]).inc();
+    result
+}
+
+async fn create_deposit_invoice(user: User, pool: &Pool<Postgres>, app_config: &AppConfig, invoice_request_str: String) -> AppResult<String> {
+    info!(request = %invoice_request_str, "invoice request");
+    let invoice_request: AddInvoiceRequest = serde_json::from_str(&invoice_request_str)?;
+    if invoice_request.sats <= 0 {
+        return Err(AppError::Validation("sats must be positive".to_string()))
+    }
 
     // create preimage
     let preimage_bytes: Vec<u8>  = rand::thread_rng()
@@ -30,16 +39,12 @@ pub async fn add_invoice_endpoint(user: User, pool: &State<Pool<Postgres>>, invo
     let memo = format!("fund {} on lightningchess.io", &user.username);
 
     // create invoice
-    let add_invoice_response_option = add_invoice(invoice_request.sats, &memo, preimage_bytes).await;
-    let add_invoice_response = match add_invoice_response_option {
-        Some(i) => i,
-        None => return Err(Status::InternalServerError)
-    };
+    let add_invoice_response = add_invoice(&app_config.lnd, invoice_request.sats, &memo, preimage_bytes).await?;
 
     // save it to db
     let ttype = "invoice";
     let state = "OPEN";
-    let pg_query_result = sqlx::query_as::<_,Transaction>("INSERT INTO lightningchess_transaction (username, ttype, detail, amount, state, payment_addr, payment_request) VALUES ($1, $2, $3, $4, $5, $6, $7) RETURNING *")
+    let transaction = sqlx::query_as::<_,Transaction>("INSERT INTO lightningchess_transaction (username, ttype, detail, amount, state, payment_addr, payment_request) VALUES ($1, $2, $3, $4, $5, $6, $7) RETURNING *")
         .bind(&user.username)
         .bind(ttype)
         .bind(&memo)
@@ -47,211 +52,236 @@ pub async fn add_invoice_endpoint(user: User, pool: &State<Pool<Postgres>>, invo
         .bind(state)
         .bind(&add_invoice_response.payment_addr)
         .bind(&add_invoice_response.payment_request)
-        .fetch_one(&**pool).await;
+        .fetch_one(pool).await?;
 
-    return match pg_query_result {
-        Ok(r) => {
-            Ok(serde_json::to_string(&r).unwrap())
-        },
-        Err(e) => {
-            println!("error: {}", e.as_database_error().unwrap().message());
-            Err(Status::InternalServerError)
-        }
-    }
+    Ok(serde_json::to_string(&transaction)?)
 }
 
 #[post("/api/transaction/<transaction_id>")]
-pub async fn lookup_transaction(user: User, pool: &State<Pool<Postgres>>, transaction_id: String) -> Result<String, Status> {
-    let transaction_id_int = match transaction_id.parse::<i32>() {
-        Ok(i) => i,
-        Err(_) => return Err(Status::BadRequest)
-    };
+#[instrument(skip_all, fields(request_id = %request_id, username = %user.username))]
+pub async fn lookup_transaction(request_id: RequestId, user: User, pool: &State<Pool<Postgres>>, transaction_id: String) -> AppResult<String> {
+    let transaction_id_int = transaction_id.parse::<i32>()
+        .map_err(|_| AppError::Validation("invalid transaction id".to_string()))?;
 
-    let transaction_result = sqlx::query_as::<_,Transaction>( "SELECT * FROM lightningchess_transaction WHERE transaction_id=$1")
+    let transaction = sqlx::query_as::<_,Transaction>( "SELECT * FROM lightningchess_transaction WHERE transaction_id=$1")
         .bind(transaction_id_int)
-        .fetch_one(&**pool).await;
-
-    let transaction = match transaction_result {
-        Ok(t) => t,
-        Err(e) => {
-            println!("error: {}", e);
-            return Err(Status::InternalServerError)
-        }
-    };
+        .fetch_optional(&**pool).await?
+        .ok_or_else(|| AppError::NotFound("transaction".to_string()))?;
 
     if transaction.username != user.username {
-        return Err(Status::Unauthorized)
+        return Err(AppError::Forbidden)
     }
 
-    let transaction_result2 = sqlx::query_as::<_,Transaction>( "SELECT * FROM lightningchess_transaction WHERE transaction_id=$1")
-        .bind(transaction_id_int)
-        .fetch_one(&**pool).await;
+    Ok(serde_json::to_string(&transaction)?)
+}
 
-    return match transaction_result2 {
-        Ok(t2) => Ok(serde_json::to_string(&t2).unwrap()),
-        Err(e) => {
-            println!("error getting t2: {}", e);
-            return Err(Status::InternalServerError)
-        }
+// filters shared by the history page, its totals and the csv export
+const TRANSACTION_FILTER: &str = "username=$1 AND ($2::VARCHAR IS NULL OR ttype=$2) AND ($3::VARCHAR IS NULL OR state=$3) AND ($4::TIMESTAMP IS NULL OR created_on >= $4) AND ($5::TIMESTAMP IS NULL OR created_on < $5)";
+
+struct TransactionFilter {
+    ttype: Option<String>,
+    state: Option<String>,
+    from: Option<NaiveDateTime>,
+    to: Option<NaiveDateTime>
+}
+
+impl TransactionFilter {
+    fn new(ttype: Option<String>, state: Option<String>, from: Option<String>, to: Option<String>) -> AppResult<TransactionFilter> {
+        let (from, to) = date_range(from.as_deref(), to.as_deref())?;
+        Ok(TransactionFilter { ttype, state, from, to })
     }
 }
 
-#[get("/api/transactions")]
-pub async fn transactions(user: User, pool: &State<Pool<Postgres>>) -> Result<String, Status> {
-    let transactions = sqlx::query_as::<_,Transaction>( "SELECT * FROM lightningchess_transaction WHERE username=$1 ORDER BY transaction_id DESC LIMIT 100")
+// newest first, limit None returns every matching transaction
+async fn filtered_transactions(pool: &Pool<Postgres>, username: &str, filter: &TransactionFilter, cursor: Option<i32>, limit: Option<i64>) -> Result<Vec<Transaction>, sqlx::Error> {
+    sqlx::query_as::<_,Transaction>(&format!("SELECT * FROM lightningchess_transaction WHERE {TRANSACTION_FILTER} AND ($6::INT IS NULL OR transaction_id < $6) ORDER BY transaction_id DESC LIMIT $7"))
+        .bind(username)
+        .bind(&filter.ttype)
+        .bind(&filter.state)
+        .bind(filter.from)
+        .bind(filter.to)
+        .bind(cursor)
+        .bind(limit)
+        .fetch_all(pool).await
+}
+
+#[allow(clippy::too_many_arguments)]
+#[get("/api/transactions?<cursor>&<limit>&<ttype>&<state>&<from>&<to>")]
+#[instrument(skip_all, fields(request_id = %request_id, username = %user.username))]
+pub async fn transactions(request_id: RequestId, user: User, pool: &State<Pool<Postgres>>, cursor: Option<String>, limit: Option<i64>, ttype: Option<String>, state: Option<String>, from: Option<String>, to: Option<String>) -> AppResult<String> {
+    let filter = TransactionFilter::new(ttype, state, from, to)?;
+    let cursor = parse_cursor(cursor.as_deref())?;
+    let page_size = page_size(limit);
+
+    let transactions = filtered_transactions(pool, &user.username, &filter, cursor, Some(page_size + 1)).await?;
+    let totals = sqlx::query_as::<_,TransactionTotals>(&format!("SELECT COUNT(*) AS count, \
+        COALESCE(SUM(amount) FILTER (WHERE amount > 0), 0)::BIGINT AS credited, \
+        COALESCE(SUM(amount) FILTER (WHERE amount < 0), 0)::BIGINT AS debited, \
+        COALESCE(SUM(amount), 0)::BIGINT AS net \
+        FROM lightningchess_transaction WHERE {TRANSACTION_FILTER}"))
         .bind(&user.username)
-        .fetch_all(&**pool).await;
+        .bind(&filter.ttype)
+        .bind(&filter.state)
+        .bind(filter.from)
+        .bind(filter.to)
+        .fetch_one(&**pool).await?;
 
-    match transactions {
-        Ok(t) => Ok(serde_json::to_string(&t).unwrap()),
-        Err(e) => {
-            println!("error: {}", e);
-            Err(Status::InternalServerError)
-        }
+    Ok(serde_json::to_string(&paginate(transactions, page_size, |t| t.transaction_id, totals))?)
+}
+
+#[derive(Responder)]
+#[response(content_type = "text/csv")]
+pub struct CsvDownload {
+    csv: String,
+    disposition: Header<'static>
+}
+
+#[allow(clippy::too_many_arguments)]
+#[get("/api/transactions/export?<format>&<ttype>&<state>&<from>&<to>")]
+#[instrument(skip_all, fields(request_id = %request_id, username = %user.username))]
+pub async fn export_transactions(request_id: RequestId, user: User, pool: &State<Pool<Postgres>>, format: Option<String>, ttype: Option<String>, state: Option<String>, from: Option<String>, to: Option<String>) -> AppResult<CsvDownload> {
+    if format.as_deref().unwrap_or("csv") != "csv" {
+        return Err(AppError::Validation("only csv exports are supported".to_string()))
     }
+    let filter = TransactionFilter::new(ttype, state, from, to)?;
+    let transactions = filtered_transactions(pool, &user.username, &filter, None, None).await?;
+    info!(rows = transactions.len(), "transactions exported");
+
+    let rows: Vec<Vec<String>> = transactions.into_iter().map(|t| vec![
+        t.transaction_id.to_string(),
+        t.created_on.map(|c| c.format("%Y-%m-%d %H:%M:%S").to_string()).unwrap_or_default(),
+        t.ttype,
+        t.state,
+        t.amount.to_string(),
+        t.detail,
+        t.lichess_challenge_id.unwrap_or_default(),
+        t.payment_hash.unwrap_or_default()
+    ]).collect();
+    let csv = to_csv(&["transaction_id", "created_on_utc", "type", "state", "amount_sats", "detail", "lichess_challenge_id", "payment_hash"], &rows);
+    let disposition = Header::new("Content-Disposition", format!("attachment; filename=\"lightningchess-{}-transactions.csv\"", user.username));
+    Ok(CsvDownload { csv, disposition })
 }
 
 #[get("/api/balance")]
-pub async fn balance(user: User, pool: &State<Pool<Postgres>>) -> Result<String, Status> {
-    println!("getting balance for {}", &user.username);
-    let balance_result = sqlx::query_as::<_,Balance>( "SELECT * FROM lightningchess_balance WHERE username=$1")
+#[instrument(skip_all, fields(request_id = %request_id, username = %user.username))]
+pub async fn balance(request_id: RequestId, user: User, pool: &State<Pool<Postgres>>) -> AppResult<String> {
+    let balance_option = sqlx::query_as::<_,Balance>( "SELECT * FROM lightningchess_balance WHERE username=$1")
         .bind(&user.username)
-        .fetch_optional(&**pool).await;
-    match balance_result {
-        Ok(balance_option) => {
-            match balance_option {
-                Some(balance) => Ok(serde_json::to_string(&balance).unwrap()),
-                None => Ok(serde_json::to_string(&Balance {
-                    balance_id: 0,
-                    username: user.username,
-                    balance: 0
-                }).unwrap())
-            }
-        },
-        Err(e) => {
-            println!("error: {}", e);
-            Err(Status::InternalServerError)
-        }
-    }
+        .fetch_optional(&**pool).await?;
+    let balance = balance_option.unwrap_or(Balance {
+        balance_id: 0,
+        username: user.username,
+        balance: 0
+    });
+    Ok(serde_json::to_string(&balance)?)
 }
 
 #[post("/api/send-payment", data = "<send_payment_request_str>")]
-pub async fn send_payment_endpoint(user: User, pool: &State<Pool<Postgres>>, send_payment_request_str: String) -> Result<String, Status> {
-    println!("send_payment_request_str: {}", send_payment_request_str);
-    let send_payment_result: Result<SendPaymentRequest, serde_json::Error> = serde_json::from_str(&send_payment_request_str);
-    let send_payment = match send_payment_result {
-        Ok(sp) => sp,
-        Err(e) => {
-            println!("error: {}", e);
-            return Err(Status::BadRequest)
-        }
+#[instrument(skip_all, fields(request_id = %request_id, username = %user.username))]
+pub async fn send_payment_endpoint(request_id: RequestId, user: User, pool: &State<Pool<Postgres>>, app_config: &State<AppConfig>, send_payment_request_str: String) -> AppResult<String> {
+    let result = withdraw(&request_id, user, pool, app_config, send_payment_request_str).await;
+    let outcome = match &result {
+        Ok(response) if !response.complete => "awaiting_approval",
+        _ => outcome_label(&result, "settled")
     };
+    WITHDRAWALS.with_label_values(&[outcome]).inc();
+    Ok(serde_json::to_string(&result?)?)
+}
+
+async fn withdraw(request_id: &RequestId, user: User, pool: &Pool<Postgres>, app_config: &AppConfig, send_payment_request_str: String) -> AppResult<SendPaymentResponse> {
+    info!(request = %send_payment_request_str, "send payment request");
+    let send_payment: SendPaymentRequest = serde_json::from_str(&send_payment_request_str)?;
 
     // decode
-    let decoded_option = decode_payment(&send_payment.payment_request).await;
-    let decoded_payment = match decoded_option {
-        Some(dp) => dp,
-        None => return Err(Status::BadRequest)
-    };
-    let withdrawal_amt = decoded_payment.num_satoshis.parse::<i64>().unwrap();
+    let decoded_payment = decode_payment(&app_config.lnd, &send_payment.payment_request).await?;
+    let withdrawal_amt = decoded_payment.num_satoshis.parse::<i64>()
+        .map_err(|_| AppError::Validation("invoice amount required".to_string()))?;
 
     // not sure if this is possible
     if withdrawal_amt <= 0 {
-        return Err(Status::BadRequest)
+        return Err(AppError::Validation("invoice amount must be positive".to_string()))
     }
 
-    let mut tx = pool.begin().await.map_err(sqlx_err_to_status)?;
+    let mut tx = pool.begin().await?;
 
-    let balance_result = sqlx::query_as::<_,Balance>( "SELECT * FROM lightningchess_balance WHERE username=$1 FOR UPDATE")
+    let balance = sqlx::query_as::<_,Balance>( "SELECT * FROM lightningchess_balance WHERE username=$1 FOR UPDATE")
         .bind(&user.username)
-        .fetch_one(&mut tx).await;
-    let balance = match balance_result {
-        Ok(b) => b,
-        Err(e) => {
-            println!("error: {}", e);
-            return Err(Status::BadRequest)
-        }
-    };
+        .fetch_optional(&mut tx).await?
+        .ok_or(AppError::InsufficientFunds)?;
 
     // only send if they have enough money
     if balance.balance < withdrawal_amt {
-        return Err(Status::BadRequest)
+        return Err(AppError::InsufficientFunds)
     }
 
-    let withdrawal_amt_neg = withdrawal_amt * -1;
+    let withdrawal_amt_neg = -withdrawal_amt;
 
-    // insert payment into transactions table with status == 0PEN, commit
+    // large withdrawals are debited now and only paid once an admin approves them
+    if app_config.admin.needs_approval(withdrawal_amt) {
+        let transaction = sqlx::query_as::<_, Transaction>( "INSERT INTO lightningchess_transaction (username, ttype, detail, amount, state, payment_request, payment_hash) VALUES ($1, $2, $3, $4, $5, $6, $7) RETURNING *")
+            .bind(&user.username)
+            .bind("withdrawal")
+            .bind("")
+            .bind(withdrawal_amt_neg)
+            .bind("AWAITING APPROVAL")
+            .bind(&send_payment.payment_request)
+            .bind(&decoded_payment.payment_hash)
+            .fetch_one(&mut tx).await?;
+        let change = BalanceChange::new(&user.username, withdrawal_amt_neg, "withdrawal held").transaction(transaction.transaction_id);
+        change_balance(&mut tx, &Actor::user(&user.username, request_id), change).await?;
+        tx.commit().await?;
+        info!(transaction_id = transaction.transaction_id, amount = withdrawal_amt, "withdrawal awaiting approval");
+        return Ok(SendPaymentResponse { complete: false })
+    }
+
+    // insert payment into transactions table with status == OPEN and debit it, commit
     // if we don't do this, we never have a way to retry if the update the db fails after the payment is made
     let withdrawal_ttype = "withdrawal";
     let withdrawal_detail = "";
     let withdrawal_state = "OPEN";
-    let withdrawal_transaction_result = sqlx::query_as::<_, Transaction>( "INSERT INTO lightningchess_transaction (username, ttype, detail, amount, state, payment_hash) VALUES ($1, $2, $3, $4, $5, $6) RETURNING *")
+    let withdrawal_transaction = sqlx::query_as::<_, Transaction>( "INSERT INTO lightningchess_transaction (username, ttype, detail, amount, state, payment_request, payment_hash) VALUES ($1, $2, $3, $4, $5, $6, $7) RETURNING *")
         .bind(&user.username)
         .bind(withdrawal_ttype)
         .bind(withdrawal_detail)
-        .bind(&withdrawal_amt_neg)
+        .bind(withdrawal_amt_neg)
         .bind(withdrawal_state)
+        .bind(&send_payment.payment_request)
         .bind(&decoded_payment.payment_hash)
-        .fetch_one(&mut tx).await;
-
-    let withdrawal_transaction = match withdrawal_transaction_result {
-        Ok(t) => {
-            println!("withdrawal transaction insert successfully");
-            t
-        },
-        Err(e) => {
-            println!("insert transaction failed {}", e);
-            return Err(Status::InternalServerError)
-        }
-    };
+        .fetch_one(&mut tx).await?;
+    let change = BalanceChange::new(&user.username, withdrawal_amt_neg, "withdrawal").transaction(withdrawal_transaction.transaction_id);
+    change_balance(&mut tx, &Actor::user(&user.username, request_id), change).await?;
+    tx.commit().await?;
+    info!(transaction_id = withdrawal_transaction.transaction_id, amount = withdrawal_amt, "withdrawal opened");
 
     // send payment to lightning node
-    match make_payment(&send_payment.payment_request).await {
-        Some(_) => (),
-        None => return Err(Status::InternalServerError)
-    }
-
-    let new_state = "SETTLED";
-    let updated_transaction = sqlx::query( "UPDATE lightningchess_transaction SET state=$1, amount=$2 WHERE transaction_id=$3")
-        .bind(new_state)
-        .bind(&withdrawal_amt_neg)
-        .bind(withdrawal_transaction.transaction_id)
-        .execute(&mut tx).await;
-
-    match updated_transaction {
-        Ok(_) => println!("successfully updated_transaction transaction id"),
+    let outcome = match make_payment(&app_config.lnd, &send_payment.payment_request, app_config.stakes.withdrawal_fee_limit_sats).await {
+        Ok(o) => o,
         Err(e) => {
-            println!("error updated_transaction transaction id : {}", e);
-            return Err(Status::InternalServerError)
+            // lnd may still pay it, it stays OPEN and debited until rechecked
+            warn!(transaction_id = withdrawal_transaction.transaction_id, error = %e, "withdrawal outcome unknown, left open");
+            return Err(e)
         }
-    }
-
-    let winner_balance = sqlx::query( "UPDATE lightningchess_balance set balance=balance + $1 WHERE username=$2")
-        .bind(&withdrawal_amt_neg)
-        .bind(&user.username)
-        .execute(&mut tx).await;
+    };
 
-    match winner_balance {
-        Ok(_) => println!("successfully payed admin"),
-        Err(e) => {
-            println!("error paying admin admin_transaction transaction{}", e);
-            return Err(Status::InternalServerError)
+    if let PaymentOutcome::Failed(reason) = outcome {
+        let mut tx = pool.begin().await?;
+        let failed = sqlx::query( "UPDATE lightningchess_transaction SET state='FAILED' WHERE transaction_id=$1 AND state='OPEN'")
+            .bind(withdrawal_transaction.transaction_id)
+            .execute(&mut tx).await?;
+        if failed.rows_affected() > 0 {
+            let change = BalanceChange::new(&user.username, withdrawal_amt, "failed withdrawal refund").transaction(withdrawal_transaction.transaction_id);
+            change_balance(&mut tx, &Actor::user(&user.username, request_id), change).await?;
         }
+        tx.commit().await?;
+        warn!(transaction_id = withdrawal_transaction.transaction_id, reason = %reason, "withdrawal failed");
+        return Err(AppError::Lnd(format!("payment failed {reason}")))
     }
 
-    // commit transaction
-    let commit_result = tx.commit().await;
-    return match commit_result {
-        Ok(_) => {
-            println!("successfully committed");
-            let send_payment_response = SendPaymentResponse {
-                complete: true
-            };
-            Ok(serde_json::to_string(&send_payment_response).unwrap())
-        },
-        Err(e) => {
-            println!("error committing: {}", e);
-            Err(Status::InternalServerError)
-        }
-    }
+    sqlx::query( "UPDATE lightningchess_transaction SET state='SETTLED' WHERE transaction_id=$1 AND state='OPEN'")
+        .bind(withdrawal_transaction.transaction_id)
+        .execute(pool).await?;
+    info!(transaction_id = withdrawal_transaction.transaction_id, "withdrawal settled");
+    Ok(SendPaymentResponse {
+        complete: true
+    })
 }
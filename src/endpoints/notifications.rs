@@ -0,0 +1,22 @@
+use rocket::State;
+use serde_json::json;
+use sqlx::{Pool, Postgres};
+use tracing::instrument;
+use crate::errors::AppResult;
+use crate::models::{RequestId, User};
+use crate::notifications::{list_notifications, mark_read};
+
+// newest first, only the ones not read yet with unread=true
+#[get("/api/notifications?<unread>")]
+#[instrument(skip_all, fields(request_id = %request_id, username = %user.username))]
+pub async fn notifications_endpoint(request_id: RequestId, user: User, pool: &State<Pool<Postgres>>, unread: Option<bool>) -> AppResult<String> {
+    let notifications = list_notifications(pool, &user.username, unread.unwrap_or(false)).await?;
+    Ok(serde_json::to_string(&notifications)?)
+}
+
+#[post("/api/notifications/read")]
+#[instrument(skip_all, fields(request_id = %request_id, username = %user.username))]
+pub async fn read_notifications_endpoint(request_id: RequestId, user: User, pool: &State<Pool<Postgres>>) -> AppResult<String> {
+    let read = mark_read(pool, &user.username).await?;
+    Ok(json!({ "read": read }).to_string())
+}
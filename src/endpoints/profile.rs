@@ -1,9 +1,44 @@
-use crate::models::{User, UserProfile};
+use rocket::State;
+use sqlx::{Pool, Postgres};
+use tracing::instrument;
+use crate::errors::{AppError, AppResult};
+use crate::models::{RequestId, User, UserProfile};
+use crate::stats::{cached_player_stats, with_head_to_head, StatsCache};
+
+// preferences are an opaque json object owned by the frontend
+const MAX_PREFERENCES_BYTES: usize = 4096;
 
 #[get("/api/profile")]
-pub async fn profile(user: User) -> String {
-    let user_profile: UserProfile = UserProfile {
-        username: user.username
-    };
-    serde_json::to_string(&user_profile).unwrap()
-}
\ No newline at end of file
+#[instrument(skip_all, fields(request_id = %request_id, username = %user.username))]
+pub async fn profile(request_id: RequestId, user: User, pool: &State<Pool<Postgres>>) -> AppResult<String> {
+    let user_profile = sqlx::query_as::<_,UserProfile>("SELECT username, lichess_id, created_on, last_seen, preferences::TEXT AS preferences FROM users WHERE user_id=$1")
+        .bind(user.user_id)
+        .fetch_optional(&**pool).await?
+        .ok_or_else(|| AppError::NotFound("user".to_string()))?;
+    Ok(serde_json::to_string(&user_profile)?)
+}
+
+#[post("/api/preferences", data = "<preferences>")]
+#[instrument(skip_all, fields(request_id = %request_id, username = %user.username))]
+pub async fn update_preferences(request_id: RequestId, user: User, pool: &State<Pool<Postgres>>, preferences: String) -> AppResult<String> {
+    if preferences.len() > MAX_PREFERENCES_BYTES {
+        return Err(AppError::Validation(format!("preferences can't be over {MAX_PREFERENCES_BYTES} bytes")))
+    }
+    let value: serde_json::Value = serde_json::from_str(&preferences)?;
+    if !value.is_object() {
+        return Err(AppError::Validation("preferences must be a json object".to_string()))
+    }
+    sqlx::query("UPDATE users SET preferences=$1::JSONB WHERE user_id=$2")
+        .bind(value.to_string())
+        .bind(user.user_id)
+        .execute(&**pool).await?;
+    Ok(value.to_string())
+}
+
+// anyone logged in can look up a player's record, opponent narrows head to head to one player
+#[get("/api/stats/<username>?<opponent>")]
+#[instrument(skip_all, fields(request_id = %request_id, username = %user.username))]
+pub async fn player_stats_endpoint(request_id: RequestId, user: User, pool: &State<Pool<Postgres>>, cache: &State<StatsCache>, username: String, opponent: Option<String>) -> AppResult<String> {
+    let stats = cached_player_stats(pool, cache, &username).await?;
+    Ok(serde_json::to_string(&with_head_to_head(stats, opponent.as_deref()))?)
+}
@@ -0,0 +1,50 @@
+use moka::future::Cache;
+use rocket::http::{Cookie, CookieJar};
+use rocket::State;
+use serde_json::json;
+use sqlx::{Pool, Postgres};
+use tracing::{info, instrument, warn};
+use crate::config::AppConfig;
+use crate::errors::{AppError, AppResult};
+use crate::ledger::Actor;
+use crate::lichess::client::revoke_token;
+use crate::models::{RequestId, User};
+use crate::sessions::{list_sessions, release_token, revoke_all_sessions, revoke_session, SESSION_COOKIE};
+
+#[post("/logout")]
+#[instrument(skip_all, fields(request_id = %request_id, username = %user.username))]
+pub async fn logout(request_id: RequestId, user: User, pool: &State<Pool<Postgres>>, app_config: &State<AppConfig>, cache: &State<Cache<String, User>>, cookies: &CookieJar<'_>) -> AppResult<String> {
+    revoke_session(pool, &user.session_hash).await?;
+    cache.invalidate(&user.session_hash).await;
+    cookies.remove(Cookie::named(SESSION_COOKIE));
+    let release = release_token(pool, app_config, &Actor::user(&user.username, &request_id), &user).await?;
+    info!(moved = release.moved, voided = release.voided, "logged out");
+
+    // the user is logged out either way, a token lichess didn't revoke still can't be used by us
+    if release.revoke_at_lichess {
+        if let Err(e) = revoke_token(&app_config.lichess, &user.access_token).await {
+            warn!(error = %e, "error revoking lichess token");
+        }
+    }
+    Ok(json!({ "ok": true, "voided_challenges": release.voided }).to_string())
+}
+
+#[get("/api/sessions")]
+#[instrument(skip_all, fields(request_id = %request_id, username = %user.username))]
+pub async fn list_sessions_endpoint(request_id: RequestId, user: User, pool: &State<Pool<Postgres>>) -> AppResult<String> {
+    let sessions = list_sessions(pool, &user).await?;
+    Ok(serde_json::to_string(&sessions)?)
+}
+
+// logs out every device, including this one
+#[post("/api/sessions/revoke-all")]
+#[instrument(skip_all, fields(request_id = %request_id, username = %user.username))]
+pub async fn revoke_all_endpoint(request_id: RequestId, user: User, pool: &State<Pool<Postgres>>, cache: &State<Cache<String, User>>, cookies: &CookieJar<'_>) -> AppResult<String> {
+    let revoked = revoke_all_sessions(pool, user.user_id).await?;
+    let user_id = user.user_id;
+    cache.invalidate_entries_if(move |_, u| u.user_id == user_id)
+        .map_err(|e| AppError::Internal(format!("cache invalidation: {e}")))?;
+    cookies.remove(Cookie::named(SESSION_COOKIE));
+    info!(revoked, "all sessions revoked");
+    Ok(json!({ "revoked": revoked }).to_string())
+}
@@ -1,23 +1,173 @@
 use std::{error, fmt};
-use rocket::http::Status;
-use sqlx::Error;
+use std::io::Cursor;
+use rocket::http::{ContentType, Status};
+use rocket::{Request, Response};
+use rocket::response::{self, Responder};
+use serde_json::json;
+use tracing::{error, info};
 
-pub type LightningChessResult<T> = Result<T, Box<dyn error::Error + Send + Sync>>;
+pub type AppResult<T> = Result<T, AppError>;
 
-#[derive(Debug, Clone)]
-pub struct ParseRequestError {
-    pub(crate) m: String
+#[derive(Debug)]
+pub enum AppError {
+    Validation(String),
+    InsufficientFunds,
+    NotFound(String),
+    Forbidden,
+    RateLimited,
+    // lichess refused the user's token, they have to log in again
+    TokenRejected(String),
+    Lichess(String),
+    Lnd(String),
+    Database(sqlx::Error),
+    // our own failures that aren't the database, e.g. crypto
+    Internal(String)
 }
 
-impl fmt::Display for ParseRequestError {
+impl AppError {
+    pub fn status(&self) -> Status {
+        match self {
+            AppError::Validation(_) => Status::BadRequest,
+            AppError::InsufficientFunds => Status::BadRequest,
+            AppError::NotFound(_) => Status::NotFound,
+            AppError::Forbidden => Status::Forbidden,
+            AppError::RateLimited => Status::TooManyRequests,
+            AppError::TokenRejected(_) => Status::Unauthorized,
+            AppError::Lichess(_) => Status::BadGateway,
+            AppError::Lnd(_) => Status::BadGateway,
+            AppError::Database(_) => Status::InternalServerError,
+            AppError::Internal(_) => Status::InternalServerError
+        }
+    }
+
+    // machine readable code for the frontend
+    pub fn code(&self) -> &'static str {
+        match self {
+            AppError::Validation(_) => "validation",
+            AppError::InsufficientFunds => "insufficient_funds",
+            AppError::NotFound(_) => "not_found",
+            AppError::Forbidden => "forbidden",
+            AppError::RateLimited => "rate_limited",
+            AppError::TokenRejected(_) => "reauthenticate",
+            AppError::Lichess(_) => "upstream_lichess",
+            AppError::Lnd(_) => "upstream_lnd",
+            AppError::Database(_) => "database",
+            AppError::Internal(_) => "internal"
+        }
+    }
+
+    // message safe to show to users, upstream and db details only go to the logs
+    pub fn message(&self) -> String {
+        match self {
+            AppError::Validation(m) => m.to_string(),
+            AppError::InsufficientFunds => "insufficient balance".to_string(),
+            AppError::NotFound(m) => format!("{m} not found"),
+            AppError::Forbidden => "forbidden".to_string(),
+            AppError::RateLimited => "rate limited by lichess, try again later".to_string(),
+            AppError::TokenRejected(_) => "lichess login expired, log in again".to_string(),
+            AppError::Lichess(_) => "lichess request failed".to_string(),
+            AppError::Lnd(_) => "lightning node request failed".to_string(),
+            AppError::Database(_) => "database error".to_string(),
+            AppError::Internal(_) => "internal error".to_string()
+        }
+    }
+}
+
+impl fmt::Display for AppError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "ParseRequestError: {}", &self.m)
+        match self {
+            AppError::Lichess(m) => write!(f, "lichess error: {m}"),
+            AppError::TokenRejected(call) => write!(f, "lichess rejected the token: {call}"),
+            AppError::Lnd(m) => write!(f, "lnd error: {m}"),
+            AppError::Database(e) => write!(f, "db error: {e}"),
+            AppError::Internal(m) => write!(f, "internal error: {m}"),
+            _ => write!(f, "{}: {}", self.code(), self.message())
+        }
     }
 }
 
-impl error::Error for ParseRequestError {}
+impl error::Error for AppError {}
+
+impl From<sqlx::Error> for AppError {
+    fn from(e: sqlx::Error) -> Self {
+        match e {
+            // call sites that know what was missing map it themselves
+            sqlx::Error::RowNotFound => AppError::NotFound("resource".to_string()),
+            e => AppError::Database(e)
+        }
+    }
+}
+
+impl From<serde_json::Error> for AppError {
+    fn from(e: serde_json::Error) -> Self {
+        AppError::Validation(format!("invalid request: {e}"))
+    }
+}
+
+pub fn error_body(code: &str, message: &str) -> String {
+    json!({ "error": { "code": code, "message": message } }).to_string()
+}
 
-pub fn sqlx_err_to_status(e: Error) -> Status {
-    println!("db error: {}", e);
-    Status::InternalServerError
-}
\ No newline at end of file
+impl<'r> Responder<'r, 'static> for AppError {
+    fn respond_to(self, _request: &'r Request<'_>) -> response::Result<'static> {
+        match self {
+            AppError::Lichess(_) | AppError::Lnd(_) | AppError::Database(_) | AppError::Internal(_) => error!(error = %self, "request failed"),
+            _ => info!(error = %self, "request rejected")
+        }
+        let body = error_body(self.code(), &self.message());
+        Response::build()
+            .status(self.status())
+            .header(ContentType::JSON)
+            .sized_body(body.len(), Cursor::new(body))
+            .ok()
+    }
+}
+
+// errors rocket raises before reaching a handler get the same json shape
+#[catch(default)]
+pub fn api_catcher(status: Status, _request: &Request) -> (Status, (ContentType, String)) {
+    let code = match status.code {
+        400 | 422 => "validation",
+        401 => "reauthenticate",
+        403 => "forbidden",
+        404 => "not_found",
+        429 => "rate_limited",
+        _ => "internal"
+    };
+    (status, (ContentType::JSON, error_body(code, status.reason_lossy())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn row_not_found_maps_to_not_found() {
+        let e: AppError = sqlx::Error::RowNotFound.into();
+        assert_eq!(e.status(), Status::NotFound);
+        assert_eq!(e.code(), "not_found");
+        assert_eq!(e.message(), "resource not found");
+    }
+
+    #[test]
+    fn upstream_details_are_not_exposed() {
+        let e = AppError::Lnd("macaroon rejected".to_string());
+        assert_eq!(e.status(), Status::BadGateway);
+        assert!(!e.message().contains("macaroon"));
+        assert!(e.to_string().contains("macaroon"));
+    }
+
+    #[test]
+    fn rejected_tokens_ask_for_login() {
+        let e = AppError::TokenRejected("create challenge".to_string());
+        assert_eq!(e.status(), Status::Unauthorized);
+        assert_eq!(e.code(), "reauthenticate");
+    }
+
+    #[test]
+    fn error_body_shape() {
+        let body: serde_json::Value = serde_json::from_str(&error_body("insufficient_funds", "insufficient balance")).unwrap();
+        assert_eq!(body["error"]["code"], "insufficient_funds");
+        assert_eq!(body["error"]["message"], "insufficient balance");
+    }
+}
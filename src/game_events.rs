@@ -0,0 +1,200 @@
+use std::time::Duration;
+use rand::{distributions::Alphanumeric, Rng};
+use sqlx::{Pool, Postgres};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tracing::{error, info, info_span, warn, Instrument};
+use crate::config::AppConfig;
+use crate::ledger::Actor;
+use crate::lichess::client::{add_games_to_stream, stream_games};
+use crate::metrics::CHALLENGES;
+use crate::models::{Challenge, LichessGameEvent};
+use crate::settlement::settle_game;
+use crate::telemetry::redact;
+
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+// lichess game ids of newly accepted challenges, followed by the game events job
+pub struct GameWatcher(UnboundedSender<String>);
+
+impl GameWatcher {
+    pub fn channel() -> (GameWatcher, UnboundedReceiver<String>) {
+        let (sender, receiver) = unbounded_channel();
+        (GameWatcher(sender), receiver)
+    }
+
+    pub fn watch(&self, game_id: &str) {
+        if self.0.send(game_id.to_string()).is_err() {
+            warn!(game_id, "game events job isn't running");
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum GameUpdate {
+    Pending,
+    Started,
+    Over
+}
+
+fn game_update(status: Option<&str>) -> GameUpdate {
+    match status {
+        None | Some("created") => GameUpdate::Pending,
+        Some("started") => GameUpdate::Started,
+        Some(_) => GameUpdate::Over
+    }
+}
+
+// complete lines of the ndjson stream, a partial last line stays in the buffer
+fn take_lines(buffer: &mut Vec<u8>) -> Vec<String> {
+    let end = match buffer.iter().rposition(|b| *b == b'\n') {
+        Some(i) => i + 1,
+        None => return vec![]
+    };
+    let complete: Vec<u8> = buffer.drain(..end).collect();
+    String::from_utf8_lossy(&complete)
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+async fn live_games(pool: &Pool<Postgres>) -> Result<Vec<String>, sqlx::Error> {
+    sqlx::query_scalar("SELECT lichess_challenge_id FROM challenge WHERE status IN ('ACCEPTED', 'STARTED') AND lichess_challenge_id IS NOT NULL")
+        .fetch_all(pool).await
+}
+
+async fn mark_started(pool: &Pool<Postgres>, game_id: &str) -> Result<(), sqlx::Error> {
+    let updated = sqlx::query("UPDATE challenge SET status='STARTED' WHERE lichess_challenge_id=$1 AND status='ACCEPTED'")
+        .bind(game_id)
+        .execute(pool).await?;
+    if updated.rows_affected() > 0 {
+        CHALLENGES.with_label_values(&["started"]).inc();
+        info!(game_id, "game started");
+    }
+    Ok(())
+}
+
+// the export has the winner, aborted games are refunded by the settlement
+async fn settle_over(pool: &Pool<Postgres>, config: &AppConfig, game_id: &str) -> Result<(), sqlx::Error> {
+    let challenge = sqlx::query_as::<_,Challenge>("SELECT * FROM challenge WHERE lichess_challenge_id=$1 AND status IN ('ACCEPTED', 'STARTED')")
+        .bind(game_id)
+        .fetch_optional(pool).await?;
+    if let Some(challenge) = challenge {
+        settle_game(pool, config, &Actor::system("game_events_job"), &challenge).await;
+    }
+    Ok(())
+}
+
+async fn handle_line(pool: &Pool<Postgres>, config: &AppConfig, line: &str) {
+    let event: LichessGameEvent = match serde_json::from_str(line) {
+        Ok(e) => e,
+        Err(e) => {
+            warn!(error = %e, line = %redact(line), "unexpected game event");
+            return
+        }
+    };
+    let result = match game_update(event.status_name.as_deref()) {
+        GameUpdate::Pending => Ok(()),
+        GameUpdate::Started => mark_started(pool, &event.id).await,
+        GameUpdate::Over => settle_over(pool, config, &event.id).await
+    };
+    if let Err(e) = result {
+        error!(game_id = %event.id, error = %e, "error handling game event");
+    }
+}
+
+// reads the stream until it ends, games accepted meanwhile are added to it
+async fn follow(pool: &Pool<Postgres>, config: &AppConfig, stream_id: &str, games: Vec<String>, added: &mut UnboundedReceiver<String>) {
+    let mut response = match stream_games(&config.lichess, stream_id, &games).await {
+        Ok(r) => r,
+        Err(e) => {
+            warn!(error = %e, "error opening game stream");
+            return
+        }
+    };
+    info!(games = games.len(), "following games");
+    let mut buffer = Vec::new();
+    loop {
+        tokio::select! {
+            chunk = response.chunk() => match chunk {
+                Ok(Some(chunk)) => {
+                    buffer.extend_from_slice(&chunk);
+                    for line in take_lines(&mut buffer) {
+                        handle_line(pool, config, &line).await;
+                    }
+                },
+                Ok(None) => {
+                    info!("game stream closed");
+                    return
+                },
+                Err(e) => {
+                    warn!(error = %e, "game stream failed");
+                    return
+                }
+            },
+            Some(game_id) = added.recv() => {
+                if let Err(e) = add_games_to_stream(&config.lichess, stream_id, &[game_id]).await {
+                    // reopening the stream picks it up from the database
+                    warn!(error = %e, "error adding game to stream");
+                    return
+                }
+            }
+        }
+    }
+}
+
+// follows every accepted game on lichess, marks games as started and settles them as soon as they're over
+pub async fn game_events_job(pool: Pool<Postgres>, config: AppConfig, mut added: UnboundedReceiver<String>) {
+    // lichess keys the stream by an id we pick
+    let stream_id: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(16)
+        .map(char::from)
+        .collect();
+    loop {
+        let games = match live_games(&pool).await {
+            Ok(g) => g,
+            Err(e) => {
+                error!(error = %e, "error loading live games");
+                tokio::time::sleep(RECONNECT_DELAY).await;
+                continue
+            }
+        };
+        if games.is_empty() {
+            // nothing to follow until a challenge is accepted
+            match added.recv().await {
+                Some(_) => continue,
+                None => return
+            }
+        }
+        follow(&pool, &config, &stream_id, games, &mut added).instrument(info_span!("game_events_job")).await;
+        tokio::time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn game_updates() {
+        assert_eq!(game_update(None), GameUpdate::Pending);
+        assert_eq!(game_update(Some("created")), GameUpdate::Pending);
+        assert_eq!(game_update(Some("started")), GameUpdate::Started);
+        assert_eq!(game_update(Some("mate")), GameUpdate::Over);
+        assert_eq!(game_update(Some("aborted")), GameUpdate::Over);
+        assert_eq!(game_update(Some("noStart")), GameUpdate::Over);
+    }
+
+    #[test]
+    fn lines_across_chunks() {
+        let mut buffer = b"{\"id\":\"a\"}\n\n{\"id\":".to_vec();
+        assert_eq!(take_lines(&mut buffer), vec!["{\"id\":\"a\"}"]);
+        assert_eq!(buffer, b"{\"id\":");
+        buffer.extend_from_slice(b"\"b\"}\n");
+        assert_eq!(take_lines(&mut buffer), vec!["{\"id\":\"b\"}"]);
+        assert!(buffer.is_empty());
+        assert!(take_lines(&mut buffer).is_empty());
+    }
+}
@@ -0,0 +1,84 @@
+use sqlx::{Pool, Postgres};
+use tracing::info;
+use crate::models::{Game, LichessExportGameResponse, LichessGamePlayer};
+
+fn player_name(player: &LichessGamePlayer) -> Option<&str> {
+    player.user.as_ref().map(|u| u.name.as_str())
+}
+
+// keeps the lichess export of a challenge's game, the first export recorded for a challenge stays
+pub async fn record_game(pool: &Pool<Postgres>, challenge_id: i32, game: &LichessExportGameResponse) -> Result<(), sqlx::Error> {
+    let recorded = sqlx::query("INSERT INTO game (challenge_id, lichess_game_id, status, winner, white, black, white_rating, black_rating, speed, \
+        clock_initial, clock_increment, opening_eco, opening_name, moves, clocks, pgn, started_on, finished_on) \
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, \
+        to_timestamp($17 / 1000.0) at time zone 'utc', to_timestamp($18 / 1000.0) at time zone 'utc') \
+        ON CONFLICT (challenge_id) DO NOTHING")
+        .bind(challenge_id)
+        .bind(&game.id)
+        .bind(&game.status)
+        .bind(&game.winner)
+        .bind(player_name(&game.players.white))
+        .bind(player_name(&game.players.black))
+        .bind(game.players.white.rating)
+        .bind(game.players.black.rating)
+        .bind(&game.speed)
+        .bind(game.clock.as_ref().map(|c| c.initial))
+        .bind(game.clock.as_ref().map(|c| c.increment))
+        .bind(game.opening.as_ref().map(|o| &o.eco))
+        .bind(game.opening.as_ref().map(|o| &o.name))
+        .bind(&game.moves)
+        .bind(&game.clocks)
+        .bind(&game.pgn)
+        .bind(game.created_at)
+        .bind(game.last_move_at)
+        .execute(pool).await?;
+    if recorded.rows_affected() > 0 {
+        info!(challenge_id, game_id = %game.id, "game recorded");
+    }
+    Ok(())
+}
+
+pub async fn load_game(pool: &Pool<Postgres>, challenge_id: i32) -> Result<Option<Game>, sqlx::Error> {
+    sqlx::query_as::<_,Game>("SELECT * FROM game WHERE challenge_id=$1")
+        .bind(challenge_id)
+        .fetch_optional(pool).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finished_game_export() {
+        let game: LichessExportGameResponse = serde_json::from_str(r#"{
+            "id": "q7ZvsdUF", "rated": true, "variant": "standard", "speed": "blitz", "perf": "blitz",
+            "createdAt": 1514505150384, "lastMoveAt": 1514505592843, "status": "resign", "winner": "white",
+            "players": {
+                "white": {"user": {"name": "Lance5500", "id": "lance5500"}, "rating": 2389, "ratingDiff": 4},
+                "black": {"user": {"name": "TryingHard87", "id": "tryinghard87"}, "rating": 2498, "ratingDiff": -4}
+            },
+            "opening": {"eco": "D31", "name": "Semi-Slav Defense: Marshall Gambit", "ply": 7},
+            "moves": "d4 d5 c4 c6", "clocks": [18003, 18003, 17915, 17771],
+            "pgn": "[Event \"Rated Blitz game\"]\n\n1. d4 d5 2. c4 c6 1-0",
+            "clock": {"initial": 300, "increment": 3, "totalTime": 420}
+        }"#).unwrap();
+        assert_eq!(player_name(&game.players.white), Some("Lance5500"));
+        assert_eq!(game.players.black.rating, Some(2498));
+        assert_eq!(game.opening.unwrap().eco, "D31");
+        assert_eq!(game.clocks.len(), 4);
+        assert_eq!(game.clock.unwrap().increment, 3);
+    }
+
+    #[test]
+    fn aborted_game_export() {
+        // no moves, opening or clocks when nobody played
+        let game: LichessExportGameResponse = serde_json::from_str(r#"{
+            "id": "abcd1234", "rated": true, "variant": "standard", "speed": "blitz", "perf": "blitz",
+            "status": "aborted", "players": {"white": {"user": {"name": "user1"}}, "black": {"aiLevel": 1}}
+        }"#).unwrap();
+        assert_eq!(game.moves, "");
+        assert!(game.clocks.is_empty());
+        assert!(game.opening.is_none());
+        assert_eq!(player_name(&game.players.black), None);
+    }
+}
@@ -1,68 +1,193 @@
-
 pub mod auth {
-    use hyper::StatusCode;
+    use chrono::Utc;
     use moka::future::Cache;
-    use reqwest::Client;
-    use rocket::http::Status;
+    use reqwest::Url;
+    use rocket::http::{Cookie, CookieJar, Method, Status};
     use rocket::outcome::Outcome::{Failure};
     use rocket::{Request, State};
     use rocket::outcome::{try_outcome};
     use rocket::request::{FromRequest, Outcome};
-    use crate::models::{Account, User};
+    use sqlx::{Pool, Postgres};
+    use tracing::{debug, info, warn};
+    use crate::config::AppConfig;
+    use crate::errors::{AppError, AppResult};
+    use crate::lichess::client::fetch_account;
+    use crate::metrics::AUTH_CACHE;
+    use crate::models::{TokenResponse, User};
+    use crate::sessions::{create_session, load_session, session_cookie, session_hash, LEGACY_TOKEN_COOKIE, SESSION_COOKIE};
 
     #[rocket::async_trait]
     impl<'r> FromRequest<'r> for User {
         type Error = ();
         async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
             let cache = try_outcome!(request.guard::<&State<Cache<String, User>>>().await);
+            let app_config = try_outcome!(request.guard::<&State<AppConfig>>().await);
+            let pool = try_outcome!(request.guard::<&State<Pool<Postgres>>>().await);
+            let cookies = request.cookies();
+
+            // session cookies are sent cross site, so state changes have to come from our own pages
+            if !matches!(request.method(), Method::Get | Method::Head | Method::Options) {
+                let source = request.headers().get_one("Origin").or_else(|| request.headers().get_one("Referer"));
+                if !same_origin(source, &[&app_config.fe_url, &app_config.url]) {
+                    warn!(source = ?source, "cross site request refused");
+                    return Failure((Status::Forbidden, ()))
+                }
+            }
 
-            let access_token = request.cookies().get("llchess_access_token").map(|c| c.value());
-            match access_token {
-                Some(token) => {
-                    let maybe_user = cache.get(token);
-                    match maybe_user {
-                        Some(u) => {
-                            return Outcome::Success(u)
-                        },
-                        None => println!("Cache miss")
+            if let Some(session_id) = cookies.get(SESSION_COOKIE).map(|c| c.value().to_string()) {
+                // the cache is keyed by session hash so the session id itself is never kept around
+                let hash = session_hash(&session_id);
+                if let Some(user) = cache.get(&hash) {
+                    if user.session_expires_on > Utc::now().naive_utc() {
+                        AUTH_CACHE.with_label_values(&["hit"]).inc();
+                        return Outcome::Success(user)
                     }
-                    let bearer = format!("Bearer {token}");
-                    let response = Client::new()
-                        .get("https://lichess.org/api/account")
-                        .header("Authorization", bearer)
-                        .send().await;
-                    match response {
-                        Ok(res) => {
-                            println!("Status: {}", res.status());
-                            println!("Headers:\n{:#?}", res.headers());
-                            if res.status() == StatusCode::TOO_MANY_REQUESTS {
-                                return Failure((Status::TooManyRequests,()))
-                            };
-                            let text = res.text().await;
-                            match text {
-                                Ok(text) => {
-                                    let account: Account = serde_json::from_str(&text).unwrap();
-                                    cache.insert(token.to_string(), User { access_token: token.to_string(), username: account.username.to_string()}).await;
-                                    Outcome::Success(User { access_token: token.to_string(), username: account.username})
-                                }
-                                Err(e) => {
-                                    println!("error in text():\n{}", e);
-                                    Outcome::Forward(())
-                                }
-                            }
-                        },
-                        Err(e) => {
-                            println!("error from api/account:\n{}", e);
-                            Outcome::Forward(())
-                        }
+                    cache.invalidate(&hash).await;
+                }
+                AUTH_CACHE.with_label_values(&["miss"]).inc();
+                debug!("auth cache miss");
+                return match load_session(pool, app_config, &hash).await {
+                    Ok(Some(user)) => {
+                        cache.insert(hash, user.clone()).await;
+                        Outcome::Success(user)
+                    },
+                    Ok(None) => {
+                        debug!("unknown, expired or revoked session");
+                        cookies.remove(Cookie::named(SESSION_COOKIE));
+                        Outcome::Forward(())
+                    },
+                    Err(e) => {
+                        warn!(error = %e, "error loading session");
+                        Failure((Status::InternalServerError, ()))
                     }
                 }
-                None => {
-                    println!("no access token\n");
-                    Outcome::Forward(())
+            }
+
+            if let Some(token) = cookies.get(LEGACY_TOKEN_COOKIE).map(|c| c.value().to_string()) {
+                return match session_from_legacy_token(pool, app_config, cookies, &token).await {
+                    Ok(user) => {
+                        cache.insert(user.session_hash.clone(), user.clone()).await;
+                        Outcome::Success(user)
+                    },
+                    Err(AppError::RateLimited) => Failure((Status::TooManyRequests, ())),
+                    Err(AppError::TokenRejected(_)) => {
+                        debug!("access token cookie rejected by lichess");
+                        cookies.remove(Cookie::named(LEGACY_TOKEN_COOKIE));
+                        Outcome::Forward(())
+                    },
+                    Err(e) => {
+                        warn!(error = %e, "error migrating access token cookie");
+                        Outcome::Forward(())
+                    }
                 }
             }
+
+            debug!("no session");
+            Outcome::Forward(())
+        }
+    }
+
+    // origin or referer, requests carrying neither are refused
+    fn same_origin(source: Option<&str>, allowed: &[&str]) -> bool {
+        match source.and_then(|s| Url::parse(s).ok()) {
+            Some(source) => allowed.iter()
+                .filter_map(|url| Url::parse(url).ok())
+                .any(|url| url.origin() == source.origin()),
+            None => false
+        }
+    }
+
+    // cookies from before sessions hold the lichess token itself, swap them for a session
+    async fn session_from_legacy_token(pool: &Pool<Postgres>, app_config: &AppConfig, cookies: &CookieJar<'_>, token: &str) -> AppResult<User> {
+        let account = fetch_account(&app_config.lichess, token).await?;
+        // scopes and expiry of these tokens weren't kept
+        let token = TokenResponse { access_token: token.to_string(), scope: None, expires_in: None };
+        let session_id = create_session(pool, app_config, &account, &token).await?;
+        let user = load_session(pool, app_config, &session_hash(&session_id)).await?
+            .ok_or_else(|| AppError::Internal("new session not found".to_string()))?;
+        cookies.add(session_cookie(session_id, &app_config.session));
+        cookies.remove(Cookie::named(LEGACY_TOKEN_COOKIE));
+        info!(username = %user.username, "access token cookie migrated to a session");
+        Ok(user)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        const ALLOWED: [&str; 2] = ["https://lightningchess.io/", "https://api.lightningchess.io"];
+
+        #[test]
+        fn origins() {
+            assert!(same_origin(Some("https://lightningchess.io"), &ALLOWED));
+            assert!(same_origin(Some("https://api.lightningchess.io"), &ALLOWED));
+            assert!(!same_origin(Some("https://evil.example"), &ALLOWED));
+            assert!(!same_origin(Some("http://lightningchess.io"), &ALLOWED));
+            assert!(!same_origin(Some("https://lightningchess.io:8443"), &ALLOWED));
+            assert!(!same_origin(Some("null"), &ALLOWED));
+            assert!(!same_origin(None, &ALLOWED));
+        }
+
+        #[test]
+        fn referers() {
+            assert!(same_origin(Some("https://lightningchess.io/challenges?id=3"), &ALLOWED));
+            assert!(!same_origin(Some("https://lightningchess.io.evil.example/"), &ALLOWED));
         }
     }
+}
 
-}
\ No newline at end of file
+pub mod admin {
+    use rocket::http::Status;
+    use rocket::outcome::Outcome::Failure;
+    use rocket::{Request, State};
+    use rocket::outcome::try_outcome;
+    use rocket::request::{FromRequest, Outcome};
+    use tracing::warn;
+    use crate::config::AppConfig;
+    use crate::models::{Admin, User};
+
+    #[rocket::async_trait]
+    impl<'r> FromRequest<'r> for Admin {
+        type Error = ();
+        async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+            let app_config = try_outcome!(request.guard::<&State<AppConfig>>().await);
+
+            if let Some(key) = request.headers().get_one("X-Admin-Key") {
+                return match &app_config.admin.api_key {
+                    Some(api_key) if keys_match(key, api_key) => Outcome::Success(Admin { username: "api key".to_string() }),
+                    _ => {
+                        warn!("invalid admin api key");
+                        Failure((Status::Forbidden, ()))
+                    }
+                }
+            }
+
+            // admin routes never forward, anyone else gets a 403 instead of the login redirect
+            match request.guard::<User>().await {
+                Outcome::Success(user) if app_config.admin.is_admin(&user.username) => Outcome::Success(Admin { username: user.username }),
+                Outcome::Failure(f) => Failure(f),
+                _ => Failure((Status::Forbidden, ()))
+            }
+        }
+    }
+
+    // constant time, memcmp::eq panics on different lengths
+    fn keys_match(key: &str, api_key: &str) -> bool {
+        key.len() == api_key.len() && openssl::memcmp::eq(key.as_bytes(), api_key.as_bytes())
+    }
+}
+
+pub mod request_id {
+    use rocket::Request;
+    use rocket::request::{FromRequest, Outcome};
+    use crate::models::RequestId;
+    use crate::telemetry::RequestContext;
+
+    #[rocket::async_trait]
+    impl<'r> FromRequest<'r> for RequestId {
+        type Error = ();
+        async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+            Outcome::Success(RequestId(RequestContext::of(request).request_id.clone()))
+        }
+    }
+}
@@ -0,0 +1,98 @@
+use chrono::{Duration, NaiveDate, NaiveDateTime};
+use serde::Serialize;
+use crate::errors::{AppError, AppResult};
+
+const DEFAULT_PAGE_SIZE: i64 = 50;
+const MAX_PAGE_SIZE: i64 = 100;
+
+// one page of history, pass next_cursor back as cursor to get the next one
+#[derive(Serialize)]
+pub struct Page<T: Serialize, S: Serialize> {
+    pub items: Vec<T>,
+    pub next_cursor: Option<i32>,
+    pub totals: S
+}
+
+pub fn page_size(limit: Option<i64>) -> i64 {
+    limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE)
+}
+
+// pages are fetched with one extra row to tell whether there's another page
+pub fn paginate<T: Serialize, S: Serialize>(mut rows: Vec<T>, page_size: i64, id: impl Fn(&T) -> i32, totals: S) -> Page<T, S> {
+    let next_cursor = if rows.len() as i64 > page_size {
+        rows.truncate(page_size as usize);
+        rows.last().map(id)
+    } else {
+        None
+    };
+    Page { items: rows, next_cursor, totals }
+}
+
+pub fn parse_cursor(cursor: Option<&str>) -> AppResult<Option<i32>> {
+    cursor.map(|c| c.parse::<i32>().map_err(|_| AppError::Validation("invalid cursor".to_string()))).transpose()
+}
+
+// from and to are inclusive YYYY-MM-DD dates in UTC, returned as [from, to + 1 day)
+pub fn date_range(from: Option<&str>, to: Option<&str>) -> AppResult<(Option<NaiveDateTime>, Option<NaiveDateTime>)> {
+    let parse = |name: &str, date: &str| NaiveDate::parse_from_str(date, "%Y-%m-%d")
+        .map_err(|_| AppError::Validation(format!("{name} must be a YYYY-MM-DD date")));
+    let midnight = |d: NaiveDate| d.and_hms_opt(0, 0, 0).unwrap();
+    let from = from.map(|d| parse("from", d)).transpose()?.map(midnight);
+    let to = to.map(|d| parse("to", d)).transpose()?.map(|d| midnight(d) + Duration::days(1));
+    if matches!((from, to), (Some(f), Some(t)) if f >= t) {
+        return Err(AppError::Validation("from must not be after to".to_string()))
+    }
+    Ok((from, to))
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+pub fn to_csv(header: &[&str], rows: &[Vec<String>]) -> String {
+    let mut csv = String::new();
+    for line in std::iter::once(header.iter().map(|h| h.to_string()).collect::<Vec<_>>()).chain(rows.iter().cloned()) {
+        csv.push_str(&line.iter().map(|f| csv_field(f)).collect::<Vec<_>>().join(","));
+        csv.push_str("\r\n");
+    }
+    csv
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn page_size_is_bounded() {
+        assert_eq!(page_size(None), 50);
+        assert_eq!(page_size(Some(0)), 1);
+        assert_eq!(page_size(Some(1_000)), 100);
+    }
+
+    #[test]
+    fn next_cursor_only_when_more_rows() {
+        let page = paginate(vec![9, 8, 7], 2, |i| *i, ());
+        assert_eq!(page.items, vec![9, 8]);
+        assert_eq!(page.next_cursor, Some(8));
+        assert_eq!(paginate(vec![9, 8], 2, |i| *i, ()).next_cursor, None);
+    }
+
+    #[test]
+    fn date_range_is_inclusive() {
+        let (from, to) = date_range(Some("2023-01-01"), Some("2023-01-31")).unwrap();
+        assert_eq!(from.unwrap().to_string(), "2023-01-01 00:00:00");
+        assert_eq!(to.unwrap().to_string(), "2023-02-01 00:00:00");
+        assert!(date_range(Some("01/01/2023"), None).is_err());
+        assert!(date_range(Some("2023-02-01"), Some("2023-01-01")).is_err());
+    }
+
+    #[test]
+    fn csv_quotes_special_characters() {
+        let csv = to_csv(&["id", "detail"], &[vec!["1".to_string(), "won vs \"bob\", 2+1".to_string()]]);
+        assert_eq!(csv, "id,detail\r\n1,\"won vs \"\"bob\"\", 2+1\"\r\n");
+    }
+}
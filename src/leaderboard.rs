@@ -0,0 +1,135 @@
+use std::time::Duration;
+use chrono::{NaiveDateTime, Utc};
+use sqlx::{Pool, Postgres};
+use tracing::{error, info, info_span, Instrument};
+use crate::errors::{AppError, AppResult};
+use crate::models::LeaderboardEntry;
+
+const REFRESH_INTERVAL_SECS: u64 = 600;
+// fewer games than this says nothing about win rate
+const MIN_GAMES_FOR_WIN_RATE: i64 = 5;
+pub const MAX_ENTRIES: i64 = 100;
+
+pub const WINDOWS: [&str; 3] = ["week", "month", "all"];
+
+// start of the window, None for all time
+pub fn window_start(window: &str, now: NaiveDateTime) -> AppResult<Option<NaiveDateTime>> {
+    match window {
+        "week" => Ok(Some(now - chrono::Duration::days(7))),
+        "month" => Ok(Some(now - chrono::Duration::days(30))),
+        "all" => Ok(None),
+        _ => Err(AppError::Validation("window must be week, month or all".to_string()))
+    }
+}
+
+// order by clause and minimum games for each category
+pub fn category_order(category: &str) -> AppResult<(&'static str, i64)> {
+    match category {
+        "net_sats" => Ok(("net_sats DESC, games DESC", 1)),
+        "games" => Ok(("games DESC, net_sats DESC", 1)),
+        "win_rate" => Ok(("win_rate DESC, games DESC", MIN_GAMES_FOR_WIN_RATE)),
+        _ => Err(AppError::Validation("category must be net_sats, games or win_rate".to_string()))
+    }
+}
+
+// same per player results as the stats endpoint, summed over settled challenges created in the window.
+// players who opted out are left out
+async fn refresh_window(tx: &mut sqlx::Transaction<'_, Postgres>, window: &str, now: NaiveDateTime) -> Result<u64, sqlx::Error> {
+    let since = window_start(window, now).unwrap_or(None);
+    sqlx::query("DELETE FROM leaderboard WHERE time_window=$1")
+        .bind(window)
+        .execute(&mut *tx).await?;
+    let inserted = sqlx::query("WITH settled AS ( \
+            SELECT * FROM challenge WHERE status IN ('FINISHED', 'REVERSED') AND ($1::TIMESTAMP IS NULL OR created_on >= $1) \
+        ), sides AS ( \
+            SELECT id, lichess_challenge_id, username, COALESCE(sats, 0) AS stake FROM settled \
+            UNION ALL \
+            SELECT id, lichess_challenge_id, opp_username, COALESCE(opponent_sats, sats, 0) FROM settled \
+        ), results AS ( \
+            SELECT s.username, COALESCE(SUM(t.amount), 0) - s.stake AS net FROM sides s \
+            LEFT JOIN lightningchess_transaction t ON t.lichess_challenge_id=s.lichess_challenge_id AND LOWER(t.username)=LOWER(s.username) \
+                AND t.ttype='challenge payout' AND t.state IN ('SETTLED', 'HELD') \
+            GROUP BY s.id, s.username, s.stake \
+        ) \
+        INSERT INTO leaderboard (time_window, username, net_sats, games, wins, losses, draws, win_rate, refreshed_on) \
+        SELECT $2, MAX(r.username), SUM(r.net), COUNT(*), COUNT(*) FILTER (WHERE r.net > 0), COUNT(*) FILTER (WHERE r.net < 0), \
+            COUNT(*) FILTER (WHERE r.net = 0), (COUNT(*) FILTER (WHERE r.net > 0))::DOUBLE PRECISION / COUNT(*), $3 \
+        FROM results r WHERE NOT EXISTS ( \
+            SELECT 1 FROM lightningchess_balance b WHERE LOWER(b.username)=LOWER(r.username) AND b.leaderboard_opt_out \
+        ) GROUP BY LOWER(r.username)")
+        .bind(since)
+        .bind(window)
+        .bind(now)
+        .execute(&mut *tx).await?;
+    Ok(inserted.rows_affected())
+}
+
+pub async fn refresh(pool: &Pool<Postgres>) -> Result<(), sqlx::Error> {
+    let now = Utc::now().naive_utc();
+    // readers see the old or the new leaderboard, never a half built one
+    let mut tx = pool.begin().await?;
+    for window in WINDOWS {
+        let players = refresh_window(&mut tx, window, now).await?;
+        info!(window, players, "leaderboard refreshed");
+    }
+    tx.commit().await
+}
+
+pub async fn top(pool: &Pool<Postgres>, window: &str, category: &str, limit: i64) -> AppResult<Vec<LeaderboardEntry>> {
+    window_start(window, Utc::now().naive_utc())?;
+    let (order, min_games) = category_order(category)?;
+    let entries = sqlx::query_as::<_,LeaderboardEntry>(&format!("SELECT * FROM leaderboard WHERE time_window=$1 AND games >= $2 ORDER BY {order}, username LIMIT $3"))
+        .bind(window)
+        .bind(min_games)
+        .bind(limit.clamp(1, MAX_ENTRIES))
+        .fetch_all(pool).await?;
+    Ok(entries)
+}
+
+pub async fn set_opt_out(pool: &Pool<Postgres>, username: &str, opt_out: bool) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    sqlx::query("INSERT INTO lightningchess_balance (username, balance, leaderboard_opt_out) VALUES ($1, 0, $2) ON CONFLICT (username) DO UPDATE SET leaderboard_opt_out=$2")
+        .bind(username)
+        .bind(opt_out)
+        .execute(&mut tx).await?;
+    // opting out takes effect right away, opting back in on the next refresh
+    if opt_out {
+        sqlx::query("DELETE FROM leaderboard WHERE LOWER(username)=LOWER($1)")
+            .bind(username)
+            .execute(&mut tx).await?;
+    }
+    tx.commit().await
+}
+
+pub async fn leaderboard_job(pool: Pool<Postgres>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(REFRESH_INTERVAL_SECS));
+    loop {
+        interval.tick().await;
+        async {
+            if let Err(e) = refresh(&pool).await {
+                error!(error = %e, "error refreshing leaderboard");
+            }
+        }.instrument(info_span!("leaderboard_job")).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn windows() {
+        let now = NaiveDateTime::parse_from_str("2023-03-31 12:00:00", "%Y-%m-%d %H:%M:%S").unwrap();
+        assert_eq!(window_start("week", now).unwrap().unwrap().to_string(), "2023-03-24 12:00:00");
+        assert_eq!(window_start("month", now).unwrap().unwrap().to_string(), "2023-03-01 12:00:00");
+        assert_eq!(window_start("all", now).unwrap(), None);
+        assert!(window_start("year", now).is_err());
+    }
+
+    #[test]
+    fn win_rate_needs_enough_games() {
+        assert_eq!(category_order("win_rate").unwrap().1, MIN_GAMES_FOR_WIN_RATE);
+        assert_eq!(category_order("net_sats").unwrap().1, 1);
+        assert!(category_order("elo").is_err());
+    }
+}
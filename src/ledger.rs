@@ -0,0 +1,92 @@
+use sqlx::Postgres;
+use crate::models::{Admin, Balance, RequestId};
+
+// who triggered a balance change, recorded on every audit log entry
+#[derive(Clone)]
+pub struct Actor {
+    pub actor_type: &'static str,
+    pub name: String,
+    pub request_id: Option<String>
+}
+
+impl Actor {
+    pub fn user(username: &str, request_id: &RequestId) -> Actor {
+        Actor { actor_type: "user", name: username.to_string(), request_id: Some(request_id.0.clone()) }
+    }
+
+    pub fn admin(admin: &Admin, request_id: &RequestId) -> Actor {
+        Actor { actor_type: "admin", name: admin.username.clone(), request_id: Some(request_id.0.clone()) }
+    }
+
+    // admin commands run from the cli
+    pub fn cli() -> Actor {
+        Actor { actor_type: "admin", name: "cli".to_string(), request_id: None }
+    }
+
+    pub fn system(job: &str) -> Actor {
+        Actor { actor_type: "system", name: job.to_string(), request_id: None }
+    }
+}
+
+pub struct BalanceChange<'a> {
+    pub username: &'a str,
+    pub amount: i64,
+    pub action: &'a str,
+    pub challenge_id: Option<i32>,
+    pub transaction_id: Option<i32>,
+    pub detail: Option<&'a str>
+}
+
+impl<'a> BalanceChange<'a> {
+    pub fn new(username: &'a str, amount: i64, action: &'a str) -> Self {
+        BalanceChange { username, amount, action, challenge_id: None, transaction_id: None, detail: None }
+    }
+
+    pub fn challenge(self, challenge_id: i32) -> Self {
+        BalanceChange { challenge_id: Some(challenge_id), ..self }
+    }
+
+    pub fn transaction(self, transaction_id: i32) -> Self {
+        BalanceChange { transaction_id: Some(transaction_id), ..self }
+    }
+
+    pub fn detail(self, detail: &'a str) -> Self {
+        BalanceChange { detail: Some(detail), ..self }
+    }
+}
+
+// the only way balances change, the audit log entry is written in the caller's db transaction
+// so it commits or rolls back together with the change. returns the balance after the change,
+// callers debiting a user check it didn't go negative
+pub async fn change_balance(tx: &mut sqlx::Transaction<'_, Postgres>, actor: &Actor, change: BalanceChange<'_>) -> Result<Balance, sqlx::Error> {
+    let before = sqlx::query_as::<_,Balance>("SELECT * FROM lightningchess_balance WHERE username=$1 FOR UPDATE")
+        .bind(change.username)
+        .fetch_optional(&mut *tx).await?;
+
+    let after = match &before {
+        Some(_) => sqlx::query_as::<_,Balance>("UPDATE lightningchess_balance SET balance=balance + $1 WHERE username=$2 RETURNING *")
+            .bind(change.amount)
+            .bind(change.username)
+            .fetch_one(&mut *tx).await?,
+        None => sqlx::query_as::<_,Balance>("INSERT INTO lightningchess_balance (username, balance) VALUES ($1, $2) RETURNING *")
+            .bind(change.username)
+            .bind(change.amount)
+            .fetch_one(&mut *tx).await?
+    };
+
+    sqlx::query("INSERT INTO audit_log (actor_type, actor, action, username, amount, balance_before, balance_after, challenge_id, transaction_id, request_id, detail) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)")
+        .bind(actor.actor_type)
+        .bind(&actor.name)
+        .bind(change.action)
+        .bind(change.username)
+        .bind(change.amount)
+        .bind(before.map(|b| b.balance).unwrap_or(0))
+        .bind(after.balance)
+        .bind(change.challenge_id)
+        .bind(change.transaction_id)
+        .bind(&actor.request_id)
+        .bind(change.detail)
+        .execute(&mut *tx).await?;
+
+    Ok(after)
+}
@@ -1,6 +1,35 @@
-use reqwest::Client;
-use rocket::http::Status;
-use crate::models::{Challenge, LichessAcceptChallengeResponse, LichessAddTimeResponse, LichessChallenge, LichessChallengeClock, LichessChallengeResponse, LichessUser, User};
+use std::time::{Duration, Instant};
+use once_cell::sync::Lazy;
+use reqwest::{Client, RequestBuilder, Response, StatusCode};
+use serde::de::DeserializeOwned;
+use serde_json::json;
+use tracing::{field, info, instrument, Span};
+use crate::config::LichessConfig;
+use crate::errors::{AppError, AppResult};
+use crate::metrics::observe_upstream;
+use crate::telemetry::redact;
+use crate::upstream::{Policy, Retry, Upstream};
+use crate::models::{Account, Challenge, LichessAcceptChallengeResponse, LichessAddTimeResponse, LichessCancelChallengeResponse, LichessChallenge, LichessChallengeClock, LichessChallengeResponse, LichessExportGameResponse, LichessUser, TokenResponse, User};
+
+// lichess asks clients to wait a full minute after a 429
+static LICHESS: Lazy<Upstream> = Lazy::new(|| Upstream::new("lichess", Policy {
+    attempts: 3,
+    rate_limit_cooldown: Some(Duration::from_secs(60)),
+    breaker: None
+}, AppError::Lichess));
+
+// streams stay open for as long as games are played, only connecting is bounded
+static STREAMING: Lazy<Client> = Lazy::new(|| Client::builder()
+    .connect_timeout(Duration::from_secs(10))
+    .build()
+    .unwrap());
+
+fn http(lichess: &LichessConfig) -> AppResult<Client> {
+    LICHESS.client(lichess.timeout_secs.to_string(), || Client::builder()
+        .timeout(Duration::from_secs(lichess.timeout_secs))
+        .build()
+        .map_err(|e| e.to_string()))
+}
 
 fn parse_to_lichess_challenge(challenge: &Challenge) -> LichessChallenge {
     let color = match challenge.color.as_deref() {
@@ -9,168 +38,202 @@ fn parse_to_lichess_challenge(challenge: &Challenge) -> LichessChallenge {
         _ => "".to_string()
     };
 
-    let time_limit = challenge.time_limit.unwrap();
-    let opponent_time_limit = challenge.opponent_time_limit.unwrap();
+    let time_limit = challenge.time_limit.unwrap_or_default();
+    let opponent_time_limit = challenge.opponent_time_limit.unwrap_or_default();
     let limit = if time_limit < opponent_time_limit { time_limit} else {opponent_time_limit};
-    return LichessChallenge {
+    LichessChallenge {
         rated: true,
         clock: LichessChallengeClock {
             limit: limit.to_string(),
-            increment: challenge.increment.unwrap().to_string(),
+            increment: challenge.increment.unwrap_or_default().to_string(),
         },
         color,
         variant: "standard".to_string(),
         rules: "noClaimWin".to_string(),
-    };
+    }
+}
+
+// sends a lichess request and maps the response to a typed body, never panics on unexpected payloads
+async fn lichess_request<T: DeserializeOwned>(request: RequestBuilder, call: &str) -> AppResult<T> {
+    lichess_call(request, call, Retry::Never).await
+}
+
+// for reads and other calls that can be repeated without side effects
+async fn lichess_request_with_retry<T: DeserializeOwned>(request: RequestBuilder, call: &str) -> AppResult<T> {
+    lichess_call(request, call, Retry::Idempotent).await
+}
+
+#[instrument(name = "lichess", skip(request, retry), fields(status = field::Empty, latency_ms = field::Empty))]
+async fn lichess_call<T: DeserializeOwned>(request: RequestBuilder, call: &str, retry: Retry) -> AppResult<T> {
+    let start = Instant::now();
+    let response = LICHESS.send(request, call, retry).await;
+    Span::current().record("latency_ms", start.elapsed().as_millis() as u64);
+    let result = lichess_response(response, call).await;
+    observe_upstream("lichess", call, start.elapsed(), &result);
+    result
+}
+
+async fn lichess_response<T: DeserializeOwned>(response: AppResult<Response>, call: &str) -> AppResult<T> {
+    let res = response?;
+    Span::current().record("status", res.status().as_u16());
+    info!("lichess call finished");
+    match res.status() {
+        StatusCode::NOT_FOUND => return Err(AppError::NotFound(call.to_string())),
+        // revoked, expired or missing a scope
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => return Err(AppError::TokenRejected(call.to_string())),
+        StatusCode::TOO_MANY_REQUESTS => return Err(AppError::RateLimited),
+        _ => ()
+    }
+    let status = res.status();
+    if status == StatusCode::NO_CONTENT {
+        return serde_json::from_str("null").map_err(|e| AppError::Lichess(format!("{call}: expected a response body {e}")))
+    }
+    let text = res.text().await.map_err(|e| AppError::Lichess(format!("{call}: {e}")))?;
+    if !status.is_success() {
+        return Err(AppError::Lichess(format!("{call}: {status} {}", redact(&text))))
+    }
+    serde_json::from_str(&text).map_err(|e| AppError::Lichess(format!("{call}: unexpected response {e}: {}", redact(&text))))
 }
 
 // programmatically accept challenge for person who created challenge
-pub async fn accept_lichess_challenge(challenge: &Challenge, lichess_challenge_response: &LichessChallengeResponse) -> Result<bool, Status> {
-    let url = format!("https://lichess.org/api/challenge/{}/accept", lichess_challenge_response.challenge.id);
-    let bearer = format!("Bearer {}", &challenge.challenger_token.as_ref().unwrap());
-    let resp = Client::new()
+pub async fn accept_lichess_challenge(lichess: &LichessConfig, challenger_token: &str, lichess_challenge_response: &LichessChallengeResponse) -> AppResult<bool> {
+    let url = format!("{}/api/challenge/{}/accept", lichess.url, lichess_challenge_response.challenge.id);
+    let bearer = format!("Bearer {challenger_token}");
+    let request = http(lichess)?
         .post(url)
-        .header("Authorization", bearer)
-        .send().await;
-    return match resp {
-        Ok(res) => {
-            println!("Status: {}", res.status());
-            println!("Headers:\n{:#?}", res.headers());
-
-            let text = res.text().await;
-            match text {
-                Ok(text) => {
-                    println!("text!: {}", text);
-                    let lichess_accept_challenge_response: LichessAcceptChallengeResponse = serde_json::from_str(&text).unwrap();
-                    if lichess_accept_challenge_response.ok {
-                        Ok(true)
-                    } else {
-                        Err(Status::InternalServerError)
-                    }
-                }
-                Err(e) => {
-                    println!("error: {}", e);
-                    Err(Status::InternalServerError)
-                }
-            }
-        },
-        Err(e) => {
-            println!("error challenge accept on lichess: {}", e);
-            Err(Status::InternalServerError)
-        }
+        .header("Authorization", bearer);
+    let lichess_accept_challenge_response: LichessAcceptChallengeResponse = lichess_request(request, "accept challenge").await?;
+    if lichess_accept_challenge_response.ok {
+        Ok(true)
+    } else {
+        Err(AppError::Lichess("accept challenge not ok".to_string()))
     }
 }
 
-pub async fn add_time(user: &User, challenge: &Challenge, lichess_challenge_response: &LichessChallengeResponse) -> Result<bool, Status> {
-    let time_limit = challenge.time_limit.unwrap();
-    let opponent_time_limit = challenge.opponent_time_limit.unwrap();
-    if time_limit == opponent_time_limit {
-        return Ok(true)
-    }
-    let time_to_add = (time_limit - opponent_time_limit).abs();
-    let token = if time_limit < opponent_time_limit {
-        &challenge.challenger_token.as_ref().unwrap()
+// gives the opponent of the token's owner more time on their clock
+pub async fn add_time(lichess: &LichessConfig, token: &str, game_id: &str, seconds: i32) -> AppResult<bool> {
+    let url = format!("{}/api/round/{game_id}/add-time/{seconds}", lichess.url);
+    let bearer = format!("Bearer {token}");
+    let request = http(lichess)?
+        .post(url)
+        .header("Authorization", bearer);
+    let lichess_add_time_response: LichessAddTimeResponse = lichess_request(request, "add time").await?;
+    if lichess_add_time_response.ok {
+        Ok(true)
     } else {
-        &user.access_token
-    };
+        Err(AppError::Lichess("add time not ok".to_string()))
+    }
+}
 
-    let url = format!("https://lichess.org/api/round/{}/add-time/{}", lichess_challenge_response.challenge.id, time_to_add.to_string());
+// cancels a challenge the token's owner created, with the opponent's token a game that was already accepted is aborted too
+pub async fn cancel_lichess_challenge(lichess: &LichessConfig, token: &str, game_id: &str, opponent_token: Option<&str>) -> AppResult<()> {
+    let url = format!("{}/api/challenge/{game_id}/cancel", lichess.url);
     let bearer = format!("Bearer {token}");
-    let resp = Client::new()
+    let mut request = http(lichess)?
         .post(url)
-        .header("Authorization", bearer)
-        .send().await;
-
-    return match resp {
-        Ok(res) => {
-            println!("Status: {}", res.status());
-            println!("Headers:\n{:#?}", res.headers());
-
-            let text = res.text().await;
-            match text {
-                Ok(text) => {
-                    println!("text!: {}", text);
-                    let lichess_add_time_response: LichessAddTimeResponse = serde_json::from_str(&text).unwrap();
-                    if lichess_add_time_response.ok {
-                        Ok(true)
-                    } else {
-                        Err(Status::InternalServerError)
-                    }
-                }
-                Err(e) => {
-                    println!("error: {}", e);
-                    Err(Status::InternalServerError)
-                }
-            }
-        },
-        Err(e) => {
-            println!("error adding time on lichess: {}", e);
-            Err(Status::InternalServerError)
-        }
+        .header("Authorization", bearer);
+    if let Some(opponent_token) = opponent_token {
+        request = request.query(&[("opponentToken", opponent_token)]);
+    }
+    // lichess may have acted on a cancel that timed out or failed, only one that never got there is sent again
+    let lichess_cancel_challenge_response: LichessCancelChallengeResponse = lichess_call(request, "cancel challenge", Retry::Connect).await?;
+    if lichess_cancel_challenge_response.ok {
+        Ok(())
+    } else {
+        Err(AppError::Lichess("cancel challenge not ok".to_string()))
     }
 }
 
-
-pub async fn create_lichess_challenge(user: &User, challenge: &Challenge) -> Result<LichessChallengeResponse, Status> {
-    let url = format!("https://lichess.org/api/challenge/{}", &challenge.username);
+pub async fn create_lichess_challenge(lichess: &LichessConfig, user: &User, challenge: &Challenge) -> AppResult<LichessChallengeResponse> {
+    let url = format!("{}/api/challenge/{}", lichess.url, &challenge.username);
     let access_token = &user.access_token;
     let bearer = format!("Bearer {access_token}");
-    let body = parse_to_lichess_challenge(&challenge);
-    let resp = Client::new()
+    let body = parse_to_lichess_challenge(challenge);
+    let request = http(lichess)?
         .post(url)
         .json(&body)
-        .header("Authorization", bearer)
-        .send().await;
-
-    let lichess_challenge_response: LichessChallengeResponse = match resp {
-        Ok(res) => {
-            println!("Status: {}", res.status());
-            println!("Headers:\n{:#?}", res.headers());
-
-            let text = res.text().await;
-            match text {
-                Ok(text) => {
-                    println!("text!: {}", text);
-                    serde_json::from_str(&text).unwrap()
-                }
-                Err(e) => {
-                    println!("error: {}", e);
-                    return Err(Status::InternalServerError)
-                }
-            }
-        },
-        Err(e) => {
-            println!("error creating on lichess: {}", e);
-            return Err(Status::InternalServerError)
-        }
-    };
+        .header("Authorization", bearer);
+    lichess_request(request, "create challenge").await
+}
+
+// trades the code from the oauth callback for an access token
+pub async fn exchange_code(lichess: &LichessConfig, redirect_uri: &str, code: &str, code_verifier: &str) -> AppResult<TokenResponse> {
+    let body = json!({
+        "grant_type": "authorization_code",
+        "redirect_uri": redirect_uri,
+        "client_id": lichess.client_id,
+        "code": code,
+        "code_verifier": code_verifier
+    });
+    let request = http(lichess)?
+        .post(format!("{}/api/token", lichess.url))
+        .json(&body);
+    lichess_request(request, "token").await
+}
+
+// the account a token belongs to
+pub async fn fetch_account(lichess: &LichessConfig, access_token: &str) -> AppResult<Account> {
+    let request = http(lichess)?
+        .get(format!("{}/api/account", lichess.url))
+        .header("Authorization", format!("Bearer {access_token}"));
+    lichess_request_with_retry(request, "account").await
+}
 
-    Ok(lichess_challenge_response)
+// invalidates the token at lichess, it can't be used by anyone afterwards
+pub async fn revoke_token(lichess: &LichessConfig, access_token: &str) -> AppResult<()> {
+    let request = http(lichess)?
+        .delete(format!("{}/api/token", lichess.url))
+        .header("Authorization", format!("Bearer {access_token}"));
+    lichess_request_with_retry(request, "revoke token").await
 }
 
-pub async fn lichess_user(username: &str) -> Result<String, Status> {
-    let url = format!("https://lichess.org/api/user/{username}");
-    println!("url: {url}");
-    let response = Client::new()
+pub async fn fetch_lichess_user(lichess: &LichessConfig, username: &str) -> AppResult<LichessUser> {
+    let url = format!("{}/api/user/{username}", lichess.url);
+    let request = http(lichess)?
+        .get(url);
+    lichess_request_with_retry(request, "lichess user").await
+}
+
+pub async fn lichess_user(lichess: &LichessConfig, username: &str) -> AppResult<String> {
+    let lichess_user = fetch_lichess_user(lichess, username).await?;
+    Ok(serde_json::to_string(&lichess_user)?)
+}
+
+pub async fn export_game(lichess: &LichessConfig, game_id: &str) -> AppResult<LichessExportGameResponse> {
+    let url = format!("{}/game/export/{game_id}", lichess.url);
+    // everything the game record keeps, moves are included by default
+    let request = http(lichess)?
         .get(url)
-        .send().await;
-    match response {
-        Ok(res) => {
-            if res.status() == 404 {
-                return Err(Status::NotFound)
-            }
-            return match res.text().await {
-                Ok(t) => {
-                    println!("text!: {}", t);
-                    let lichess_user: LichessUser = serde_json::from_str(&t).unwrap();
-                    Ok(serde_json::to_string(&lichess_user).unwrap())
-                }
-                Err(e) => {
-                    println!("error: {}", e);
-                    Err(Status::InternalServerError)
-                }
-            }
-        },
-        Err(_) => Err(Status::InternalServerError)
+        .query(&[("pgnInJson", "true"), ("clocks", "true"), ("opening", "true")])
+        .header("Accept", "application/json");
+    lichess_request_with_retry(request, "game").await
+}
+
+// ndjson stream of the games, it sends every game once and then again whenever one starts or finishes
+pub async fn stream_games(lichess: &LichessConfig, stream_id: &str, game_ids: &[String]) -> AppResult<Response> {
+    let request = STREAMING
+        .post(format!("{}/api/stream/games/{stream_id}", lichess.url))
+        .body(game_ids.join(","));
+    let response = LICHESS.send(request, "stream games", Retry::Never).await?;
+    match response.status() {
+        StatusCode::TOO_MANY_REQUESTS => Err(AppError::RateLimited),
+        status if !status.is_success() => Err(AppError::Lichess(format!("stream games: {status}"))),
+        _ => Ok(response)
     }
-}
\ No newline at end of file
+}
+
+pub async fn add_games_to_stream(lichess: &LichessConfig, stream_id: &str, game_ids: &[String]) -> AppResult<()> {
+    let request = http(lichess)?
+        .post(format!("{}/api/stream/games/{stream_id}/add", lichess.url))
+        .body(game_ids.join(","));
+    let _: serde_json::Value = lichess_request(request, "add games to stream").await?;
+    Ok(())
+}
+
+// cheap call used by readiness probes to check lichess is reachable
+pub async fn lichess_status(lichess: &LichessConfig) -> AppResult<()> {
+    let request = http(lichess)?
+        .get(format!("{}/api/users/status?ids=lichess", lichess.url));
+    let _: serde_json::Value = lichess_request(request, "status").await?;
+    Ok(())
+}
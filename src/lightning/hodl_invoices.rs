@@ -1,13 +1,19 @@
+use crate::errors::{AppError, AppResult};
+use crate::config::LndConfig;
+use crate::lightning::{lnd_client, lnd_request, lnd_request_with_retry, lnd_send, macaroon};
+use crate::metrics::observe_upstream;
 use crate::models::{AddInvoiceResponse, LookupInvoiceResponse, Challenge};
-use reqwest::{Client, StatusCode};
+use crate::upstream::Retry;
+use std::time::Instant;
+use reqwest::StatusCode;
 use serde_json::json;
-use std::{env, str};
 use sha2::{Digest, Sha256};
+use tracing::{field, info, instrument, Span};
 
-pub async fn add_hodl_invoice(challenge: &Challenge, preimage_bytes: Vec<u8>) -> Option<AddInvoiceResponse> {
-    let macaroon = env::var("LND_MACAROON").unwrap();
-    let sats_str = challenge.sats.unwrap().to_string();
-    let memo = format!("lightningchess.io chess game");
+pub async fn add_hodl_invoice(lnd: &LndConfig, challenge: &Challenge, preimage_bytes: Vec<u8>) -> AppResult<AddInvoiceResponse> {
+    let macaroon = macaroon(lnd)?;
+    let sats_str = challenge.sats.unwrap_or_default().to_string();
+    let memo = "lightningchess.io chess game";
     let preimage_hash_bytes = Sha256::digest(preimage_bytes);
     let preimage_hash_base64 = base64::encode(preimage_hash_bytes);
     let body = json!({
@@ -16,99 +22,43 @@ pub async fn add_hodl_invoice(challenge: &Challenge, preimage_bytes: Vec<u8>) ->
         "memo": memo,
         "expiry": "1800"
     });
-    println!("body: {}", body.to_string());
 
-    let response = Client::new()
-        .post("https://lightningchess.m.voltageapp.io:8080/v2/invoices/hodl")
+    let request = lnd_client(lnd)?
+        .post(format!("{}/v2/invoices/hodl", lnd.url))
         .json(&body)
-        .header("Grpc-Metadata-macaroon", macaroon)
-        .send().await;
-
-    match response {
-        Ok(res) => {
-            println!("Status: {}", res.status());
-            println!("Headers:\n{:#?}", res.headers());
-            let text = res.text().await;
-            match text {
-                Ok(text) => {
-                    println!("text: {}", text);
-                    let json_response: AddInvoiceResponse = serde_json::from_str(&text).unwrap();
-                    println!("addHoldInvoiceResponse: {}", serde_json::to_string(&json_response).unwrap());
-                    Some(json_response)
-                }
-                Err(e) => {
-                    println!("error in text() :\n{}", e);
-                    None
-                }
-            }
-        },
-        Err(e) => {
-            println!("error from lnd addHoldInvoice\n{}", e);
-            None
-        }
-    }
+        .header("Grpc-Metadata-macaroon", macaroon);
+    lnd_request(request, "add hodl invoice").await
 }
 
-pub async fn lookup_hodl_invoice(payment_addr: &str) -> Option<LookupInvoiceResponse> {
-    let macaroon = env::var("LND_MACAROON").unwrap();
-    println!("payment_addr: {}", payment_addr);
-    let base64_decoded_bytes = base64::decode(payment_addr).unwrap();
+pub async fn lookup_hodl_invoice(lnd: &LndConfig, payment_addr: &str) -> AppResult<LookupInvoiceResponse> {
+    let macaroon = macaroon(lnd)?;
+    let base64_decoded_bytes = base64::decode(payment_addr).map_err(|e| AppError::Validation(format!("invalid payment_addr: {e}")))?;
     let base64_url_safe_encoded = base64::encode_config(base64_decoded_bytes, base64::URL_SAFE);
-    println!("base64_url_safe_encoded: {}", base64_url_safe_encoded);
-    let response = Client::new()
-        .get(format!("https://lightningchess.m.voltageapp.io:8080/v2/invoices/lookup?payment_addr={}", base64_url_safe_encoded))
-        .header("Grpc-Metadata-macaroon", macaroon)
-        .send().await;
-
-    return match response {
-        Ok(res) => {
-            println!("Status: {}", res.status());
-            println!("Headers:\n{:#?}", res.headers());
-            let text = res.text().await;
-            match text {
-                Ok(text) => {
-                    println!("text: {}", text);
-                    let json_response: LookupInvoiceResponse = serde_json::from_str(&text).unwrap();
-                    println!("lookupInvoiceResponse: {}", serde_json::to_string(&json_response).unwrap());
-                    Some(json_response)
-                }
-                Err(e) => {
-                    println!("error in text() :\n{}", e);
-                    None
-                }
-            }
-        },
-        Err(e) => {
-            println!("error from lnd lookup_invoice\n{}", e);
-            None
-        }
-    };
+    let request = lnd_client(lnd)?
+        .get(format!("{}/v2/invoices/lookup?payment_addr={}", lnd.url, base64_url_safe_encoded))
+        .header("Grpc-Metadata-macaroon", macaroon);
+    lnd_request_with_retry(request, "lookup invoice").await
 }
 
-pub async fn settle_hodl_invoice(preimage: &str) -> bool {
-    let macaroon = env::var("LND_MACAROON").unwrap();
+#[instrument(name = "lnd", skip(lnd, preimage), fields(call = "settle invoice", status = field::Empty, latency_ms = field::Empty))]
+pub async fn settle_hodl_invoice(lnd: &LndConfig, preimage: &str) -> AppResult<bool> {
+    let macaroon = macaroon(lnd)?;
     let body = json!({
         "preimage": preimage
     });
-    println!("preimage body: {}", body.to_string());
-    let response = Client::new()
-        .post("https://lightningchess.m.voltageapp.io:8080/v2/invoices/settle")
+    let start = Instant::now();
+    let request = lnd_client(lnd)?
+        .post(format!("{}/v2/invoices/settle", lnd.url))
         .json(&body)
-        .header("Grpc-Metadata-macaroon", macaroon)
-        .send().await;
-
-    return match response {
-        Ok(res) => {
+        .header("Grpc-Metadata-macaroon", macaroon);
 
-            println!("Status: {}", res.status());
-            println!("Headers:\n{:#?}", res.headers());
-            StatusCode::from_u16(200).unwrap();
-            res.status() == StatusCode::OK
-        },
-        Err(e) => {
-            println!("error from lnd settle_invoice\n{}", e);
-            false
-        }
-    }
+    let result = lnd_send(request, "settle invoice", Retry::Never).await.map(|res| {
+        let span = Span::current();
+        span.record("latency_ms", start.elapsed().as_millis() as u64);
+        span.record("status", res.status().as_u16());
+        info!("lnd call finished");
+        res.status() == StatusCode::OK
+    });
+    observe_upstream("lnd", "settle invoice", start.elapsed(), &result);
+    result
 }
-
@@ -1,10 +1,11 @@
-use std::env;
-use reqwest::Client;
 use serde_json::json;
-use crate::models::{AddInvoiceResponse};
+use crate::errors::AppResult;
+use crate::config::LndConfig;
+use crate::lightning::{lnd_client, lnd_request, lnd_request_with_retry, macaroon};
+use crate::models::{AddInvoiceResponse, ListInvoicesResponse};
 
-pub async fn add_invoice(sats: i64, memo: &str, preimage_bytes: Vec<u8>) -> Option<AddInvoiceResponse> {
-    let macaroon = env::var("LND_MACAROON").unwrap();
+pub async fn add_invoice(lnd: &LndConfig, sats: i64, memo: &str, preimage_bytes: Vec<u8>) -> AppResult<AddInvoiceResponse> {
+    let macaroon = macaroon(lnd)?;
     let sats_str = sats.to_string();
     let preimage_hash_base64 = base64::encode(preimage_bytes);
     let body = json!({
@@ -13,35 +14,19 @@ pub async fn add_invoice(sats: i64, memo: &str, preimage_bytes: Vec<u8>) -> Opti
         "memo": memo,
         "expiry": "1800"
     });
-    println!("body: {}", body.to_string());
 
-    let response = Client::new()
-        .post("https://lightningchess.m.voltageapp.io:8080/v1/invoices")
+    let request = lnd_client(lnd)?
+        .post(format!("{}/v1/invoices", lnd.url))
         .json(&body)
-        .header("Grpc-Metadata-macaroon", macaroon)
-        .send().await;
+        .header("Grpc-Metadata-macaroon", macaroon);
+    lnd_request(request, "add invoice").await
+}
 
-    match response {
-        Ok(res) => {
-            println!("Status: {}", res.status());
-            println!("Headers:\n{:#?}", res.headers());
-            let text = res.text().await;
-            match text {
-                Ok(text) => {
-                    println!("text: {}", text);
-                    let json_response: AddInvoiceResponse = serde_json::from_str(&text).unwrap();
-                    println!("add_invoice: {}", serde_json::to_string(&json_response).unwrap());
-                    Some(json_response)
-                }
-                Err(e) => {
-                    println!("error in text() :\n{}", e);
-                    None
-                }
-            }
-        },
-        Err(e) => {
-            println!("error from lnd addHoldInvoice\n{}", e);
-            None
-        }
-    }
-}
\ No newline at end of file
+// one page of invoices after index_offset, oldest first
+pub async fn list_invoices(lnd: &LndConfig, index_offset: u64, page_size: u64) -> AppResult<ListInvoicesResponse> {
+    let macaroon = macaroon(lnd)?;
+    let request = lnd_client(lnd)?
+        .get(format!("{}/v1/invoices?index_offset={index_offset}&num_max_invoices={page_size}", lnd.url))
+        .header("Grpc-Metadata-macaroon", macaroon);
+    lnd_request_with_retry(request, "list invoices").await
+}
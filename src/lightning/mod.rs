@@ -1,3 +1,76 @@
+use std::time::{Duration, Instant};
+use once_cell::sync::Lazy;
+use reqwest::{Certificate, Client, RequestBuilder, Response};
+use serde::de::DeserializeOwned;
+use tracing::{field, info, instrument, Span};
+use crate::config::LndConfig;
+use crate::errors::{AppError, AppResult};
+use crate::metrics::observe_upstream;
+use crate::telemetry::redact;
+use crate::upstream::{Policy, Retry, Upstream};
+
 pub mod hodl_invoices;
 pub mod invoices;
-pub mod payment;
\ No newline at end of file
+pub mod node;
+pub mod payment;
+
+// a node that stopped answering is left alone for a while instead of piling up timed out calls
+static LND: Lazy<Upstream> = Lazy::new(|| Upstream::new("lnd", Policy {
+    attempts: 3,
+    rate_limit_cooldown: None,
+    breaker: Some((5, Duration::from_secs(30)))
+}, AppError::Lnd));
+
+// sends an lnd rest request and maps the response to a typed body, never panics on unexpected payloads
+pub(crate) async fn lnd_request<T: DeserializeOwned>(request: RequestBuilder, call: &str) -> AppResult<T> {
+    lnd_call(request, call, Retry::Never).await
+}
+
+// for lookups that can be repeated without side effects
+pub(crate) async fn lnd_request_with_retry<T: DeserializeOwned>(request: RequestBuilder, call: &str) -> AppResult<T> {
+    lnd_call(request, call, Retry::Idempotent).await
+}
+
+#[instrument(name = "lnd", skip(request, retry), fields(status = field::Empty, latency_ms = field::Empty))]
+async fn lnd_call<T: DeserializeOwned>(request: RequestBuilder, call: &str, retry: Retry) -> AppResult<T> {
+    let start = Instant::now();
+    let response = lnd_send(request, call, retry).await;
+    Span::current().record("latency_ms", start.elapsed().as_millis() as u64);
+    let result = lnd_response(response, call).await;
+    observe_upstream("lnd", call, start.elapsed(), &result);
+    result
+}
+
+// for streamed responses that are read by the caller
+pub(crate) async fn lnd_send(request: RequestBuilder, call: &str, retry: Retry) -> AppResult<Response> {
+    LND.send(request, call, retry).await
+}
+
+async fn lnd_response<T: DeserializeOwned>(response: AppResult<Response>, call: &str) -> AppResult<T> {
+    let res = response?;
+    Span::current().record("status", res.status().as_u16());
+    info!("lnd call finished");
+    let status = res.status();
+    let text = res.text().await.map_err(|e| AppError::Lnd(format!("{call}: {e}")))?;
+    if !status.is_success() {
+        return Err(AppError::Lnd(format!("{call}: {status} {}", redact(&text))))
+    }
+    serde_json::from_str(&text).map_err(|e| AppError::Lnd(format!("{call}: unexpected response {e}: {}", redact(&text))))
+}
+
+// the configured cert is trusted on top of the system roots, for nodes with a self signed cert
+pub(crate) fn lnd_client(lnd: &LndConfig) -> AppResult<Client> {
+    LND.client(format!("{}:{:?}", lnd.timeout_secs, lnd.tls_cert_path), || {
+        let mut builder = Client::builder().timeout(Duration::from_secs(lnd.timeout_secs));
+        if let Some(path) = &lnd.tls_cert_path {
+            let pem = std::fs::read(path).map_err(|e| format!("tls cert {path}: {e}"))?;
+            let cert = Certificate::from_pem(&pem).map_err(|e| format!("tls cert {path}: {e}"))?;
+            builder = builder.add_root_certificate(cert);
+        }
+        builder.build().map_err(|e| e.to_string())
+    })
+}
+
+pub(crate) fn macaroon(lnd: &LndConfig) -> AppResult<String> {
+    lnd.macaroon_hex().map_err(AppError::Lnd)
+}
@@ -0,0 +1,28 @@
+use crate::errors::AppResult;
+use crate::config::LndConfig;
+use crate::lightning::{lnd_client, lnd_request_with_retry, macaroon};
+use crate::models::{ChannelBalanceResponse, GetInfoResponse, WalletBalanceResponse};
+
+pub async fn get_info(lnd: &LndConfig) -> AppResult<GetInfoResponse> {
+    let macaroon = macaroon(lnd)?;
+    let request = lnd_client(lnd)?
+        .get(format!("{}/v1/getinfo", lnd.url))
+        .header("Grpc-Metadata-macaroon", macaroon);
+    lnd_request_with_retry(request, "get info").await
+}
+
+pub async fn channel_balance(lnd: &LndConfig) -> AppResult<ChannelBalanceResponse> {
+    let macaroon = macaroon(lnd)?;
+    let request = lnd_client(lnd)?
+        .get(format!("{}/v1/balance/channels", lnd.url))
+        .header("Grpc-Metadata-macaroon", macaroon);
+    lnd_request_with_retry(request, "channel balance").await
+}
+
+pub async fn wallet_balance(lnd: &LndConfig) -> AppResult<WalletBalanceResponse> {
+    let macaroon = macaroon(lnd)?;
+    let request = lnd_client(lnd)?
+        .get(format!("{}/v1/balance/blockchain", lnd.url))
+        .header("Grpc-Metadata-macaroon", macaroon);
+    lnd_request_with_retry(request, "wallet balance").await
+}
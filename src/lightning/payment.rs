@@ -1,82 +1,156 @@
-use std::env;
-use reqwest::Client;
+use std::time::Instant;
+use reqwest::Response;
 use serde_json::json;
-use crate::models::{DecodedPayment};
-
-pub async fn decode_payment(payment_request: &str) -> Option<DecodedPayment> {
-    let macaroon = env::var("LND_MACAROON").unwrap();
-
-    let response = Client::new()
-        .get(format!("https://lightningchess.m.voltageapp.io:8080/v1/payreq/{}", payment_request))
-        .header("Grpc-Metadata-macaroon", macaroon)
-        .send().await;
-
-    return match response {
-        Ok(res) => {
-            println!("Status: {}", res.status());
-            println!("Headers:\n{:#?}", res.headers());
-            let text = res.text().await;
-            match text {
-                Ok(text) => {
-                    println!("text: {}", text);
-                    let json_response: DecodedPayment = serde_json::from_str(&text).unwrap();
-                    println!("DecodedPayment: {}", serde_json::to_string(&json_response).unwrap());
-                    Some(json_response)
-                }
-                Err(e) => {
-                    println!("error in text() :\n{}", e);
-                    None
-                }
-            }
-        },
-        Err(e) => {
-            println!("error from lnd decode_payment\n{}", e);
-            None
-        }
-    };
+use crate::errors::{AppError, AppResult};
+use crate::config::LndConfig;
+use crate::lightning::{lnd_client, lnd_request_with_retry, lnd_send, macaroon};
+use crate::models::{DecodedPayment, ListPaymentsResponse, PaymentUpdate, TrackPaymentResponse};
+use crate::metrics::observe_upstream;
+use crate::telemetry::redact;
+use crate::upstream::Retry;
+use tracing::{debug, field, info, instrument, Span};
+
+pub async fn decode_payment(lnd: &LndConfig, payment_request: &str) -> AppResult<DecodedPayment> {
+    let macaroon = macaroon(lnd)?;
+
+    let request = lnd_client(lnd)?
+        .get(format!("{}/v1/payreq/{}", lnd.url, payment_request))
+        .header("Grpc-Metadata-macaroon", macaroon);
+    lnd_request_with_retry(request, "decode payment").await
+}
+
+// one page of outgoing payments after index_offset, oldest first
+pub async fn list_payments(lnd: &LndConfig, index_offset: u64, page_size: u64) -> AppResult<ListPaymentsResponse> {
+    let macaroon = macaroon(lnd)?;
+    let request = lnd_client(lnd)?
+        .get(format!("{}/v1/payments?include_incomplete=true&index_offset={index_offset}&max_payments={page_size}", lnd.url))
+        .header("Grpc-Metadata-macaroon", macaroon);
+    lnd_request_with_retry(request, "list payments").await
+}
+
+// how a payment ended according to lnd, an error means it's unknown and the payment may still go through
+#[derive(Debug, PartialEq, Eq)]
+pub enum PaymentOutcome {
+    Succeeded,
+    // lnd gave up, nothing was paid
+    Failed(String)
 }
 
-pub async fn make_payment(payment_request: &str) -> Option<bool> {
-    let macaroon = env::var("LND_MACAROON").unwrap();
+#[instrument(name = "lnd", skip(lnd, payment_request), fields(call = "send payment", status = field::Empty, latency_ms = field::Empty))]
+pub async fn make_payment(lnd: &LndConfig, payment_request: &str, fee_limit_sats: i64) -> AppResult<PaymentOutcome> {
+    let macaroon = macaroon(lnd)?;
 
     let body = json!({
         "payment_request": payment_request,
         "timeout_seconds": 10,
         "max_parts": 3,
-        "fee_limit_sat": 10
+        "fee_limit_sat": fee_limit_sats
     });
-    println!("body: {}", body.to_string());
 
-    let res_result = Client::new()
-        .post(format!("https://lightningchess.m.voltageapp.io:8080/v2/router/send"))
+    let start = Instant::now();
+    let request = lnd_client(lnd)?
+        .post(format!("{}/v2/router/send", lnd.url))
         .json(&body)
-        .header("Grpc-Metadata-macaroon", macaroon)
-        .send().await;
-
-    match res_result {
-        Ok(mut res) => {
-            let mut still_chunky = true;
-            while still_chunky {
-                let chunk_result = res.chunk().await;
-                match chunk_result {
-                    Ok(maybe_chunk) => match maybe_chunk {
-                        Some(chunk) => println!("Chunk: {:?}", chunk),
-                        None => {
-                            println!("No chonks");
-                            still_chunky = false;
-                        }
-                    },
-                    Err(e) => {
-                        println!("No more chunks {}", e);
-                        return None;
-                    }
-                }
-            }
-        },
-        Err(e) => {
-            println!("error in v2/router/send :\n{}", e);
-            return None;
+        .header("Grpc-Metadata-macaroon", macaroon);
+    // never repeated, lnd refuses a second payment to the same invoice anyway
+    let res_result = lnd_send(request, "send payment", Retry::Never).await;
+
+    let result = read_payment_updates(res_result).await;
+    Span::current().record("latency_ms", start.elapsed().as_millis() as u64);
+    observe_upstream("lnd", "send payment", start.elapsed(), &result);
+    info!("lnd call finished");
+    result
+}
+
+async fn read_payment_updates(res_result: AppResult<Response>) -> AppResult<PaymentOutcome> {
+    let mut res = res_result?;
+    let status = res.status();
+    Span::current().record("status", status.as_u16());
+    let mut body = Vec::new();
+    while let Some(chunk) = res.chunk().await.map_err(|e| AppError::Lnd(format!("v2/router/send chunk: {e}")))? {
+        // payment updates carry the preimage once settled
+        debug!(update = %redact(&String::from_utf8_lossy(&chunk)), "payment update");
+        body.extend_from_slice(&chunk);
+    }
+    if !status.is_success() {
+        return Err(AppError::Lnd(format!("v2/router/send: {status} {}", redact(&String::from_utf8_lossy(&body)))))
+    }
+    payment_outcome(&body)
+}
+
+// the last update of the stream is the final state, only SUCCEEDED and FAILED are final
+fn payment_outcome(body: &[u8]) -> AppResult<PaymentOutcome> {
+    let last = body.rsplit(|b| *b == b'\n')
+        .find(|line| !line.iter().all(u8::is_ascii_whitespace))
+        .map(|line| String::from_utf8_lossy(line).to_string())
+        .ok_or_else(|| AppError::Lnd("v2/router/send: no payment update".to_string()))?;
+    let update: TrackPaymentResponse = serde_json::from_str(&last)
+        .map_err(|e| AppError::Lnd(format!("v2/router/send: unexpected response {e}: {}", redact(&last))))?;
+    let update = update.result.ok_or_else(|| AppError::Lnd(format!("v2/router/send: {}", redact(&last))))?;
+    match update.status.as_str() {
+        "SUCCEEDED" => Ok(PaymentOutcome::Succeeded),
+        "FAILED" => Ok(PaymentOutcome::Failed(update.failure_reason)),
+        other => Err(AppError::Lnd(format!("v2/router/send: stream ended with payment {other}")))
+    }
+}
+
+// current state of an outgoing payment, only the first update of the track stream is read
+#[instrument(name = "lnd", skip(lnd), fields(call = "track payment", status = field::Empty, latency_ms = field::Empty))]
+pub async fn track_payment(lnd: &LndConfig, payment_hash: &str) -> AppResult<PaymentUpdate> {
+    let macaroon = macaroon(lnd)?;
+    let hash_bytes = hex::decode(payment_hash).map_err(|_| AppError::Validation("invalid payment hash".to_string()))?;
+
+    let start = Instant::now();
+    let request = lnd_client(lnd)?
+        .get(format!("{}/v2/router/track/{}", lnd.url, base64::encode_config(hash_bytes, base64::URL_SAFE)))
+        .header("Grpc-Metadata-macaroon", macaroon);
+    let res_result = lnd_send(request, "track payment", Retry::Idempotent).await;
+
+    let result = read_first_payment_update(res_result).await;
+    Span::current().record("latency_ms", start.elapsed().as_millis() as u64);
+    observe_upstream("lnd", "track payment", start.elapsed(), &result);
+    info!("lnd call finished");
+    result
+}
+
+async fn read_first_payment_update(res_result: AppResult<Response>) -> AppResult<PaymentUpdate> {
+    let mut res = res_result?;
+    let status = res.status();
+    Span::current().record("status", status.as_u16());
+    let mut body = Vec::new();
+    while let Some(chunk) = res.chunk().await.map_err(|e| AppError::Lnd(format!("v2/router/track chunk: {e}")))? {
+        body.extend_from_slice(&chunk);
+        if body.contains(&b'\n') {
+            break
         }
     }
-    Some(true)
-}
\ No newline at end of file
+    let line = body.split(|b| *b == b'\n').next().unwrap_or_default();
+    let text = String::from_utf8_lossy(line);
+    if !status.is_success() {
+        return Err(AppError::Lnd(format!("v2/router/track: {status} {}", redact(&text))))
+    }
+    let update: TrackPaymentResponse = serde_json::from_str(&text)
+        .map_err(|e| AppError::Lnd(format!("v2/router/track: unexpected response {e}: {}", redact(&text))))?;
+    update.result.ok_or_else(|| AppError::Lnd(format!("v2/router/track: {}", redact(&text))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failed_payment_stream() {
+        let body = b"{\"result\":{\"payment_hash\":\"ab\",\"status\":\"IN_FLIGHT\"}}\n{\"result\":{\"payment_hash\":\"ab\",\"status\":\"FAILED\",\"failure_reason\":\"FAILURE_REASON_NO_ROUTE\"}}\n";
+        assert_eq!(payment_outcome(body).unwrap(), PaymentOutcome::Failed("FAILURE_REASON_NO_ROUTE".to_string()));
+    }
+
+    #[test]
+    fn only_final_updates_are_outcomes() {
+        let succeeded = b"{\"result\":{\"status\":\"IN_FLIGHT\"}}\n{\"result\":{\"status\":\"SUCCEEDED\"}}\n";
+        assert_eq!(payment_outcome(succeeded).unwrap(), PaymentOutcome::Succeeded);
+        // the payment may still settle
+        assert!(payment_outcome(b"{\"result\":{\"status\":\"IN_FLIGHT\"}}\n").is_err());
+        assert!(payment_outcome(b"{\"error\":{\"code\":2,\"message\":\"invoice expired\"}}").is_err());
+        assert!(payment_outcome(b"").is_err());
+    }
+}
@@ -1,21 +1,35 @@
 #[macro_use] extern crate rocket;
 
-use crate::config::parse_config;
+use crate::accept_saga::accept_recovery_job;
+use crate::config::{figment, parse_config, AppConfig};
+use crate::errors::api_catcher;
+use crate::endpoints::admin::{admin_adjust_balance, admin_audit_log, admin_approve_withdrawal, admin_challenges, admin_discrepancies, admin_disputes, admin_reject_withdrawal, admin_resolve_challenge, admin_resolve_dispute, admin_transactions, admin_users};
 use crate::endpoints::callback::callback;
-use crate::endpoints::challenge::{accept_challenge, create_challenge, lookup_challenge, challenges};
-use crate::endpoints::money::{add_invoice_endpoint, balance, transactions, lookup_transaction, send_payment_endpoint};
+use crate::endpoints::challenge::{accept_challenge, challenge_game, create_challenge, dispute_challenge, lookup_challenge, challenges, suggest_odds};
+use crate::endpoints::money::{add_invoice_endpoint, balance, export_transactions, transactions, lookup_transaction, send_payment_endpoint};
+use crate::endpoints::health::{healthz, readyz, ReadinessCache};
+use crate::endpoints::leaderboard::{leaderboard_endpoint, leaderboard_opt_out};
 use crate::endpoints::login::login;
 use crate::endpoints::lichess::lichess_user_endpoint;
-use crate::endpoints::profile::profile;
-use crate::models::{AppConfig, User};
-use moka::future::Cache;
+use crate::endpoints::profile::{player_stats_endpoint, profile, update_preferences};
+use crate::endpoints::session::{list_sessions_endpoint, logout, revoke_all_endpoint};
+use crate::endpoints::metrics::metrics_endpoint;
+use crate::endpoints::notifications::{notifications_endpoint, read_notifications_endpoint};
+use crate::game_events::{game_events_job, GameWatcher};
+use crate::leaderboard::leaderboard_job;
+use crate::reconcile::reconcile_job;
+use crate::settlement::{payout_hold_job, settlement_job};
+use crate::stats::StatsCache;
+use crate::telemetry::{init_tracing, RequestTracing};
 use rocket::fairing::AdHoc;
-use rocket::State;
+use rocket::figment::Figment;
+use rocket::{Build, Rocket, State};
 use rocket_dyn_templates::Template;
 use std::collections::HashMap;
-use std::env;
+use std::{env, process};
 use rocket::response::Redirect;
-use sqlx::postgres::PgPoolOptions;
+use sqlx::{Pool, Postgres};
+use tracing::{debug, error};
 
 pub mod errors;
 pub mod guard;
@@ -24,6 +38,26 @@ pub mod lichess;
 pub mod lightning;
 pub mod endpoints;
 pub mod config;
+pub mod odds;
+pub mod settlement;
+pub mod telemetry;
+pub mod metrics;
+pub mod migrate;
+pub mod cli;
+pub mod admin;
+pub mod ledger;
+pub mod history;
+pub mod stats;
+pub mod leaderboard;
+pub mod crypto;
+pub mod sessions;
+pub mod reconcile;
+pub mod upstream;
+pub mod game_events;
+pub mod accept_saga;
+pub mod games;
+pub mod disputes;
+pub mod notifications;
 
 #[get("/")]
 async fn index(app_config: &State<AppConfig>) -> Template {
@@ -34,7 +68,7 @@ async fn index(app_config: &State<AppConfig>) -> Template {
 
 #[get("/<any_str>", rank = 100)]
 async fn index_catch_all(app_config: &State<AppConfig>, any_str: String) -> Template {
-    println!("index catch all / {}", any_str);
+    debug!(path = %any_str, "index catch all");
     let mut context = HashMap::new();
     context.insert("fe_url", app_config.fe_url.to_string());
     Template::render("index", &context)
@@ -45,22 +79,50 @@ async fn api_catch_all(_any_str: String) -> Redirect {
     Redirect::to("/login")
 }
 
-#[launch]
-async fn rocket() -> _ {
+#[rocket::main]
+async fn main() {
+    let figment = figment();
+    let log_format: String = figment.extract_inner("log_format").unwrap_or_else(|_| "pretty".to_string());
+    init_tracing(&log_format);
 
-    let db_url = env::var("DB_URL").unwrap();
+    let args: Vec<String> = env::args().skip(1).collect();
+    if !args.is_empty() {
+        if let Err(e) = cli::run(&args, &figment).await {
+            eprintln!("{e}");
+            process::exit(1);
+        }
+        return
+    }
 
-    let pool = PgPoolOptions::new()
-        .max_connections(5)
-        .connect(&db_url)
-        .await.unwrap();
+    if let Err(e) = rocket(figment).launch().await {
+        error!(error = %e, "rocket failed to launch");
+        process::exit(1);
+    }
+}
 
-    let cache: Cache<String, User> = Cache::new(10_000);
+fn rocket(figment: Figment) -> Rocket<Build> {
+    let (game_watcher, watched_games) = GameWatcher::channel();
 
-    rocket::build()
+    rocket::custom(figment)
+        .attach(RequestTracing)
         .attach(AdHoc::try_on_ignite("appConfig", parse_config))
-        .manage(pool)
-        .manage(cache)
+        .attach(AdHoc::on_liftoff("settlementJobs", |rocket| Box::pin(async move {
+            let pool = rocket.state::<Pool<Postgres>>().unwrap().clone();
+            let config = rocket.state::<AppConfig>().unwrap().clone();
+            // tokens still on a retired key or stored before encryption
+            if let Err(e) = admin::rotate_token_keys(&pool, &config.encryption).await {
+                error!(error = %e, "error rotating token keys");
+            }
+            tokio::spawn(settlement_job(pool.clone(), config.clone()));
+            tokio::spawn(payout_hold_job(pool.clone(), config.clone()));
+            tokio::spawn(reconcile_job(pool.clone(), config.clone()));
+            tokio::spawn(accept_recovery_job(pool.clone(), config.clone()));
+            tokio::spawn(game_events_job(pool.clone(), config, watched_games));
+            tokio::spawn(leaderboard_job(pool));
+        })))
+        .manage(game_watcher)
+        .manage(ReadinessCache::default())
+        .manage(StatsCache::default())
         .mount("/", routes![
             index,
             index_catch_all,
@@ -68,15 +130,43 @@ async fn rocket() -> _ {
             login,
             callback,
             profile,
+            update_preferences,
+            logout,
+            list_sessions_endpoint,
+            revoke_all_endpoint,
+            notifications_endpoint,
+            read_notifications_endpoint,
+            player_stats_endpoint,
+            leaderboard_endpoint,
+            leaderboard_opt_out,
             create_challenge,
             accept_challenge,
             lookup_challenge,
+            challenge_game,
+            dispute_challenge,
+            suggest_odds,
             lichess_user_endpoint,
             challenges,
             add_invoice_endpoint,
             balance,
             transactions,
+            export_transactions,
             lookup_transaction,
-            send_payment_endpoint])
+            send_payment_endpoint,
+            metrics_endpoint,
+            healthz,
+            readyz,
+            admin_users,
+            admin_transactions,
+            admin_challenges,
+            admin_audit_log,
+            admin_discrepancies,
+            admin_adjust_balance,
+            admin_approve_withdrawal,
+            admin_reject_withdrawal,
+            admin_resolve_challenge,
+            admin_disputes,
+            admin_resolve_dispute])
+        .register("/api", catchers![api_catcher])
         .attach(Template::fairing())
 }
@@ -0,0 +1,79 @@
+use std::time::Duration;
+use once_cell::sync::Lazy;
+use prometheus::{register_histogram_vec, register_int_counter, register_int_counter_vec, register_int_gauge_vec, HistogramVec, IntCounter, IntCounterVec, IntGaugeVec};
+use crate::errors::{AppError, AppResult};
+
+pub static CHALLENGES: Lazy<IntCounterVec> = Lazy::new(|| register_int_counter_vec!(
+    "lightningchess_challenges_total", "Challenges by the status they moved to", &["status"]
+).unwrap());
+
+pub static SATS_ESCROWED: Lazy<IntCounter> = Lazy::new(|| register_int_counter!(
+    "lightningchess_sats_escrowed_total", "Sats put into escrow by accepted challenges"
+).unwrap());
+
+pub static ACCEPT_SAGAS: Lazy<IntCounterVec> = Lazy::new(|| register_int_counter_vec!(
+    "lightningchess_accept_sagas_total", "Challenge accepts by how they ended", &["outcome"]
+).unwrap());
+
+pub static DISPUTES: Lazy<IntCounterVec> = Lazy::new(|| register_int_counter_vec!(
+    "lightningchess_disputes_total", "Disputes filed and how they were resolved", &["outcome"]
+).unwrap());
+
+pub static DEPOSITS: Lazy<IntCounterVec> = Lazy::new(|| register_int_counter_vec!(
+    "lightningchess_deposits_total", "Deposit invoices by outcome", &["outcome"]
+).unwrap());
+
+pub static WITHDRAWALS: Lazy<IntCounterVec> = Lazy::new(|| register_int_counter_vec!(
+    "lightningchess_withdrawals_total", "Withdrawals by outcome", &["outcome"]
+).unwrap());
+
+pub static UPSTREAM_LATENCY: Lazy<HistogramVec> = Lazy::new(|| register_histogram_vec!(
+    "lightningchess_upstream_request_duration_seconds", "Latency of lichess and lnd calls", &["upstream", "call", "outcome"]
+).unwrap());
+
+pub static AUTH_CACHE: Lazy<IntCounterVec> = Lazy::new(|| register_int_counter_vec!(
+    "lightningchess_auth_cache_requests_total", "Auth cache lookups by result", &["result"]
+).unwrap());
+
+pub static DB_POOL: Lazy<IntGaugeVec> = Lazy::new(|| register_int_gauge_vec!(
+    "lightningchess_db_pool_connections", "Postgres pool connections by state", &["state"]
+).unwrap());
+
+pub static SOLVENCY: Lazy<IntGaugeVec> = Lazy::new(|| register_int_gauge_vec!(
+    "lightningchess_solvency_sats", "Sats owed to users and held by the node at the last reconciliation", &["kind"]
+).unwrap());
+
+pub static DISCREPANCIES: Lazy<IntGaugeVec> = Lazy::new(|| register_int_gauge_vec!(
+    "lightningchess_reconciliation_discrepancies", "Open reconciliation discrepancies by kind", &["kind"]
+).unwrap());
+
+pub static CIRCUIT_OPEN: Lazy<IntGaugeVec> = Lazy::new(|| register_int_gauge_vec!(
+    "lightningchess_upstream_circuit_open", "1 while calls to the upstream are refused after repeated failures", &["upstream"]
+).unwrap());
+
+pub fn observe_upstream<T>(upstream: &str, call: &str, elapsed: Duration, result: &AppResult<T>) {
+    let outcome = if result.is_ok() { "success" } else { "error" };
+    UPSTREAM_LATENCY.with_label_values(&[upstream, call, outcome]).observe(elapsed.as_secs_f64());
+}
+
+// outcome label for money movements, so user mistakes and real failures can be told apart
+pub fn outcome_label<T>(result: &AppResult<T>, success: &'static str) -> &'static str {
+    match result {
+        Ok(_) => success,
+        Err(AppError::InsufficientFunds) => "insufficient_funds",
+        Err(AppError::Validation(_)) => "rejected",
+        Err(_) => "failed"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn outcome_labels() {
+        assert_eq!(outcome_label(&Ok(()), "settled"), "settled");
+        assert_eq!(outcome_label::<()>(&Err(AppError::InsufficientFunds), "settled"), "insufficient_funds");
+        assert_eq!(outcome_label::<()>(&Err(AppError::Lnd("down".to_string())), "settled"), "failed");
+    }
+}
@@ -0,0 +1,85 @@
+use sqlx::migrate::{Migrate, MigrateError, Migrator};
+use sqlx::{Pool, Postgres};
+use tracing::info;
+
+// migrations are embedded at compile time so deployments only need the binary
+pub static MIGRATOR: Migrator = sqlx::migrate!();
+
+pub struct MigrationStatus {
+    pub version: i64,
+    pub description: String,
+    pub applied: bool,
+    // applied with a different script than the one embedded in this binary
+    pub checksum_mismatch: bool
+}
+
+pub async fn status(pool: &Pool<Postgres>) -> Result<Vec<MigrationStatus>, MigrateError> {
+    let mut conn = pool.acquire().await?;
+    conn.ensure_migrations_table().await?;
+    let applied = conn.list_applied_migrations().await?;
+
+    let statuses = MIGRATOR.iter()
+        .filter(|m| !m.migration_type.is_down_migration())
+        .map(|m| {
+            let applied_migration = applied.iter().find(|a| a.version == m.version);
+            MigrationStatus {
+                version: m.version,
+                description: m.description.to_string(),
+                applied: applied_migration.is_some(),
+                checksum_mismatch: applied_migration.map(|a| a.checksum != m.checksum).unwrap_or(false)
+            }
+        })
+        .collect();
+    Ok(statuses)
+}
+
+pub async fn run(pool: &Pool<Postgres>) -> Result<(), MigrateError> {
+    MIGRATOR.run(pool).await?;
+    info!("migrations up to date");
+    Ok(())
+}
+
+// reverts the latest applied migration, returns its version
+pub async fn revert(pool: &Pool<Postgres>) -> Result<Option<i64>, MigrateError> {
+    let mut conn = pool.acquire().await?;
+    conn.ensure_migrations_table().await?;
+    let applied: Vec<i64> = conn.list_applied_migrations().await?.iter().map(|a| a.version).collect();
+    drop(conn);
+
+    match revert_target(&applied) {
+        Some((latest, target)) => {
+            MIGRATOR.undo(pool, target).await?;
+            info!(version = latest, "migration reverted");
+            Ok(Some(latest))
+        }
+        None => Ok(None)
+    }
+}
+
+// the latest applied version and the version to undo back to
+fn revert_target(applied: &[i64]) -> Option<(i64, i64)> {
+    let mut versions = applied.to_vec();
+    versions.sort_unstable();
+    let latest = versions.pop()?;
+    Some((latest, versions.pop().unwrap_or(0)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_migration_is_reversible() {
+        let ups: Vec<i64> = MIGRATOR.iter().filter(|m| !m.migration_type.is_down_migration()).map(|m| m.version).collect();
+        let downs: Vec<i64> = MIGRATOR.iter().filter(|m| m.migration_type.is_down_migration()).map(|m| m.version).collect();
+        assert!(!ups.is_empty());
+        assert_eq!(ups, downs);
+    }
+
+    #[test]
+    fn revert_latest_only() {
+        assert_eq!(revert_target(&[20230201014600, 20221106000404, 20230211190341]), Some((20230211190341, 20230201014600)));
+        assert_eq!(revert_target(&[20221106000404]), Some((20221106000404, 0)));
+        assert_eq!(revert_target(&[]), None);
+    }
+}
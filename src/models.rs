@@ -4,18 +4,10 @@ use sqlx::{FromRow};
 
 #[derive(Serialize, Deserialize)]
 pub struct Account {
+    pub id: String, // lowercased username, never changes
     pub username: String
 }
 
-pub struct AppConfig {
-    pub url: String,
-    pub fe_url: String
-}
-
-pub struct EnvVariables {
-    pub macaroon: String // hex encoded
-}
-
 fn default_string() -> String {
     "".to_string()
 }
@@ -33,15 +25,54 @@ pub struct Challenge {
     pub opponent_time_limit: Option<i32>, // seconds
     pub increment: Option<i32>, // seconds
     pub color: Option<String>,
-    pub sats: Option<i64>,
+    pub sats: Option<i64>, // challenger stake
+    pub opponent_sats: Option<i64>, // opponent stake, defaults to sats
     pub opp_username: String,
     pub status: Option<String>,
     pub lichess_challenge_id: Option<String>,
     pub created_on: Option<NaiveDateTime>, // UTC
     pub expire_after: Option<i32>, // seconds
+    // encrypted, only kept while the challenge waits to be accepted and never sent to clients
+    #[serde(skip)]
     pub challenger_token: Option<String>
 }
 
+// user1 playing white against user2, 5+0 for 1000 sats each, tests override what they need
+#[cfg(test)]
+pub fn test_challenge() -> Challenge {
+    Challenge {
+        id: 1,
+        username: "user1".to_string(),
+        time_limit: Some(300),
+        opponent_time_limit: Some(300),
+        increment: Some(0),
+        color: Some("white".to_string()),
+        sats: Some(1000),
+        opponent_sats: Some(1000),
+        opp_username: "user2".to_string(),
+        status: None,
+        lichess_challenge_id: None,
+        created_on: None,
+        expire_after: None,
+        challenger_token: None
+    }
+}
+
+// the persisted progress of accepting a challenge, see accept_saga.rs
+#[derive(Serialize, Deserialize, FromRow)]
+pub struct AcceptSaga {
+    pub saga_id: i32,
+    pub challenge_id: i32,
+    pub username: String, // the accepting player
+    #[serde(skip)]
+    pub acceptor_token: Option<String>, // encrypted
+    pub step: String,
+    pub lichess_challenge_id: Option<String>,
+    pub error: Option<String>,
+    pub created_on: NaiveDateTime, // UTC
+    pub updated_on: NaiveDateTime // UTC
+}
+
 #[derive(Serialize, Deserialize, FromRow)]
 pub struct Transaction {
     #[serde(default = "default_i32")]
@@ -56,7 +87,53 @@ pub struct Transaction {
     pub payment_addr: Option<String>, // base64 encoded
     pub payment_request: Option<String>,
     pub payment_hash: Option<String>,
-    pub lichess_challenge_id: Option<String>
+    pub lichess_challenge_id: Option<String>,
+    pub release_after: Option<NaiveDateTime>, // UTC, game payouts are held until then
+    pub created_on: Option<NaiveDateTime> // UTC
+}
+
+#[derive(Serialize, Deserialize, FromRow)]
+pub struct TransactionTotals {
+    pub count: i64,
+    pub credited: i64,
+    pub debited: i64,
+    pub net: i64
+}
+
+#[derive(Serialize, Deserialize, FromRow)]
+pub struct ChallengeTotals {
+    pub count: i64,
+    pub sats_staked: i64 // the user's own stakes
+}
+
+#[derive(Serialize, Deserialize, FromRow)]
+pub struct AuditEntry {
+    pub audit_id: i64,
+    pub actor_type: String,
+    pub actor: String,
+    pub action: String,
+    pub username: String,
+    pub amount: i64,
+    pub balance_before: i64,
+    pub balance_after: i64,
+    pub challenge_id: Option<i32>,
+    pub transaction_id: Option<i32>,
+    pub request_id: Option<String>,
+    pub detail: Option<String>,
+    pub created_on: NaiveDateTime // UTC
+}
+
+#[derive(Serialize, Deserialize, FromRow)]
+pub struct Discrepancy {
+    pub discrepancy_id: i32,
+    pub kind: String,
+    pub transaction_id: Option<i32>,
+    pub expected: Option<i64>,
+    pub actual: Option<i64>,
+    pub detail: String,
+    pub first_seen: NaiveDateTime, // UTC
+    pub last_seen: NaiveDateTime, // UTC
+    pub resolved_on: Option<NaiveDateTime> // UTC
 }
 
 #[derive(Serialize, Deserialize, FromRow)]
@@ -72,6 +149,85 @@ pub struct ChallengeAcceptRequest {
     pub id: i32,
 }
 
+#[derive(Serialize, Deserialize, FromRow)]
+pub struct LeaderboardEntry {
+    pub username: String,
+    pub net_sats: i64,
+    pub games: i64,
+    pub wins: i64,
+    pub losses: i64,
+    pub draws: i64,
+    pub win_rate: f64,
+    pub refreshed_on: NaiveDateTime // UTC
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct LeaderboardResponse {
+    pub window: String,
+    pub category: String,
+    pub entries: Vec<LeaderboardEntry>
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct LeaderboardOptOutRequest {
+    pub opt_out: bool
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct BalanceAdjustmentRequest {
+    pub username: String,
+    pub sats: i64, // negative to debit
+    pub note: String
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct WithdrawalRejectRequest {
+    pub reason: String
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ChallengeResolveRequest {
+    pub outcome: String // white, black, draw, refund or void
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DisputeRequest {
+    pub reason: String
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DisputeResolveRequest {
+    pub outcome: String, // uphold, refund or award
+    pub winner: Option<String>, // the username awarded both stakes
+    pub note: Option<String>
+}
+
+#[derive(Serialize, Deserialize, FromRow)]
+pub struct Dispute {
+    pub dispute_id: i32,
+    pub challenge_id: i32,
+    pub username: String, // the player who filed it
+    pub reason: String,
+    pub status: String, // OPEN or RESOLVED
+    pub outcome: Option<String>,
+    pub awarded_to: Option<String>,
+    pub resolved_by: Option<String>,
+    pub note: Option<String>,
+    pub created_on: NaiveDateTime, // UTC
+    pub resolved_on: Option<NaiveDateTime> // UTC
+}
+
+#[derive(Serialize, Deserialize, FromRow)]
+pub struct Notification {
+    pub notification_id: i64,
+    pub username: String,
+    pub kind: String,
+    pub message: String,
+    pub challenge_id: Option<i32>,
+    pub created_on: NaiveDateTime, // UTC
+    pub read_on: Option<NaiveDateTime> // UTC
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct LichessChallenge {
     pub rated: bool,
@@ -112,6 +268,11 @@ pub struct LichessAcceptChallengeResponse {
     pub ok: bool
 }
 
+#[derive(Serialize, Deserialize)]
+pub struct LichessCancelChallengeResponse {
+    pub ok: bool
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct LichessExportGameResponse {
     pub id: String,
@@ -120,7 +281,94 @@ pub struct LichessExportGameResponse {
     pub speed: String,
     pub perf: String,
     pub status: String,
-    pub winner: Option<String>
+    pub winner: Option<String>,
+    #[serde(default)]
+    pub players: LichessGamePlayers,
+    pub clock: Option<LichessGameClock>,
+    pub opening: Option<LichessOpening>,
+    #[serde(default)]
+    pub moves: String, // SAN, space separated
+    #[serde(default)]
+    pub clocks: Vec<i32>, // centiseconds left after each move
+    pub pgn: Option<String>,
+    #[serde(rename = "createdAt")]
+    pub created_at: Option<i64>, // epoch millis
+    #[serde(rename = "lastMoveAt")]
+    pub last_move_at: Option<i64> // epoch millis
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct LichessGamePlayers {
+    #[serde(default)]
+    pub white: LichessGamePlayer,
+    #[serde(default)]
+    pub black: LichessGamePlayer
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct LichessGamePlayer {
+    pub user: Option<LichessGameUser>,
+    pub rating: Option<i32>
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct LichessGameUser {
+    pub name: String
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct LichessGameClock {
+    pub initial: i32, // seconds
+    pub increment: i32 // seconds
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct LichessOpening {
+    pub eco: String,
+    pub name: String
+}
+
+// a settled challenge's game as exported by lichess
+#[derive(Serialize, Deserialize, FromRow)]
+pub struct Game {
+    pub game_id: i32,
+    pub challenge_id: i32,
+    pub lichess_game_id: String,
+    pub status: String,
+    pub winner: Option<String>, // white or black, None for draws and aborted games
+    pub white: Option<String>,
+    pub black: Option<String>,
+    pub white_rating: Option<i32>,
+    pub black_rating: Option<i32>,
+    pub speed: Option<String>,
+    pub clock_initial: Option<i32>, // seconds
+    pub clock_increment: Option<i32>, // seconds
+    pub opening_eco: Option<String>,
+    pub opening_name: Option<String>,
+    pub moves: String, // SAN, space separated
+    pub clocks: Vec<i32>, // centiseconds left after each move
+    pub pgn: Option<String>,
+    pub started_on: Option<NaiveDateTime>, // UTC
+    pub finished_on: Option<NaiveDateTime>, // UTC
+    pub created_on: NaiveDateTime // UTC
+}
+
+// a line of the games stream, the status is a lichess game status like started, mate or aborted
+#[derive(Deserialize, Debug)]
+pub struct LichessGameEvent {
+    pub id: String,
+    #[serde(rename = "statusName")]
+    pub status_name: Option<String>
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct OddsSuggestion {
+    pub speed: String,
+    pub rating: i64,
+    pub opponent_rating: i64,
+    pub win_probability: f64,
+    pub sats: i64,
+    pub opponent_sats: i64
 }
 
 #[derive(Serialize, Deserialize)]
@@ -128,7 +376,7 @@ pub struct LichessChallengeAcceptResponse {
     pub ok: bool
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Default)]
 pub struct LichessPerf {
     pub games: i64,
     pub rating: i64,
@@ -136,7 +384,9 @@ pub struct LichessPerf {
     pub prog: i64,
 }
 
-#[derive(Serialize, Deserialize)]
+// closed accounts come back without perfs
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
 pub struct LichessPerfs {
     pub blitz: LichessPerf,
     pub bullet: LichessPerf,
@@ -149,16 +399,24 @@ pub struct LichessPerfs {
 pub struct LichessUser {
     pub id: String,
     pub username: String,
+    #[serde(default)]
     pub perfs: LichessPerfs,
-    #[serde(rename = "createdAt")]
+    #[serde(rename = "createdAt", default)]
     pub created_at: i64,
-    #[serde(rename = "seenAt")]
+    #[serde(rename = "seenAt", default)]
     pub seen_at: i64,
+    #[serde(rename = "tosViolation", default)]
+    pub tos_violation: bool,
+    #[serde(default)]
+    pub disabled: bool,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct TokenResponse {
-    pub access_token: String
+    pub access_token: String,
+    // space separated, left out when it's what was asked for
+    pub scope: Option<String>,
+    pub expires_in: Option<i64> // seconds
 }
 
 #[derive(Serialize, Deserialize)]
@@ -167,15 +425,46 @@ pub struct Url {
     pub url: String
 }
 
+// id of the current rocket request, carried on every log line
+#[derive(Clone)]
+pub struct RequestId(pub String);
+
+impl std::fmt::Display for RequestId {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+// the logged in user behind a session cookie
 #[derive(Clone)]
 pub struct User {
-    pub access_token: String,
+    pub user_id: i32,
+    pub username: String,
+    pub access_token: String, // decrypted lichess token
+    pub session_hash: String,
+    pub session_expires_on: NaiveDateTime // UTC
+}
+
+#[derive(Serialize, Deserialize, FromRow)]
+pub struct SessionInfo {
+    pub created_on: NaiveDateTime, // UTC
+    pub last_seen: NaiveDateTime, // UTC
+    pub expires_on: NaiveDateTime, // UTC
+    pub current: bool
+}
+
+// a configured admin user, or "api key" when authenticated with X-Admin-Key
+pub struct Admin {
     pub username: String
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, FromRow)]
 pub struct UserProfile {
-    pub username: String
+    pub username: String,
+    pub lichess_id: String,
+    pub created_on: NaiveDateTime, // UTC
+    pub last_seen: NaiveDateTime, // UTC
+    pub preferences: String // json object, see /api/preferences
 }
 
 #[derive(Serialize, Deserialize)]
@@ -196,6 +485,32 @@ pub struct AddInvoiceResponse {
     pub payment_addr: String
 }
 
+#[derive(Serialize, Deserialize)]
+pub struct ListInvoicesResponse {
+    #[serde(default)]
+    pub invoices: Vec<LndInvoice>,
+    #[serde(default)]
+    pub last_index_offset: String
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct LndInvoice {
+    #[serde(default)]
+    pub payment_addr: String, // base64 encoded
+    #[serde(default)]
+    pub state: String, // OPEN, SETTLED, CANCELED or ACCEPTED
+    #[serde(default)]
+    pub amt_paid_sat: String
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ListPaymentsResponse {
+    #[serde(default)]
+    pub payments: Vec<PaymentUpdate>,
+    #[serde(default)]
+    pub last_index_offset: String
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct DecodedPayment {
     pub destination: String,
@@ -222,4 +537,57 @@ pub struct LookupInvoiceResponse {
     pub expiry: String,
     pub amt_paid_sat: String,
     pub state: String
-}
\ No newline at end of file
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct GetInfoResponse {
+    #[serde(default)]
+    pub alias: String,
+    #[serde(default)]
+    pub synced_to_chain: bool,
+    #[serde(default)]
+    pub num_active_channels: i64,
+    #[serde(default)]
+    pub block_height: i64
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct Amount {
+    #[serde(default)]
+    pub sat: String
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct WalletBalanceResponse {
+    #[serde(default)]
+    pub confirmed_balance: String,
+    #[serde(default)]
+    pub unconfirmed_balance: String
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TrackPaymentResponse {
+    pub result: Option<PaymentUpdate>
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PaymentUpdate {
+    #[serde(default)]
+    pub payment_hash: String,
+    #[serde(default)]
+    pub status: String, // IN_FLIGHT, SUCCEEDED or FAILED
+    #[serde(default)]
+    pub value_sat: String,
+    #[serde(default)]
+    pub fee_sat: String,
+    #[serde(default)]
+    pub failure_reason: String
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ChannelBalanceResponse {
+    #[serde(default)]
+    pub local_balance: Amount,
+    #[serde(default)]
+    pub remote_balance: Amount
+}
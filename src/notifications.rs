@@ -0,0 +1,35 @@
+use sqlx::{Pool, Postgres};
+use tracing::warn;
+use crate::models::Notification;
+
+// messages for players about their challenges, read through /api/notifications
+
+const PAGE_SIZE: i64 = 50;
+
+pub async fn notify(pool: &Pool<Postgres>, username: &str, kind: &str, challenge_id: Option<i32>, message: &str) {
+    let result = sqlx::query("INSERT INTO notification (username, kind, message, challenge_id) VALUES ($1, $2, LEFT($3, 1024), $4)")
+        .bind(username)
+        .bind(kind)
+        .bind(message)
+        .bind(challenge_id)
+        .execute(pool).await;
+    // a lost notification never holds up what it's about
+    if let Err(e) = result {
+        warn!(username, kind, error = %e, "error saving notification");
+    }
+}
+
+pub async fn list_notifications(pool: &Pool<Postgres>, username: &str, unread: bool) -> Result<Vec<Notification>, sqlx::Error> {
+    sqlx::query_as::<_,Notification>("SELECT * FROM notification WHERE username=$1 AND (NOT $2 OR read_on IS NULL) ORDER BY notification_id DESC LIMIT $3")
+        .bind(username)
+        .bind(unread)
+        .bind(PAGE_SIZE)
+        .fetch_all(pool).await
+}
+
+pub async fn mark_read(pool: &Pool<Postgres>, username: &str) -> Result<u64, sqlx::Error> {
+    let updated = sqlx::query("UPDATE notification SET read_on=(now() at time zone 'utc') WHERE username=$1 AND read_on IS NULL")
+        .bind(username)
+        .execute(pool).await?;
+    Ok(updated.rows_affected())
+}
@@ -0,0 +1,76 @@
+use crate::models::{LichessPerfs, LichessPerf};
+
+// lichess estimates game duration as limit + 40 * increment to pick the rating pool
+pub fn speed_for_clock(limit: i32, increment: i32) -> &'static str {
+    let estimated = limit + 40 * increment;
+    if estimated < 180 {
+        "bullet"
+    } else if estimated < 480 {
+        "blitz"
+    } else if estimated < 1500 {
+        "rapid"
+    } else {
+        "classical"
+    }
+}
+
+// None when the player has no games in that speed, missing perfs deserialize as a rating of 0
+pub fn perf_for_speed<'a>(perfs: &'a LichessPerfs, speed: &str) -> Option<&'a LichessPerf> {
+    let perf = match speed {
+        "bullet" => &perfs.bullet,
+        "blitz" => &perfs.blitz,
+        "rapid" => &perfs.rapid,
+        _ => &perfs.classical
+    };
+    Some(perf).filter(|p| p.games > 0 && p.rating > 0)
+}
+
+// elo expected score of a player rated `rating` against `opponent_rating`
+pub fn win_probability(rating: i64, opponent_rating: i64) -> f64 {
+    1.0 / (1.0 + 10f64.powf((opponent_rating - rating) as f64 / 400.0))
+}
+
+// stake the opponent should put up against `sats` so the wager has zero expected value
+pub fn fair_opponent_sats(sats: i64, win_probability: f64) -> i64 {
+    let p = win_probability.clamp(0.01, 0.99);
+    ((sats as f64) * (1.0 - p) / p).round() as i64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn speed_categories() {
+        assert_eq!(speed_for_clock(60, 0), "bullet");
+        assert_eq!(speed_for_clock(60, 3), "blitz");
+        assert_eq!(speed_for_clock(300, 0), "blitz");
+        assert_eq!(speed_for_clock(600, 0), "rapid");
+        assert_eq!(speed_for_clock(600, 5), "rapid");
+    }
+
+    #[test]
+    fn unrated_speeds_have_no_perf() {
+        let mut perfs = LichessPerfs::default();
+        assert!(perf_for_speed(&perfs, "blitz").is_none());
+        perfs.blitz = LichessPerf { games: 12, rating: 1650, rd: 60, prog: 4 };
+        assert_eq!(perf_for_speed(&perfs, "blitz").map(|p| p.rating), Some(1650));
+        assert!(perf_for_speed(&perfs, "rapid").is_none());
+    }
+
+    #[test]
+    fn equal_ratings_are_even() {
+        assert!((win_probability(1500, 1500) - 0.5).abs() < f64::EPSILON);
+        assert_eq!(fair_opponent_sats(1000, win_probability(1500, 1500)), 1000);
+    }
+
+    #[test]
+    fn stronger_player_gives_odds() {
+        // 400 points stronger is expected to score ~0.91
+        let p = win_probability(1900, 1500);
+        assert!(p > 0.9 && p < 0.92);
+        assert_eq!(fair_opponent_sats(3000, p), 300);
+        // the weaker side puts up more than the stake it plays against
+        assert!(fair_opponent_sats(3000, win_probability(1500, 1900)) > 3000);
+    }
+}
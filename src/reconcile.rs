@@ -0,0 +1,268 @@
+use std::collections::HashMap;
+use std::time::Duration;
+use chrono::{NaiveDateTime, Utc};
+use sqlx::{Pool, Postgres};
+use tracing::{error, info, info_span, warn, Instrument};
+use crate::admin::{solvency, SolvencyReport};
+use crate::config::{AppConfig, LndConfig};
+use crate::errors::AppResult;
+use crate::lightning::invoices::list_invoices;
+use crate::lightning::payment::list_payments;
+use crate::metrics::{DISCREPANCIES, SOLVENCY};
+use crate::models::{LndInvoice, PaymentUpdate, Transaction};
+
+const RECONCILE_INTERVAL_SECS: u64 = 3600;
+const LND_PAGE_SIZE: u64 = 1000;
+
+// every kind of discrepancy, open counts are exported per kind
+pub const KINDS: [&str; 9] = [
+    "insolvent",
+    "invoice_missing",
+    "invoice_not_settled",
+    "invoice_amount_mismatch",
+    "invoice_not_credited",
+    "withdrawal_missing",
+    "withdrawal_not_paid",
+    "withdrawal_amount_mismatch",
+    "withdrawal_paid_not_settled"
+];
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Finding {
+    pub kind: &'static str,
+    pub transaction_id: Option<i32>,
+    pub expected: Option<i64>,
+    pub actual: Option<i64>,
+    pub detail: String
+}
+
+impl Finding {
+    fn transaction(kind: &'static str, transaction: &Transaction, expected: Option<i64>, actual: Option<i64>, detail: String) -> Finding {
+        Finding { kind, transaction_id: Some(transaction.transaction_id), expected, actual, detail }
+    }
+}
+
+fn sats(value: &str) -> i64 {
+    value.parse::<i64>().unwrap_or(0)
+}
+
+pub fn solvency_findings(report: &SolvencyReport) -> Vec<Finding> {
+    if report.surplus >= 0 {
+        return vec![]
+    }
+    vec![Finding {
+        kind: "insolvent",
+        transaction_id: None,
+        expected: Some(report.liabilities),
+        actual: Some(report.assets),
+        detail: format!("node holds {} sats less than users are owed", -report.surplus)
+    }]
+}
+
+// compares our invoices and withdrawals with the node's, invoices are keyed by payment_addr
+// and payments by payment_hash. withdrawal amounts are stored negative
+pub fn find_discrepancies(transactions: &[Transaction], invoices: &HashMap<String, LndInvoice>, payments: &HashMap<String, PaymentUpdate>) -> Vec<Finding> {
+    let mut findings = vec![];
+    for t in transactions {
+        match t.ttype.as_str() {
+            "invoice" => {
+                let invoice = t.payment_addr.as_ref().and_then(|addr| invoices.get(addr));
+                match (t.state.as_str(), invoice) {
+                    ("SETTLED", None) => findings.push(Finding::transaction("invoice_missing", t, Some(t.amount), None, "settled invoice not found on the node".to_string())),
+                    ("SETTLED", Some(i)) if i.state != "SETTLED" => findings.push(Finding::transaction("invoice_not_settled", t, Some(t.amount), Some(0), format!("node invoice is {}", i.state))),
+                    ("SETTLED", Some(i)) if sats(&i.amt_paid_sat) != t.amount => findings.push(Finding::transaction("invoice_amount_mismatch", t, Some(t.amount), Some(sats(&i.amt_paid_sat)), "credited amount differs from amount paid".to_string())),
+                    ("OPEN", Some(i)) if i.state == "SETTLED" => findings.push(Finding::transaction("invoice_not_credited", t, Some(sats(&i.amt_paid_sat)), Some(0), "invoice paid on the node but never credited".to_string())),
+                    _ => ()
+                }
+            },
+            "withdrawal" => {
+                let payment = t.payment_hash.as_ref().and_then(|hash| payments.get(hash));
+                match (t.state.as_str(), payment) {
+                    ("SETTLED", None) => findings.push(Finding::transaction("withdrawal_missing", t, Some(-t.amount), None, "settled withdrawal not found on the node".to_string())),
+                    ("SETTLED", Some(p)) if p.status != "SUCCEEDED" => findings.push(Finding::transaction("withdrawal_not_paid", t, Some(-t.amount), Some(0), format!("node payment is {}", p.status))),
+                    ("SETTLED", Some(p)) if sats(&p.value_sat) != -t.amount => findings.push(Finding::transaction("withdrawal_amount_mismatch", t, Some(-t.amount), Some(sats(&p.value_sat)), "debited amount differs from amount sent".to_string())),
+                    (state, Some(p)) if state != "SETTLED" && p.status == "SUCCEEDED" => findings.push(Finding::transaction("withdrawal_paid_not_settled", t, Some(0), Some(sats(&p.value_sat)), format!("payment succeeded but withdrawal is {state}"))),
+                    _ => ()
+                }
+            },
+            _ => ()
+        }
+    }
+    findings
+}
+
+async fn all_invoices(lnd: &LndConfig) -> AppResult<HashMap<String, LndInvoice>> {
+    let mut invoices = HashMap::new();
+    let mut offset = 0;
+    loop {
+        let page = list_invoices(lnd, offset, LND_PAGE_SIZE).await?;
+        if page.invoices.is_empty() {
+            return Ok(invoices)
+        }
+        offset = page.last_index_offset.parse::<u64>().unwrap_or(u64::MAX);
+        invoices.extend(page.invoices.into_iter().map(|i| (i.payment_addr.clone(), i)));
+    }
+}
+
+async fn all_payments(lnd: &LndConfig) -> AppResult<HashMap<String, PaymentUpdate>> {
+    let mut payments = HashMap::new();
+    let mut offset = 0;
+    loop {
+        let page = list_payments(lnd, offset, LND_PAGE_SIZE).await?;
+        if page.payments.is_empty() {
+            return Ok(payments)
+        }
+        offset = page.last_index_offset.parse::<u64>().unwrap_or(u64::MAX);
+        payments.extend(page.payments.into_iter().map(|p| (p.payment_hash.clone(), p)));
+    }
+}
+
+// refreshes the open discrepancies and resolves the ones that weren't found again,
+// returns the findings of this run
+pub async fn reconcile(pool: &Pool<Postgres>, app_config: &AppConfig) -> AppResult<Vec<Finding>> {
+    let run_started = Utc::now().naive_utc();
+    let report = solvency(pool, app_config).await?;
+    let transactions = sqlx::query_as::<_,Transaction>("SELECT * FROM lightningchess_transaction WHERE ttype IN ('invoice', 'withdrawal') AND state <> 'CANCELED'")
+        .fetch_all(pool).await?;
+    let (invoices, payments) = tokio::try_join!(all_invoices(&app_config.lnd), all_payments(&app_config.lnd))?;
+
+    let mut findings = solvency_findings(&report);
+    findings.extend(find_discrepancies(&transactions, &invoices, &payments));
+    record_findings(pool, &findings, run_started).await?;
+
+    SOLVENCY.with_label_values(&["liabilities"]).set(report.liabilities);
+    SOLVENCY.with_label_values(&["assets"]).set(report.assets);
+    SOLVENCY.with_label_values(&["surplus"]).set(report.surplus);
+    for kind in KINDS {
+        let open = findings.iter().filter(|f| f.kind == kind).count();
+        DISCREPANCIES.with_label_values(&[kind]).set(open as i64);
+    }
+    Ok(findings)
+}
+
+async fn record_findings(pool: &Pool<Postgres>, findings: &[Finding], run_started: NaiveDateTime) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    for f in findings {
+        sqlx::query("INSERT INTO reconciliation_discrepancy (kind, transaction_id, expected, actual, detail, first_seen, last_seen) VALUES ($1, $2, $3, $4, $5, $6, $6) \
+            ON CONFLICT (kind, COALESCE(transaction_id, 0)) WHERE resolved_on IS NULL DO UPDATE SET expected=$3, actual=$4, detail=$5, last_seen=$6")
+            .bind(f.kind)
+            .bind(f.transaction_id)
+            .bind(f.expected)
+            .bind(f.actual)
+            .bind(&f.detail)
+            .bind(run_started)
+            .execute(&mut tx).await?;
+    }
+    let resolved = sqlx::query("UPDATE reconciliation_discrepancy SET resolved_on=$1 WHERE resolved_on IS NULL AND last_seen < $1")
+        .bind(run_started)
+        .execute(&mut tx).await?;
+    tx.commit().await?;
+    if resolved.rows_affected() > 0 {
+        info!(resolved = resolved.rows_affected(), "discrepancies resolved");
+    }
+    Ok(())
+}
+
+pub async fn reconcile_job(pool: Pool<Postgres>, config: AppConfig) {
+    let mut interval = tokio::time::interval(Duration::from_secs(RECONCILE_INTERVAL_SECS));
+    loop {
+        interval.tick().await;
+        async {
+            match reconcile(&pool, &config).await {
+                Ok(findings) if findings.is_empty() => info!("books reconcile with the node"),
+                Ok(findings) => {
+                    for f in findings {
+                        warn!(kind = f.kind, transaction_id = ?f.transaction_id, expected = ?f.expected, actual = ?f.actual, detail = %f.detail, "reconciliation discrepancy");
+                    }
+                },
+                Err(e) => error!(error = %e, "error reconciling with the node")
+            }
+        }.instrument(info_span!("reconcile_job")).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::admin::solvency_report;
+
+    fn transaction(transaction_id: i32, ttype: &str, state: &str, amount: i64) -> Transaction {
+        Transaction {
+            transaction_id,
+            username: "alice".to_string(),
+            ttype: ttype.to_string(),
+            detail: String::new(),
+            amount,
+            state: state.to_string(),
+            preimage: None,
+            payment_addr: Some(format!("addr{transaction_id}")),
+            payment_request: None,
+            payment_hash: Some(format!("hash{transaction_id}")),
+            lichess_challenge_id: None,
+            release_after: None,
+            created_on: None
+        }
+    }
+
+    fn invoice(transaction_id: i32, state: &str, paid: i64) -> (String, LndInvoice) {
+        (format!("addr{transaction_id}"), LndInvoice { payment_addr: format!("addr{transaction_id}"), state: state.to_string(), amt_paid_sat: paid.to_string() })
+    }
+
+    fn payment(transaction_id: i32, status: &str, sent: i64) -> (String, PaymentUpdate) {
+        (format!("hash{transaction_id}"), PaymentUpdate { payment_hash: format!("hash{transaction_id}"), status: status.to_string(), value_sat: sent.to_string(), fee_sat: "0".to_string(), failure_reason: String::new() })
+    }
+
+    fn kinds(findings: Vec<Finding>) -> Vec<(&'static str, Option<i32>)> {
+        findings.into_iter().map(|f| (f.kind, f.transaction_id)).collect()
+    }
+
+    #[test]
+    fn matching_books_have_no_findings() {
+        let transactions = vec![transaction(1, "invoice", "SETTLED", 500), transaction(2, "withdrawal", "SETTLED", -200), transaction(3, "invoice", "OPEN", 0)];
+        let invoices = HashMap::from([invoice(1, "SETTLED", 500), invoice(3, "OPEN", 0)]);
+        let payments = HashMap::from([payment(2, "SUCCEEDED", 200)]);
+        assert!(find_discrepancies(&transactions, &invoices, &payments).is_empty());
+    }
+
+    #[test]
+    fn invoice_discrepancies() {
+        let transactions = vec![
+            transaction(1, "invoice", "SETTLED", 500),
+            transaction(2, "invoice", "SETTLED", 500),
+            transaction(3, "invoice", "SETTLED", 500),
+            transaction(4, "invoice", "OPEN", 0)
+        ];
+        let invoices = HashMap::from([invoice(2, "CANCELED", 0), invoice(3, "SETTLED", 400), invoice(4, "SETTLED", 300)]);
+        assert_eq!(kinds(find_discrepancies(&transactions, &invoices, &HashMap::new())), vec![
+            ("invoice_missing", Some(1)),
+            ("invoice_not_settled", Some(2)),
+            ("invoice_amount_mismatch", Some(3)),
+            ("invoice_not_credited", Some(4))
+        ]);
+    }
+
+    #[test]
+    fn withdrawal_discrepancies() {
+        let transactions = vec![
+            transaction(1, "withdrawal", "SETTLED", -200),
+            transaction(2, "withdrawal", "SETTLED", -200),
+            transaction(3, "withdrawal", "SETTLED", -200),
+            transaction(4, "withdrawal", "FAILED", -200),
+            transaction(5, "withdrawal", "OPEN", -200)
+        ];
+        let payments = HashMap::from([payment(2, "FAILED", 200), payment(3, "SUCCEEDED", 250), payment(4, "SUCCEEDED", 200), payment(5, "IN_FLIGHT", 200)]);
+        assert_eq!(kinds(find_discrepancies(&transactions, &HashMap::new(), &payments)), vec![
+            ("withdrawal_missing", Some(1)),
+            ("withdrawal_not_paid", Some(2)),
+            ("withdrawal_amount_mismatch", Some(3)),
+            ("withdrawal_paid_not_settled", Some(4))
+        ]);
+    }
+
+    #[test]
+    fn shortfall_is_flagged() {
+        assert!(solvency_findings(&solvency_report(1_000, 0, 0, 0, 1_000, 0)).is_empty());
+        let findings = solvency_findings(&solvency_report(1_000, 0, 500, 0, 1_200, 0));
+        assert_eq!(findings, vec![Finding { kind: "insolvent", transaction_id: None, expected: Some(1_500), actual: Some(1_200), detail: "node holds 300 sats less than users are owed".to_string() }]);
+    }
+}
@@ -0,0 +1,249 @@
+use std::time::Duration;
+use chrono::{NaiveDateTime, Utc};
+use cookie::SameSite;
+use moka::future::Cache;
+use openssl::rand::rand_bytes;
+use rocket::http::Cookie;
+use sha2::{Digest, Sha256};
+use sqlx::{FromRow, Pool, Postgres};
+use tracing::{info, warn};
+use crate::config::{AppConfig, SessionConfig};
+use crate::crypto::{decrypt, encrypt};
+use crate::errors::{AppError, AppResult};
+use crate::ledger::Actor;
+use crate::models::{Account, SessionInfo, TokenResponse, User};
+use crate::settlement::void_waiting;
+
+pub const SESSION_COOKIE: &str = "llchess_session";
+// the lichess token itself, from before sessions. migrated to a session on first use
+pub const LEGACY_TOKEN_COOKIE: &str = "llchess_access_token";
+
+#[derive(FromRow)]
+struct SessionRow {
+    user_id: i32,
+    username: String,
+    lichess_token: String,
+    expires_on: NaiveDateTime
+}
+
+// opaque to the client, 32 random bytes
+fn new_session_id() -> AppResult<String> {
+    let mut bytes = [0u8; 32];
+    rand_bytes(&mut bytes).map_err(|e| AppError::Internal(format!("session id: {e}")))?;
+    Ok(base64::encode_config(bytes, base64::URL_SAFE_NO_PAD))
+}
+
+pub fn session_cookie(session_id: String, session: &SessionConfig) -> Cookie<'static> {
+    Cookie::build(SESSION_COOKIE, session_id)
+        .same_site(SameSite::None)
+        .secure(true)
+        .http_only(true)
+        .max_age(cookie::time::Duration::days(session.ttl_days))
+        .finish()
+}
+
+// keyed by session hash, supports invalidating every session of a user
+pub fn session_cache(session: &SessionConfig) -> Cache<String, User> {
+    Cache::builder()
+        .max_capacity(10_000)
+        .time_to_live(Duration::from_secs(session.cache_ttl_secs))
+        .time_to_idle(Duration::from_secs(session.cache_idle_secs))
+        .support_invalidation_closures()
+        .build()
+}
+
+pub fn session_hash(session_id: &str) -> String {
+    hex::encode(Sha256::digest(session_id.as_bytes()))
+}
+
+// creates or updates the user and starts a session, returns the session id for the cookie
+// the session ends with the token when lichess expires it first
+pub async fn create_session(pool: &Pool<Postgres>, app_config: &AppConfig, account: &Account, token: &TokenResponse) -> AppResult<String> {
+    let encrypted = encrypt(&app_config.encryption, &token.access_token).map_err(|e| AppError::Internal(format!("token encryption: {e}")))?;
+    let session_id = new_session_id()?;
+    let now = Utc::now().naive_utc();
+    let token_expires_on = token.expires_in.map(|s| now + chrono::Duration::seconds(s));
+    let session_expires_on = now + chrono::Duration::days(app_config.session.ttl_days);
+    let expires_on = token_expires_on.map_or(session_expires_on, |t| t.min(session_expires_on));
+
+    let mut tx = pool.begin().await?;
+    let user_id: i32 = sqlx::query_scalar("INSERT INTO users (lichess_id, username) VALUES ($1, $2) \
+        ON CONFLICT (lichess_id) DO UPDATE SET username=$2, last_seen=$3 RETURNING user_id")
+        .bind(account.id.to_lowercase())
+        .bind(&account.username)
+        .bind(now)
+        .fetch_one(&mut tx).await?;
+    // old sessions are kept until they expire so they can be listed, then dropped here
+    sqlx::query("DELETE FROM user_session WHERE user_id=$1 AND (expires_on < $2 OR revoked_on IS NOT NULL)")
+        .bind(user_id)
+        .bind(now)
+        .execute(&mut tx).await?;
+    sqlx::query("INSERT INTO user_session (session_hash, user_id, lichess_token, created_on, last_seen, expires_on, token_scopes, token_expires_on) \
+        VALUES ($1, $2, $3, $4, $4, $5, $6, $7)")
+        .bind(session_hash(&session_id))
+        .bind(user_id)
+        .bind(encrypted)
+        .bind(now)
+        .bind(expires_on)
+        .bind(&token.scope)
+        .bind(token_expires_on)
+        .execute(&mut tx).await?;
+    tx.commit().await?;
+
+    info!(user_id, username = %account.username, "session created");
+    Ok(session_id)
+}
+
+// the user behind a live session, None when it's unknown, expired or revoked
+pub async fn load_session(pool: &Pool<Postgres>, app_config: &AppConfig, hash: &str) -> AppResult<Option<User>> {
+    let now = Utc::now().naive_utc();
+    let row = sqlx::query_as::<_,SessionRow>("SELECT s.user_id, u.username, s.lichess_token, s.expires_on FROM user_session s \
+        JOIN users u ON u.user_id=s.user_id WHERE s.session_hash=$1 AND s.revoked_on IS NULL AND s.expires_on > $2")
+        .bind(hash)
+        .bind(now)
+        .fetch_optional(pool).await?;
+    let row = match row {
+        Some(r) => r,
+        None => return Ok(None)
+    };
+    let access_token = match decrypt(&app_config.encryption, &row.lichess_token) {
+        Ok(t) => t,
+        Err(e) => {
+            // its key was dropped before being rotated, the user has to log in again
+            warn!(error = %e, user_id = row.user_id, "undecryptable session token");
+            return Ok(None)
+        }
+    };
+
+    sqlx::query("UPDATE user_session SET last_seen=$1 WHERE session_hash=$2")
+        .bind(now)
+        .bind(hash)
+        .execute(pool).await?;
+    sqlx::query("UPDATE users SET last_seen=$1 WHERE user_id=$2")
+        .bind(now)
+        .bind(row.user_id)
+        .execute(pool).await?;
+
+    Ok(Some(User {
+        user_id: row.user_id,
+        username: row.username,
+        access_token,
+        session_hash: hash.to_string(),
+        session_expires_on: row.expires_on
+    }))
+}
+
+// what logging out did to the challenges waiting on the session's token
+#[derive(Default)]
+pub struct TokenRelease {
+    // false when another live session uses the same token
+    pub revoke_at_lichess: bool,
+    pub moved: u64,
+    pub voided: u64
+}
+
+// waiting challenges are accepted with the challenger's token, so before it's revoked they move to the
+// token of another live session of the user, or are voided and their stake refunded when there is none.
+// call after the session itself is revoked
+pub async fn release_token(pool: &Pool<Postgres>, app_config: &AppConfig, actor: &Actor, user: &User) -> AppResult<TokenRelease> {
+    let others: Vec<String> = sqlx::query_scalar("SELECT lichess_token FROM user_session WHERE user_id=$1 AND session_hash<>$2 \
+        AND revoked_on IS NULL AND expires_on > $3 ORDER BY last_seen DESC")
+        .bind(user.user_id)
+        .bind(&user.session_hash)
+        .bind(Utc::now().naive_utc())
+        .fetch_all(pool).await?;
+    let mut replacement = None;
+    for token in others {
+        match decrypt(&app_config.encryption, &token) {
+            // sessions migrated from the same legacy cookie share its token, it keeps working for them
+            Ok(t) if t == user.access_token => return Ok(TokenRelease::default()),
+            Ok(_) if replacement.is_none() => replacement = Some(token),
+            _ => ()
+        }
+    }
+
+    let mut release = TokenRelease { revoke_at_lichess: true, ..Default::default() };
+    let waiting: Vec<(i32, String)> = sqlx::query_as("SELECT id, challenger_token FROM challenge \
+        WHERE username=$1 AND status='WAITING FOR ACCEPTANCE' AND challenger_token IS NOT NULL")
+        .bind(&user.username)
+        .fetch_all(pool).await?;
+    for (id, token) in waiting {
+        if decrypt(&app_config.encryption, &token).ok().as_deref() != Some(user.access_token.as_str()) {
+            continue
+        }
+        match &replacement {
+            Some(r) => {
+                let updated = sqlx::query("UPDATE challenge SET challenger_token=$1 WHERE id=$2 AND status='WAITING FOR ACCEPTANCE'")
+                    .bind(r)
+                    .bind(id)
+                    .execute(pool).await?;
+                release.moved += updated.rows_affected();
+            },
+            None => {
+                if void_waiting(pool, actor, id).await?.is_some() {
+                    release.voided += 1;
+                }
+            }
+        }
+    }
+    Ok(release)
+}
+
+pub async fn revoke_session(pool: &Pool<Postgres>, hash: &str) -> Result<(), sqlx::Error> {
+    sqlx::query("UPDATE user_session SET revoked_on=$1 WHERE session_hash=$2 AND revoked_on IS NULL")
+        .bind(Utc::now().naive_utc())
+        .bind(hash)
+        .execute(pool).await?;
+    Ok(())
+}
+
+// ends the user's sessions holding a token lichess refused, so they log in again for a new one.
+// returns how many sessions were revoked
+pub async fn revoke_rejected_token(pool: &Pool<Postgres>, app_config: &AppConfig, cache: &Cache<String, User>, username: &str, token: &str) -> AppResult<u64> {
+    let sessions: Vec<(String, String)> = sqlx::query_as("SELECT s.session_hash, s.lichess_token FROM user_session s JOIN users u ON u.user_id=s.user_id \
+        WHERE LOWER(u.username)=LOWER($1) AND s.revoked_on IS NULL")
+        .bind(username)
+        .fetch_all(pool).await?;
+    let mut revoked = 0;
+    for (hash, encrypted) in sessions {
+        if decrypt(&app_config.encryption, &encrypted).ok().as_deref() == Some(token) {
+            revoke_session(pool, &hash).await?;
+            cache.invalidate(&hash).await;
+            revoked += 1;
+        }
+    }
+    warn!(username, revoked, "lichess token rejected, sessions revoked");
+    Ok(revoked)
+}
+
+// returns how many sessions were revoked
+pub async fn revoke_all_sessions(pool: &Pool<Postgres>, user_id: i32) -> Result<u64, sqlx::Error> {
+    let revoked = sqlx::query("UPDATE user_session SET revoked_on=$1 WHERE user_id=$2 AND revoked_on IS NULL")
+        .bind(Utc::now().naive_utc())
+        .bind(user_id)
+        .execute(pool).await?;
+    Ok(revoked.rows_affected())
+}
+
+pub async fn list_sessions(pool: &Pool<Postgres>, user: &User) -> Result<Vec<SessionInfo>, sqlx::Error> {
+    sqlx::query_as::<_,SessionInfo>("SELECT created_on, last_seen, expires_on, session_hash=$2 AS current FROM user_session \
+        WHERE user_id=$1 AND revoked_on IS NULL AND expires_on > $3 ORDER BY last_seen DESC")
+        .bind(user.user_id)
+        .bind(&user.session_hash)
+        .bind(Utc::now().naive_utc())
+        .fetch_all(pool).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn session_ids_are_random_and_hashed() {
+        let id = new_session_id().unwrap();
+        assert_eq!(id.len(), 43);
+        assert_ne!(id, new_session_id().unwrap());
+        assert_eq!(session_hash(&id).len(), 64);
+        assert_eq!(session_hash(&id), session_hash(&id));
+    }
+}
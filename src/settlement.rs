@@ -0,0 +1,462 @@
+use std::collections::HashMap;
+use std::time::Duration;
+use chrono::Utc;
+use tracing::{error, info, info_span, warn, Instrument};
+use sqlx::{Pool, Postgres};
+use crate::config::{AppConfig, LichessConfig};
+use crate::disputes::{lock_challenge, under_dispute};
+use crate::games::record_game;
+use crate::ledger::{change_balance, Actor, BalanceChange};
+use crate::lichess::client::{export_game, fetch_lichess_user};
+use crate::metrics::CHALLENGES;
+use crate::models::{Challenge, LichessExportGameResponse, LichessUser, Transaction};
+
+const SETTLEMENT_INTERVAL_SECS: u64 = 300;
+
+// lichess game statuses for games that are still being played
+pub const UNFINISHED_STATUSES: [&str; 2] = ["created", "started"];
+// lichess game statuses for games that never got going, stakes are refunded
+const ABORTED_STATUSES: [&str; 2] = ["aborted", "noStart"];
+
+// amounts credited to each player when the game is over, winner is the lichess winning color
+// the winner takes both stakes, on a draw or abort everyone gets their own stake back
+pub fn payouts(challenge: &Challenge, winner: Option<&str>) -> Vec<(String, i64)> {
+    let sats = challenge.sats.unwrap_or(0);
+    let opponent_sats = challenge.opponent_sats.unwrap_or(sats);
+    match winner {
+        Some(color) if Some(color) == challenge.color.as_deref() => vec![(challenge.username.clone(), sats + opponent_sats)],
+        Some(_) => vec![(challenge.opp_username.clone(), sats + opponent_sats)],
+        None => vec![(challenge.username.clone(), sats), (challenge.opp_username.clone(), opponent_sats)]
+    }
+}
+
+// held payouts of a draw are one player's own stake, a winner's is the whole pot
+pub fn drawn(challenge: &Challenge, payout: &Transaction) -> bool {
+    let sats = challenge.sats.unwrap_or(0);
+    payout.amount < sats + challenge.opponent_sats.unwrap_or(sats)
+}
+
+// what each player gets back when held payouts are refunded
+pub fn refunds(challenge: &Challenge, payout: &Transaction) -> Vec<(String, i64)> {
+    if drawn(challenge, payout) {
+        vec![(payout.username.clone(), payout.amount)]
+    } else {
+        payouts(challenge, None)
+    }
+}
+
+pub async fn settle_challenge(pool: &Pool<Postgres>, actor: &Actor, challenge: &Challenge, game: &LichessExportGameResponse, payout_hold_hours: i64) -> Result<bool, sqlx::Error> {
+    // recorded first, a failure leaves the challenge for the next settlement attempt
+    record_game(pool, challenge.id, game).await?;
+    if ABORTED_STATUSES.contains(&game.status.as_str()) {
+        close_challenge(pool, actor, challenge, "ABORTED", None, payout_hold_hours).await
+    } else {
+        close_challenge(pool, actor, challenge, "FINISHED", game.winner.as_deref(), payout_hold_hours).await
+    }
+}
+
+// moves an accepted or started challenge to its final status and pays out the escrowed stakes,
+// returns false when the challenge was already closed or an open dispute freezes it
+pub async fn close_challenge(pool: &Pool<Postgres>, actor: &Actor, challenge: &Challenge, status: &str, winner: Option<&str>, payout_hold_hours: i64) -> Result<bool, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    match lock_challenge(&mut tx, challenge.id).await? {
+        Some((_, None)) => (),
+        Some((_, Some(dispute))) => {
+            info!(challenge_id = challenge.id, dispute_id = dispute.dispute_id, "challenge is disputed, not settling");
+            return Ok(false)
+        },
+        None => return Ok(false)
+    }
+    let closed = settle_stakes(&mut tx, actor, challenge, status, winner, payout_hold_hours).await?;
+    tx.commit().await?;
+    Ok(closed)
+}
+
+// the payouts of close_challenge inside the caller's transaction, which has checked for disputes
+pub async fn settle_stakes(tx: &mut sqlx::Transaction<'_, Postgres>, actor: &Actor, challenge: &Challenge, status: &str, winner: Option<&str>, payout_hold_hours: i64) -> Result<bool, sqlx::Error> {
+    let ttype = if status == "FINISHED" { "challenge payout" } else { "challenge refund" };
+
+    // only settle a challenge once
+    let updated = sqlx::query("UPDATE challenge SET status=$1 WHERE id=$2 AND status IN ('ACCEPTED', 'STARTED')")
+        .bind(status)
+        .bind(challenge.id)
+        .execute(&mut *tx).await?;
+    if updated.rows_affected() == 0 {
+        info!(challenge_id = challenge.id, "challenge already settled");
+        return Ok(false)
+    }
+
+    for (username, amount) in payouts(challenge, winner) {
+        let other = if username == challenge.username { &challenge.opp_username } else { &challenge.username };
+        if status == "FINISHED" {
+            // results of played games, draws too, are held until the fair play checks pass
+            let release_after = Utc::now().naive_utc() + chrono::Duration::hours(payout_hold_hours);
+            sqlx::query("INSERT INTO lightningchess_transaction (username, ttype, detail, amount, state, lichess_challenge_id, release_after) VALUES ($1, $2, $3, $4, $5, $6, $7)")
+                .bind(&username)
+                .bind(ttype)
+                .bind(format!("challenge vs {}", other))
+                .bind(amount)
+                .bind("HELD")
+                .bind(&challenge.lichess_challenge_id)
+                .bind(release_after)
+                .execute(&mut *tx).await?;
+        } else {
+            credit(tx, actor, challenge, &username, ttype, other, amount).await?;
+        }
+    }
+
+    CHALLENGES.with_label_values(&[&status.to_lowercase()]).inc();
+    info!(challenge_id = challenge.id, status, "settled challenge");
+    Ok(true)
+}
+
+pub async fn credit(tx: &mut sqlx::Transaction<'_, Postgres>, actor: &Actor, challenge: &Challenge, username: &str, ttype: &str, other: &str, amount: i64) -> Result<(), sqlx::Error> {
+    let transaction = sqlx::query_as::<_,Transaction>("INSERT INTO lightningchess_transaction (username, ttype, detail, amount, state, lichess_challenge_id) VALUES ($1, $2, $3, $4, $5, $6) RETURNING *")
+        .bind(username)
+        .bind(ttype)
+        .bind(format!("challenge vs {}", other))
+        .bind(amount)
+        .bind("SETTLED")
+        .bind(&challenge.lichess_challenge_id)
+        .fetch_one(&mut *tx).await?;
+    let change = BalanceChange::new(username, amount, ttype)
+        .challenge(challenge.id)
+        .transaction(transaction.transaction_id);
+    change_balance(tx, actor, change).await?;
+    Ok(())
+}
+
+// voids a challenge nobody accepted yet and gives the challenger their escrowed stake back,
+// None when it's no longer waiting for acceptance
+pub async fn void_waiting(pool: &Pool<Postgres>, actor: &Actor, challenge_id: i32) -> Result<Option<Challenge>, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    let voided = sqlx::query_as::<_,Challenge>("UPDATE challenge SET status='VOIDED' WHERE id=$1 AND status='WAITING FOR ACCEPTANCE' RETURNING *")
+        .bind(challenge_id)
+        .fetch_optional(&mut tx).await?;
+    let challenge = match voided {
+        Some(c) => c,
+        None => return Ok(None)
+    };
+    credit(&mut tx, actor, &challenge, &challenge.username, "challenge refund", &challenge.opp_username, challenge.sats.unwrap_or_default()).await?;
+    tx.commit().await?;
+    CHALLENGES.with_label_values(&["voided"]).inc();
+    info!(challenge_id, "voided challenge");
+    Ok(Some(challenge))
+}
+
+fn flagged(lichess_user: &LichessUser) -> bool {
+    lichess_user.tos_violation || lichess_user.disabled
+}
+
+// what to do with a held payout given the latest lichess account status of both players.
+// a flagged loser taints the game so it's refunded, a drawn game has no winner to reverse
+pub fn hold_outcome(winner_flagged: bool, loser_flagged: bool, draw: bool, hold_expired: bool) -> HoldOutcome {
+    match (winner_flagged, loser_flagged) {
+        (false, false) if hold_expired => HoldOutcome::Release,
+        (false, false) => HoldOutcome::Wait,
+        (true, false) if !draw => HoldOutcome::Reverse,
+        _ => HoldOutcome::Refund
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum HoldOutcome {
+    Wait,
+    Release,
+    // the flagged winner loses the game, the pot goes to the other player
+    Reverse,
+    // the game doesn't count, everyone gets their stake back
+    Refund
+}
+
+// applies the outcome to held winnings unless an open dispute freezes them
+pub async fn resolve_held_payout(pool: &Pool<Postgres>, actor: &Actor, payout: &Transaction, challenge: &Challenge, outcome: HoldOutcome) -> Result<(), sqlx::Error> {
+    if outcome == HoldOutcome::Wait {
+        return Ok(())
+    }
+    let mut tx = pool.begin().await?;
+    if let Some((_, Some(dispute))) = lock_challenge(&mut tx, challenge.id).await? {
+        info!(transaction_id = payout.transaction_id, dispute_id = dispute.dispute_id, "held payout is disputed, not resolving");
+        return Ok(())
+    }
+    move_held_payout(&mut tx, actor, payout, challenge, outcome).await?;
+    tx.commit().await?;
+    Ok(())
+}
+
+// resolve_held_payout inside the caller's transaction, which has checked for disputes
+pub async fn move_held_payout(tx: &mut sqlx::Transaction<'_, Postgres>, actor: &Actor, payout: &Transaction, challenge: &Challenge, outcome: HoldOutcome) -> Result<(), sqlx::Error> {
+    let loser = if payout.username == challenge.username { &challenge.opp_username } else { &challenge.username };
+
+    let (state, challenge_status) = match outcome {
+        HoldOutcome::Wait => return Ok(()),
+        HoldOutcome::Release => ("SETTLED", None),
+        HoldOutcome::Reverse => ("REVERSED", Some("REVERSED")),
+        HoldOutcome::Refund => ("REVERSED", Some("REFUNDED"))
+    };
+
+    let updated = sqlx::query("UPDATE lightningchess_transaction SET state=$1 WHERE transaction_id=$2 AND state='HELD'")
+        .bind(state)
+        .bind(payout.transaction_id)
+        .execute(&mut *tx).await?;
+    if updated.rows_affected() == 0 {
+        return Ok(())
+    }
+
+    match outcome {
+        HoldOutcome::Release => {
+            let change = BalanceChange::new(&payout.username, payout.amount, "release held payout")
+                .challenge(challenge.id)
+                .transaction(payout.transaction_id);
+            change_balance(tx, actor, change).await?;
+        },
+        HoldOutcome::Reverse => {
+            credit(tx, actor, challenge, loser, "challenge payout", &payout.username, payout.amount).await?;
+        },
+        _ => {
+            for (username, amount) in refunds(challenge, payout) {
+                let other = if username == challenge.username { &challenge.opp_username } else { &challenge.username };
+                credit(tx, actor, challenge, &username, "challenge refund", other, amount).await?;
+            }
+        }
+    }
+
+    if let Some(status) = challenge_status {
+        sqlx::query("UPDATE challenge SET status=$1 WHERE id=$2")
+            .bind(status)
+            .bind(challenge.id)
+            .execute(&mut *tx).await?;
+        CHALLENGES.with_label_values(&[&status.to_lowercase()]).inc();
+    }
+    info!(transaction_id = payout.transaction_id, state, "held payout resolved");
+    Ok(())
+}
+
+// whether lichess flagged the account, None when it couldn't be checked. lookups are kept in accounts, failed ones too
+async fn account_flagged(lichess: &LichessConfig, accounts: &mut HashMap<String, Option<bool>>, username: &str) -> Option<bool> {
+    let key = username.to_lowercase();
+    if let Some(flagged) = accounts.get(&key) {
+        return *flagged
+    }
+    let result = fetch_lichess_user(lichess, username).await
+        .map_err(|e| warn!(username, error = %e, "error fetching lichess account"))
+        .ok()
+        .map(|user| flagged(&user));
+    accounts.insert(key, result);
+    result
+}
+
+// re-checks both players of every held payout on lichess, releases clean winnings once the hold expires
+pub async fn payout_hold_job(pool: Pool<Postgres>, config: AppConfig) {
+    let mut interval = tokio::time::interval(Duration::from_secs(600));
+    loop {
+        interval.tick().await;
+        check_held_payouts(&pool, &config.lichess).instrument(info_span!("payout_hold_job")).await;
+    }
+}
+
+async fn check_held_payouts(pool: &Pool<Postgres>, lichess: &LichessConfig) {
+    let held_result = sqlx::query_as::<_,Transaction>("SELECT * FROM lightningchess_transaction WHERE state='HELD'")
+        .fetch_all(pool).await;
+    let held = match held_result {
+        Ok(h) => h,
+        Err(e) => {
+            error!(error = %e, "error loading held payouts");
+            return
+        }
+    };
+
+    // players with several held payouts are looked up once per run
+    let mut accounts = HashMap::new();
+    for payout in held {
+        let challenge_result = sqlx::query_as::<_,Challenge>("SELECT * FROM challenge WHERE lichess_challenge_id=$1")
+            .bind(&payout.lichess_challenge_id)
+            .fetch_one(pool).await;
+        let challenge = match challenge_result {
+            Ok(c) => c,
+            Err(e) => {
+                error!(transaction_id = payout.transaction_id, error = %e, "error loading challenge for held payout");
+                continue
+            }
+        };
+        // saves the lichess lookups, resolve_held_payout checks again under the challenge lock
+        match under_dispute(pool, challenge.id).await {
+            Ok(false) => (),
+            Ok(true) => continue,
+            Err(e) => {
+                error!(transaction_id = payout.transaction_id, error = %e, "error checking for disputes");
+                continue
+            }
+        }
+        let loser = if payout.username == challenge.username { &challenge.opp_username } else { &challenge.username };
+
+        let winner_flagged = account_flagged(lichess, &mut accounts, &payout.username).await;
+        let loser_flagged = account_flagged(lichess, &mut accounts, loser).await;
+        let (winner_flagged, loser_flagged) = match (winner_flagged, loser_flagged) {
+            (Some(w), Some(l)) => (w, l),
+            _ => {
+                warn!(transaction_id = payout.transaction_id, "could not check lichess accounts for held payout");
+                continue
+            }
+        };
+
+        let hold_expired = !matches!(payout.release_after, Some(r) if r > Utc::now().naive_utc());
+        let outcome = hold_outcome(winner_flagged, loser_flagged, drawn(&challenge, &payout), hold_expired);
+        if let Err(e) = resolve_held_payout(pool, &Actor::system("payout_hold_job"), &payout, &challenge, outcome).await {
+            error!(transaction_id = payout.transaction_id, error = %e, "error resolving held payout");
+        }
+    }
+}
+
+// polls lichess for the result of every accepted challenge and pays out finished games.
+// the game events stream settles games as they finish, this catches whatever it missed.
+// challenges nobody accepted in time are voided here too
+pub async fn settlement_job(pool: Pool<Postgres>, config: AppConfig) {
+    let mut interval = tokio::time::interval(Duration::from_secs(SETTLEMENT_INTERVAL_SECS));
+    loop {
+        interval.tick().await;
+        async {
+            settle_finished_games(&pool, &config).await;
+            expire_waiting_challenges(&pool).await;
+        }.instrument(info_span!("settlement_job")).await;
+    }
+}
+
+async fn expire_waiting_challenges(pool: &Pool<Postgres>) {
+    let expired_result: Result<Vec<i32>, sqlx::Error> = sqlx::query_scalar("SELECT id FROM challenge WHERE status='WAITING FOR ACCEPTANCE' \
+        AND created_on + make_interval(secs => COALESCE(expire_after, 1800)) < (now() at time zone 'utc')")
+        .fetch_all(pool).await;
+    let expired = match expired_result {
+        Ok(e) => e,
+        Err(e) => {
+            error!(error = %e, "error loading expired challenges");
+            return
+        }
+    };
+
+    for challenge_id in expired {
+        if let Err(e) = void_waiting(pool, &Actor::system("settlement_job"), challenge_id).await {
+            error!(challenge_id, error = %e, "error voiding expired challenge");
+        }
+    }
+}
+
+async fn settle_finished_games(pool: &Pool<Postgres>, config: &AppConfig) {
+    let challenges_result = sqlx::query_as::<_,Challenge>("SELECT * FROM challenge WHERE status IN ('ACCEPTED', 'STARTED') AND lichess_challenge_id IS NOT NULL")
+        .fetch_all(pool).await;
+    let challenges = match challenges_result {
+        Ok(c) => c,
+        Err(e) => {
+            error!(error = %e, "error loading accepted challenges");
+            return
+        }
+    };
+
+    for challenge in challenges {
+        settle_game(pool, config, &Actor::system("settlement_job"), &challenge).await;
+    }
+}
+
+// looks the challenge's game up on lichess and settles it once it's over
+pub async fn settle_game(pool: &Pool<Postgres>, config: &AppConfig, actor: &Actor, challenge: &Challenge) {
+    let game_id = challenge.lichess_challenge_id.as_deref().unwrap_or_default();
+    let game = match export_game(&config.lichess, game_id).await {
+        Ok(g) => g,
+        Err(e) => {
+            warn!(game_id, error = %e, "error exporting game");
+            return
+        }
+    };
+    if UNFINISHED_STATUSES.contains(&game.status.as_str()) {
+        return
+    }
+    if let Err(e) = settle_challenge(pool, actor, challenge, &game, config.stakes.payout_hold_hours).await {
+        error!(challenge_id = challenge.id, error = %e, "error settling challenge");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::test_challenge;
+
+    fn get_challenge() -> Challenge {
+        Challenge {
+            sats: Some(3000),
+            opponent_sats: Some(1000),
+            status: Some("ACCEPTED".to_string()),
+            lichess_challenge_id: Some("abcd1234".to_string()),
+            ..test_challenge()
+        }
+    }
+
+    #[test]
+    fn challenger_wins_both_stakes() {
+        let payouts = payouts(&get_challenge(), Some("white"));
+        assert_eq!(payouts, vec![("user1".to_string(), 4000)]);
+    }
+
+    #[test]
+    fn opponent_wins_both_stakes() {
+        let payouts = payouts(&get_challenge(), Some("black"));
+        assert_eq!(payouts, vec![("user2".to_string(), 4000)]);
+    }
+
+    #[test]
+    fn draw_refunds_unequal_stakes() {
+        let payouts = payouts(&get_challenge(), None);
+        assert_eq!(payouts, vec![("user1".to_string(), 3000), ("user2".to_string(), 1000)]);
+    }
+
+    #[test]
+    fn clean_winnings_wait_for_hold() {
+        assert_eq!(hold_outcome(false, false, false, false), HoldOutcome::Wait);
+        assert_eq!(hold_outcome(false, false, false, true), HoldOutcome::Release);
+    }
+
+    #[test]
+    fn flagged_winner_is_resolved_during_hold() {
+        assert_eq!(hold_outcome(true, false, false, false), HoldOutcome::Reverse);
+        assert_eq!(hold_outcome(true, true, false, false), HoldOutcome::Refund);
+    }
+
+    #[test]
+    fn flagged_loser_refunds() {
+        assert_eq!(hold_outcome(false, true, false, false), HoldOutcome::Refund);
+        assert_eq!(hold_outcome(false, true, false, true), HoldOutcome::Refund);
+    }
+
+    #[test]
+    fn draws_are_held_and_refunded_when_flagged() {
+        assert_eq!(hold_outcome(false, false, true, false), HoldOutcome::Wait);
+        assert_eq!(hold_outcome(false, false, true, true), HoldOutcome::Release);
+        assert_eq!(hold_outcome(true, false, true, false), HoldOutcome::Refund);
+        assert_eq!(hold_outcome(false, true, true, false), HoldOutcome::Refund);
+    }
+
+    #[test]
+    fn drawn_stakes_refund_their_owner() {
+        let challenge = get_challenge();
+        let mut payout = Transaction {
+            transaction_id: 1,
+            username: "user2".to_string(),
+            ttype: "challenge payout".to_string(),
+            detail: "challenge vs user1".to_string(),
+            amount: 1000,
+            state: "HELD".to_string(),
+            preimage: None,
+            payment_addr: None,
+            payment_request: None,
+            payment_hash: None,
+            lichess_challenge_id: challenge.lichess_challenge_id.clone(),
+            release_after: None,
+            created_on: None
+        };
+        assert!(drawn(&challenge, &payout));
+        assert_eq!(refunds(&challenge, &payout), vec![("user2".to_string(), 1000)]);
+
+        payout.amount = 4000;
+        assert!(!drawn(&challenge, &payout));
+        assert_eq!(refunds(&challenge, &payout), payouts(&challenge, None));
+    }
+}
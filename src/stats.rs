@@ -0,0 +1,197 @@
+use std::collections::HashMap;
+use std::time::Duration;
+use moka::future::Cache;
+use serde::Serialize;
+use sqlx::{FromRow, Pool, Postgres};
+use crate::odds::speed_for_clock;
+
+// stats only change when a game settles, a few minutes of staleness is fine
+const STATS_TTL: Duration = Duration::from_secs(300);
+const HEAD_TO_HEAD_LIMIT: usize = 20;
+
+// stats by lowercased username
+pub struct StatsCache(pub Cache<String, PlayerStats>);
+
+impl Default for StatsCache {
+    fn default() -> Self {
+        StatsCache(Cache::builder().max_capacity(10_000).time_to_live(STATS_TTL).build())
+    }
+}
+
+// a finished game from one player's side, received is what the payouts credited them
+#[derive(FromRow)]
+pub struct PlayedGame {
+    pub opponent: String,
+    pub stake: i64,
+    pub received: i64,
+    pub time_limit: Option<i32>, // seconds
+    pub increment: Option<i32> // seconds
+}
+
+#[derive(Serialize, Default, Clone, Debug, PartialEq, Eq)]
+pub struct Record {
+    pub games: i64,
+    pub wins: i64,
+    pub losses: i64,
+    pub draws: i64,
+    pub net_sats: i64
+}
+
+impl Record {
+    fn add(&mut self, game: &PlayedGame) {
+        let net = game.received - game.stake;
+        self.games += 1;
+        self.net_sats += net;
+        match net {
+            n if n > 0 => self.wins += 1,
+            0 => self.draws += 1,
+            _ => self.losses += 1
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct HeadToHead {
+    pub opponent: String,
+    #[serde(flatten)]
+    pub record: Record
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct PlayerStats {
+    pub username: String,
+    #[serde(flatten)]
+    pub record: Record,
+    pub biggest_win_sats: i64,
+    pub favorite_time_control: Option<String>, // minutes+increment, e.g. 5+3
+    pub favorite_speed: Option<String>,
+    // most played opponents first
+    pub head_to_head: Vec<HeadToHead>
+}
+
+fn time_control(limit: i32, increment: i32) -> String {
+    if limit % 60 == 0 {
+        format!("{}+{increment}", limit / 60)
+    } else {
+        format!("{}+{increment}", limit as f64 / 60.0)
+    }
+}
+
+// most common value, ties go to the one seen first
+fn most_common(values: Vec<String>) -> Option<String> {
+    let mut counts: Vec<(String, usize)> = vec![];
+    for value in values {
+        match counts.iter_mut().find(|(v, _)| *v == value) {
+            Some((_, count)) => *count += 1,
+            None => counts.push((value, 1))
+        }
+    }
+    let max = counts.iter().map(|(_, c)| *c).max()?;
+    counts.into_iter().find(|(_, c)| *c == max).map(|(v, _)| v)
+}
+
+pub fn player_stats(username: &str, games: &[PlayedGame]) -> PlayerStats {
+    let mut record = Record::default();
+    let mut opponents: HashMap<String, Record> = HashMap::new();
+    for game in games {
+        record.add(game);
+        opponents.entry(game.opponent.to_lowercase()).or_default().add(game);
+    }
+
+    let clocks: Vec<(i32, i32)> = games.iter()
+        .filter_map(|g| Some((g.time_limit?, g.increment.unwrap_or(0))))
+        .collect();
+
+    let mut head_to_head: Vec<HeadToHead> = opponents.into_iter()
+        .map(|(opponent, record)| HeadToHead { opponent, record })
+        .collect();
+    head_to_head.sort_by(|a, b| b.record.games.cmp(&a.record.games).then_with(|| a.opponent.cmp(&b.opponent)));
+
+    PlayerStats {
+        username: username.to_string(),
+        record,
+        biggest_win_sats: games.iter().map(|g| g.received - g.stake).max().unwrap_or(0).max(0),
+        favorite_time_control: most_common(clocks.iter().map(|(l, i)| time_control(*l, *i)).collect()),
+        favorite_speed: most_common(clocks.iter().map(|(l, i)| speed_for_clock(*l, *i).to_string()).collect()),
+        head_to_head
+    }
+}
+
+// finished games, including ones reversed after a fair play ban, oldest first
+pub async fn played_games(pool: &Pool<Postgres>, username: &str) -> Result<Vec<PlayedGame>, sqlx::Error> {
+    sqlx::query_as::<_,PlayedGame>("SELECT \
+            CASE WHEN LOWER(c.username)=LOWER($1) THEN c.opp_username ELSE c.username END AS opponent, \
+            CASE WHEN LOWER(c.username)=LOWER($1) THEN COALESCE(c.sats, 0) ELSE COALESCE(c.opponent_sats, c.sats, 0) END AS stake, \
+            COALESCE((SELECT SUM(t.amount) FROM lightningchess_transaction t WHERE t.lichess_challenge_id=c.lichess_challenge_id \
+                AND t.ttype='challenge payout' AND t.state IN ('SETTLED', 'HELD') AND LOWER(t.username)=LOWER($1)), 0)::BIGINT AS received, \
+            CASE WHEN LOWER(c.username)=LOWER($1) THEN c.time_limit ELSE COALESCE(c.opponent_time_limit, c.time_limit) END AS time_limit, \
+            c.increment \
+        FROM challenge c WHERE c.status IN ('FINISHED', 'REVERSED') AND (LOWER(c.username)=LOWER($1) OR LOWER(c.opp_username)=LOWER($1)) \
+        ORDER BY c.id")
+        .bind(username)
+        .fetch_all(pool).await
+}
+
+// the most played opponents, or only the one asked for
+pub fn with_head_to_head(mut stats: PlayerStats, opponent: Option<&str>) -> PlayerStats {
+    match opponent {
+        Some(o) => stats.head_to_head.retain(|h| h.opponent.eq_ignore_ascii_case(o)),
+        None => stats.head_to_head.truncate(HEAD_TO_HEAD_LIMIT)
+    }
+    stats
+}
+
+pub async fn cached_player_stats(pool: &Pool<Postgres>, cache: &StatsCache, username: &str) -> Result<PlayerStats, sqlx::Error> {
+    let key = username.to_lowercase();
+    if let Some(stats) = cache.0.get(&key) {
+        return Ok(stats)
+    }
+    let stats = player_stats(username, &played_games(pool, username).await?);
+    cache.0.insert(key, stats.clone()).await;
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn game(opponent: &str, stake: i64, received: i64, time_limit: i32, increment: i32) -> PlayedGame {
+        PlayedGame { opponent: opponent.to_string(), stake, received, time_limit: Some(time_limit), increment: Some(increment) }
+    }
+
+    #[test]
+    fn record_and_net() {
+        let games = vec![
+            game("bob", 1_000, 2_000, 300, 3),
+            game("Bob", 1_000, 0, 300, 3),
+            game("carol", 500, 500, 60, 0),
+            game("carol", 500, 1_500, 180, 2)
+        ];
+        let stats = player_stats("alice", &games);
+        assert_eq!(stats.record, Record { games: 4, wins: 2, losses: 1, draws: 1, net_sats: 1_000 });
+        assert_eq!(stats.biggest_win_sats, 1_000);
+        assert_eq!(stats.favorite_time_control.as_deref(), Some("5+3"));
+        assert_eq!(stats.favorite_speed.as_deref(), Some("blitz"));
+        assert_eq!(stats.head_to_head[0].opponent, "bob");
+        assert_eq!(stats.head_to_head[0].record, Record { games: 2, wins: 1, losses: 1, draws: 0, net_sats: 0 });
+
+        let vs_carol = with_head_to_head(stats, Some("Carol"));
+        assert_eq!(vs_carol.head_to_head.len(), 1);
+        assert_eq!(vs_carol.head_to_head[0].record.net_sats, 1_000);
+    }
+
+    #[test]
+    fn no_games() {
+        let stats = player_stats("alice", &[]);
+        assert_eq!(stats.record, Record::default());
+        assert_eq!(stats.biggest_win_sats, 0);
+        assert_eq!(stats.favorite_time_control, None);
+        assert!(stats.head_to_head.is_empty());
+    }
+
+    #[test]
+    fn fractional_minutes() {
+        assert_eq!(time_control(30, 0), "0.5+0");
+        assert_eq!(time_control(600, 5), "10+5");
+    }
+}
@@ -0,0 +1,117 @@
+use std::time::Instant;
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::Header;
+use rocket::{Data, Request, Response};
+use serde_json::Value;
+use tracing::info;
+use tracing_subscriber::EnvFilter;
+
+// keys whose values never make it into the logs
+const SECRET_KEYS: [&str; 10] = ["access_token", "refresh_token", "challenger_token", "token", "code", "code_verifier", "preimage", "r_preimage", "payment_preimage", "macaroon"];
+
+// json in production, pretty printed in development, level from RUST_LOG
+pub fn init_tracing(log_format: &str) {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let builder = tracing_subscriber::fmt().with_env_filter(filter);
+    if log_format == "json" {
+        builder.json().flatten_event(true).with_current_span(true).init();
+    } else {
+        builder.pretty().init();
+    }
+}
+
+// replaces secret values in a json body, anything that isn't json is truncated
+pub fn redact(text: &str) -> String {
+    match serde_json::from_str::<Value>(text) {
+        Ok(mut value) => {
+            redact_value(&mut value);
+            value.to_string()
+        },
+        Err(_) => text.chars().take(256).collect()
+    }
+}
+
+fn redact_value(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            for (key, v) in map.iter_mut() {
+                if SECRET_KEYS.contains(&key.as_str()) {
+                    *v = Value::String("[redacted]".to_string());
+                } else {
+                    redact_value(v);
+                }
+            }
+        },
+        Value::Array(values) => values.iter_mut().for_each(redact_value),
+        _ => ()
+    }
+}
+
+pub struct RequestContext {
+    pub request_id: String,
+    pub start: Instant
+}
+
+impl RequestContext {
+    fn new(request_id: Option<&str>) -> Self {
+        // honor a sane id from the load balancer so logs can be joined up
+        let request_id = match request_id {
+            Some(id) if !id.is_empty() && id.len() <= 64 && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') => id.to_string(),
+            _ => rand::thread_rng().sample_iter(&Alphanumeric).take(16).map(char::from).collect()
+        };
+        RequestContext { request_id, start: Instant::now() }
+    }
+
+    pub fn of<'r>(request: &'r Request<'_>) -> &'r RequestContext {
+        request.local_cache(|| RequestContext::new(None))
+    }
+}
+
+// tags every request with an id and logs its status and latency
+pub struct RequestTracing;
+
+#[rocket::async_trait]
+impl Fairing for RequestTracing {
+    fn info(&self) -> Info {
+        Info { name: "Request tracing", kind: Kind::Request | Kind::Response }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _: &mut Data<'_>) {
+        let header_id = request.headers().get_one("X-Request-Id").map(str::to_string);
+        request.local_cache(|| RequestContext::new(header_id.as_deref()));
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        let context = RequestContext::of(request);
+        info!(
+            request_id = %context.request_id,
+            method = %request.method(),
+            uri = %request.uri().path(),
+            status = response.status().code,
+            latency_ms = context.start.elapsed().as_millis() as u64,
+            "request finished"
+        );
+        response.set_header(Header::new("X-Request-Id", context.request_id.clone()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redacts_nested_secrets() {
+        let redacted = redact(r#"{"access_token":"lio_secret","data":[{"r_preimage":"abc","value":"10"}]}"#);
+        assert!(!redacted.contains("lio_secret"));
+        assert!(!redacted.contains("abc"));
+        assert!(redacted.contains("\"value\":\"10\""));
+    }
+
+    #[test]
+    fn request_id_header_is_sanitized() {
+        assert_eq!(RequestContext::new(Some("abc-123")).request_id, "abc-123");
+        assert_ne!(RequestContext::new(Some("bad id\n")).request_id, "bad id\n");
+    }
+}
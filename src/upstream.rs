@@ -0,0 +1,268 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use reqwest::{Client, RequestBuilder, Response, StatusCode};
+use tracing::warn;
+use crate::errors::{AppError, AppResult};
+use crate::metrics::CIRCUIT_OPEN;
+
+// every lichess and lnd call goes through its upstream: one pooled client with a timeout,
+// bounded retries for calls that can be repeated, a cooldown after being rate limited and a circuit breaker
+
+const RETRY_BACKOFF: Duration = Duration::from_millis(500);
+
+pub struct Policy {
+    // attempts for calls that can be repeated, others are sent once
+    pub attempts: u32,
+    // every call is refused for this long after a 429
+    pub rate_limit_cooldown: Option<Duration>,
+    // consecutive failures that open the circuit and how long it stays open
+    pub breaker: Option<(u32, Duration)>
+}
+
+// when a call is repeated
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Retry {
+    Never,
+    // only when the request never reached the upstream, for calls that mustn't run twice
+    // but would leave things behind when they don't run at all
+    Connect,
+    // after any failure, for calls that are safe to repeat
+    Idempotent
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Outcome {
+    Success,
+    RateLimited,
+    // unreachable, timed out or a 5xx
+    Failure
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Refusal {
+    CoolingDown,
+    CircuitOpen
+}
+
+#[derive(Default)]
+struct State {
+    cooldown_until: Option<Instant>,
+    failures: u32,
+    open_until: Option<Instant>,
+    // when the single call let through a half open circuit went out
+    probe_since: Option<Instant>
+}
+
+impl State {
+    fn admit(&mut self, policy: &Policy, now: Instant) -> Result<(), Refusal> {
+        if matches!(self.cooldown_until, Some(t) if now < t) {
+            return Err(Refusal::CoolingDown)
+        }
+        match self.open_until {
+            Some(t) if now < t => return Err(Refusal::CircuitOpen),
+            // half open, one call probes the upstream and its outcome closes or reopens the circuit.
+            // a probe that never reports back is replaced after another open period
+            Some(_) => {
+                let open_for = policy.breaker.map(|(_, open_for)| open_for).unwrap_or_default();
+                if matches!(self.probe_since, Some(p) if now < p + open_for) {
+                    return Err(Refusal::CircuitOpen)
+                }
+                self.probe_since = Some(now);
+            },
+            None => ()
+        }
+        Ok(())
+    }
+
+    // returns true when this outcome opened the circuit
+    fn record(&mut self, policy: &Policy, outcome: &Outcome, now: Instant) -> bool {
+        match outcome {
+            Outcome::Success => {
+                self.failures = 0;
+                self.open_until = None;
+                self.probe_since = None;
+                false
+            },
+            Outcome::RateLimited => {
+                self.cooldown_until = policy.rate_limit_cooldown.map(|c| now + c);
+                self.probe_since = None;
+                false
+            },
+            Outcome::Failure => {
+                self.failures += 1;
+                self.probe_since = None;
+                match policy.breaker {
+                    Some((threshold, open_for)) if self.failures >= threshold => {
+                        self.open_until = Some(now + open_for);
+                        true
+                    },
+                    _ => false
+                }
+            }
+        }
+    }
+}
+
+fn outcome(response: &Result<Response, reqwest::Error>) -> Outcome {
+    match response {
+        Ok(r) if r.status() == StatusCode::TOO_MANY_REQUESTS => Outcome::RateLimited,
+        Ok(r) if r.status().is_server_error() => Outcome::Failure,
+        Ok(_) => Outcome::Success,
+        Err(_) => Outcome::Failure
+    }
+}
+
+pub struct Upstream {
+    name: &'static str,
+    policy: Policy,
+    // how failures of this upstream are reported
+    error: fn(String) -> AppError,
+    // keyed by the config the client was built from, clients share their connection pool when cloned
+    clients: Mutex<HashMap<String, Client>>,
+    state: Mutex<State>
+}
+
+impl Upstream {
+    pub fn new(name: &'static str, policy: Policy, error: fn(String) -> AppError) -> Self {
+        Upstream { name, policy, error, clients: Mutex::new(HashMap::new()), state: Mutex::new(State::default()) }
+    }
+
+    // built on first use of each config, key is whatever in the config the client depends on
+    pub fn client(&self, key: String, build: impl FnOnce() -> Result<Client, String>) -> AppResult<Client> {
+        let mut clients = self.clients.lock().unwrap();
+        if let Some(client) = clients.get(&key) {
+            return Ok(client.clone())
+        }
+        let client = build().map_err(|e| (self.error)(format!("client: {e}")))?;
+        clients.insert(key, client.clone());
+        Ok(client)
+    }
+
+    fn admit(&self, call: &str) -> AppResult<()> {
+        match self.state.lock().unwrap().admit(&self.policy, Instant::now()) {
+            Ok(()) => Ok(()),
+            Err(Refusal::CoolingDown) => Err(AppError::RateLimited),
+            Err(Refusal::CircuitOpen) => Err((self.error)(format!("{call}: circuit open")))
+        }
+    }
+
+    fn record(&self, outcome: &Outcome) {
+        let mut state = self.state.lock().unwrap();
+        let was_open = state.open_until.is_some();
+        if state.record(&self.policy, outcome, Instant::now()) {
+            warn!(upstream = self.name, failures = state.failures, "circuit opened");
+        }
+        if *outcome == Outcome::RateLimited && state.cooldown_until.is_some() {
+            warn!(upstream = self.name, "rate limited, cooling down");
+        }
+        CIRCUIT_OPEN.with_label_values(&[self.name]).set(state.open_until.is_some() as i64);
+        if was_open && state.open_until.is_none() {
+            warn!(upstream = self.name, "circuit closed");
+        }
+    }
+
+    // sends the request, repeating it with exponential backoff as far as retry allows
+    pub async fn send(&self, mut request: RequestBuilder, call: &str, retry: Retry) -> AppResult<Response> {
+        let attempts = if retry == Retry::Never { 1 } else { self.policy.attempts };
+        let mut attempt = 1;
+        loop {
+            self.admit(call)?;
+            let next = if attempt < attempts { request.try_clone() } else { None };
+            let response = request.send().await;
+            let outcome = outcome(&response);
+            self.record(&outcome);
+            let repeat = match retry {
+                Retry::Never => false,
+                Retry::Connect => matches!(&response, Err(e) if e.is_connect()),
+                Retry::Idempotent => outcome == Outcome::Failure
+            };
+            match next {
+                Some(next) if repeat => {
+                    let backoff = RETRY_BACKOFF * 2u32.pow(attempt - 1);
+                    warn!(upstream = self.name, call, attempt, backoff_ms = backoff.as_millis() as u64, "retrying");
+                    tokio::time::sleep(backoff).await;
+                    request = next;
+                    attempt += 1;
+                },
+                // urls can carry tokens in their query
+                _ => return response.map_err(|e| (self.error)(format!("{call}: {}", e.without_url())))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> Policy {
+        Policy { attempts: 3, rate_limit_cooldown: Some(Duration::from_secs(60)), breaker: Some((3, Duration::from_secs(30))) }
+    }
+
+    #[test]
+    fn circuit_opens_after_consecutive_failures() {
+        let now = Instant::now();
+        let mut state = State::default();
+        assert!(!state.record(&policy(), &Outcome::Failure, now));
+        assert!(!state.record(&policy(), &Outcome::Failure, now));
+        assert_eq!(state.admit(&policy(), now), Ok(()));
+        assert!(state.record(&policy(), &Outcome::Failure, now));
+        assert_eq!(state.admit(&policy(), now + Duration::from_secs(29)), Err(Refusal::CircuitOpen));
+
+        // half open, one more failure reopens it right away
+        let later = now + Duration::from_secs(30);
+        assert_eq!(state.admit(&policy(), later), Ok(()));
+        assert!(state.record(&policy(), &Outcome::Failure, later));
+        assert_eq!(state.admit(&policy(), later), Err(Refusal::CircuitOpen));
+
+        let probe = later + Duration::from_secs(30);
+        assert_eq!(state.admit(&policy(), probe), Ok(()));
+        state.record(&policy(), &Outcome::Success, probe);
+        assert_eq!(state.admit(&policy(), probe), Ok(()));
+        assert_eq!(state.failures, 0);
+    }
+
+    #[test]
+    fn half_open_circuit_lets_one_probe_through() {
+        let now = Instant::now();
+        let mut state = State::default();
+        for _ in 0..3 {
+            state.record(&policy(), &Outcome::Failure, now);
+        }
+        let half_open = now + Duration::from_secs(30);
+        assert_eq!(state.admit(&policy(), half_open), Ok(()));
+        assert_eq!(state.admit(&policy(), half_open), Err(Refusal::CircuitOpen));
+        assert_eq!(state.admit(&policy(), half_open + Duration::from_secs(29)), Err(Refusal::CircuitOpen));
+
+        // a probe that never reported back is replaced
+        let stuck = half_open + Duration::from_secs(30);
+        assert_eq!(state.admit(&policy(), stuck), Ok(()));
+        assert_eq!(state.admit(&policy(), stuck), Err(Refusal::CircuitOpen));
+    }
+
+    #[test]
+    fn successes_reset_the_failure_count() {
+        let now = Instant::now();
+        let mut state = State::default();
+        state.record(&policy(), &Outcome::Failure, now);
+        state.record(&policy(), &Outcome::Failure, now);
+        state.record(&policy(), &Outcome::Success, now);
+        assert!(!state.record(&policy(), &Outcome::Failure, now));
+        assert_eq!(state.admit(&policy(), now), Ok(()));
+    }
+
+    #[test]
+    fn rate_limit_cooldown() {
+        let now = Instant::now();
+        let mut state = State::default();
+        state.record(&policy(), &Outcome::RateLimited, now);
+        assert_eq!(state.admit(&policy(), now + Duration::from_secs(59)), Err(Refusal::CoolingDown));
+        assert_eq!(state.admit(&policy(), now + Duration::from_secs(60)), Ok(()));
+
+        let no_cooldown = Policy { rate_limit_cooldown: None, ..policy() };
+        let mut state = State::default();
+        state.record(&no_cooldown, &Outcome::RateLimited, now);
+        assert_eq!(state.admit(&no_cooldown, now), Ok(()));
+    }
+}